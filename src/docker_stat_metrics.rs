@@ -1,31 +1,339 @@
-use std::{borrow::Cow, sync::atomic::AtomicU64};
+use std::{ops::Sub, sync::atomic::AtomicU64};
+
+use regex::Regex;
 
 use prometheus_client::{
-    metrics::gauge::Gauge,
+    collector::Collector,
+    encoding::{DescriptorEncoder, EncodeMetric},
+    metrics::{
+        MetricType,
+        counter::{Atomic, Counter},
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
     registry::{Registry, Unit},
 };
 
+/// cgroup driver of docker daemon, decides the cgroup path of containers
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum CgroupDriver {
+    #[default]
+    Systemd,
+    Cgroupfs,
+}
+
+/// cgroup v2 path of a container, relative to the cgroup root
+pub fn container_cgroup_path(id: &str, driver: CgroupDriver) -> String {
+    match driver {
+        CgroupDriver::Systemd => format!("/system.slice/docker-{}.scope", id),
+        CgroupDriver::Cgroupfs => format!("/docker/{}", id),
+    }
+}
+
+/// options of labels attached to every container metric
+#[derive(Debug, Clone, Default)]
+pub struct ContainerLabelOptions {
+    /// also emit a `short_id` label with the first 12 chars of the container id
+    pub short_id_label: bool,
+
+    /// regex whose first capture group of container name becomes the `name` label
+    pub name_regex: Option<Regex>,
+
+    /// cgroup driver the `id` label path follows
+    pub cgroup_driver: CgroupDriver,
+}
+
+/// `name` label value, the first capture group of `name_regex` when it matches,
+/// otherwise the name itself
+fn name_label(name: &str, options: &ContainerLabelOptions) -> String {
+    options
+        .name_regex
+        .as_ref()
+        .and_then(|re| re.captures(name))
+        .and_then(|captures| captures.get(1))
+        .map_or(name, |m| m.as_str())
+        .to_owned()
+}
+
+/// label set of a container series
+pub type ContainerLabels = Vec<(String, String)>;
+
+pub fn container_labels(id: &str, name: &str, options: &ContainerLabelOptions) -> ContainerLabels {
+    let mut labels = vec![
+        (
+            "id".to_owned(),
+            container_cgroup_path(id, options.cgroup_driver),
+        ),
+        ("name".to_owned(), name_label(name, options)),
+    ];
+    if options.short_id_label {
+        let short_id = &id[..id.len().min(12)];
+        labels.push(("short_id".to_owned(), short_id.to_owned()));
+    }
+    labels
+}
+
+/// label set of a compose service series with `group_by_service`
+pub fn service_labels(project: &str, service: &str) -> ContainerLabels {
+    vec![
+        ("project".to_owned(), project.to_owned()),
+        ("service".to_owned(), service.to_owned()),
+    ]
+}
+
+/// label set of the info series of a container, with `command` when known
+pub fn info_labels(labels: &ContainerLabels, command: Option<&str>) -> ContainerLabels {
+    let mut labels = labels.clone();
+    if let Some(command) = command {
+        labels.push(("command".to_owned(), command.to_owned()));
+    }
+    labels
+}
+
+/// label set of a mount info series of a container
+pub fn mount_labels(
+    labels: &ContainerLabels,
+    source: &str,
+    destination: &str,
+    mount_type: &str,
+) -> ContainerLabels {
+    let mut labels = labels.clone();
+    labels.extend([
+        ("source".to_owned(), source.to_owned()),
+        ("destination".to_owned(), destination.to_owned()),
+        ("type".to_owned(), mount_type.to_owned()),
+    ]);
+    labels
+}
+
+/// label set of a network interface series of a container, `interface` is the
+/// key of the `networks` map in docker stats, eg. `eth0`
+pub fn interface_labels(labels: &ContainerLabels, interface: &str) -> ContainerLabels {
+    let mut labels = labels.clone();
+    labels.push(("interface".to_owned(), interface.to_owned()));
+    labels
+}
+
+/// label set of a block device series of a container, `device` is `major:minor`
+/// and `device_name` the kernel name, empty when unknown
+pub fn device_labels(labels: &ContainerLabels, device: &str, device_name: &str) -> ContainerLabels {
+    let mut labels = labels.clone();
+    labels.extend([
+        ("device".to_owned(), device.to_owned()),
+        ("device_name".to_owned(), device_name.to_owned()),
+    ]);
+    labels
+}
+
+/// unit of exported network data and throughput metrics
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum DataUnit {
+    Bits,
+    Bytes,
+}
+
+impl DataUnit {
+    /// a byte count in this unit
+    pub fn from_bytes(self, bytes: u64) -> u64 {
+        match self {
+            DataUnit::Bits => bytes.saturating_mul(8),
+            DataUnit::Bytes => bytes,
+        }
+    }
+
+    /// a bits per second throughput in this unit per second
+    pub fn from_bits_per_second(self, bps: f64) -> f64 {
+        match self {
+            DataUnit::Bits => bps,
+            DataUnit::Bytes => bps / 8.,
+        }
+    }
+
+    fn unit(self) -> Unit {
+        match self {
+            DataUnit::Bits => Unit::Other("bits".to_owned()),
+            DataUnit::Bytes => Unit::Bytes,
+        }
+    }
+
+    /// suffix of throughput metric names, same as blkio `byteps`
+    fn rate_suffix(self) -> &'static str {
+        match self {
+            DataUnit::Bits => "bps",
+            DataUnit::Bytes => "byteps",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            DataUnit::Bits => "bits",
+            DataUnit::Bytes => "bytes",
+        }
+    }
+}
+
+/// units of network and CPU usage metrics, name, help and value follow them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricUnits {
+    /// unit of transferred data totals
+    pub network: DataUnit,
+    /// unit of throughputs
+    pub throughput: DataUnit,
+    /// CPU usage in percent of one CPU instead of ratio
+    pub cpu_percent: bool,
+}
+impl Default for MetricUnits {
+    fn default() -> Self {
+        Self {
+            network: DataUnit::Bytes,
+            throughput: DataUnit::Bits,
+            cpu_percent: false,
+        }
+    }
+}
+
+impl MetricUnits {
+    /// CPU usage ratio in the configured unit
+    pub fn cpu_usage(&self, ratio: f64) -> f64 {
+        if self.cpu_percent {
+            ratio * 100.
+        } else {
+            ratio
+        }
+    }
+
+    fn cpu_unit(&self) -> Unit {
+        if self.cpu_percent {
+            Unit::Other("percent".to_owned())
+        } else {
+            Unit::Ratios
+        }
+    }
+}
+
+type GaugeFamily<T> = Family<ContainerLabels, Gauge<T, AtomicU64>>;
+type CounterFamily<N = u64> = Family<ContainerLabels, Counter<N, AtomicU64>>;
+
+/// set a counter to a cumulative value reported by docker, a value lower than
+/// current one means the source was reset, so the counter restarts from it
+pub fn set_counter<N>(family: &CounterFamily<N>, labels: &ContainerLabels, value: N)
+where
+    N: Copy + PartialOrd + Sub<Output = N>,
+    AtomicU64: Atomic<N>,
+{
+    let current = family.get_or_create(labels).get();
+    if value >= current {
+        family.get_or_create(labels).inc_by(value - current);
+    } else {
+        family.remove(labels);
+        family.get_or_create(labels).inc_by(value);
+    }
+}
+type HistogramFamily = Family<ContainerLabels, Histogram, fn() -> Histogram>;
+
+fn cpu_usage_histogram() -> Histogram {
+    Histogram::new([0.05, 0.1, 0.25, 0.5, 1., 2., 4., 8.])
+}
+
+fn mem_usage_histogram() -> Histogram {
+    // 1 MiB to 256 GiB
+    Histogram::new(exponential_buckets(1024. * 1024., 4., 10))
+}
+
+const CPU_USAGE_HISTOGRAM_NAME: &str = "cpu_usage_ratio_histogram";
+const CPU_USAGE_HISTOGRAM_HELP: &str =
+    "Distribution of container logical CPU usage within polling intervals";
+const MEM_USAGE_HISTOGRAM_NAME: &str = "memory_usage_bytes_histogram";
+const MEM_USAGE_HISTOGRAM_HELP: &str =
+    "Distribution of container memory usage in bytes within polling intervals";
+
+/// series of `labels` only out of a histogram family, read on every encode
+#[derive(Debug)]
+struct SelectedHistograms {
+    name: &'static str,
+    help: &'static str,
+    family: HistogramFamily,
+    labels: Vec<ContainerLabels>,
+}
+
+impl Collector for SelectedHistograms {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let mut metric_encoder =
+            encoder.encode_descriptor(self.name, self.help, None, MetricType::Histogram)?;
+        for labels in self.labels.iter() {
+            if let Some(histogram) = self.family.get(labels) {
+                histogram.encode(metric_encoder.encode_family(labels)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// container metrics keyed by label set, held by the polling worker and
+/// updated in place after every poll
+#[derive(Debug, Clone)]
 pub struct DockerStatContainerMetrics {
-    id: String,
-    pub cpu_usage: Gauge<f64, AtomicU64>,
-    pub mem_usage: Gauge<u64, AtomicU64>,
-    pub mem_limit: Gauge<u64, AtomicU64>,
-    pub net_in: Gauge<u64, AtomicU64>,
-    pub net_out: Gauge<u64, AtomicU64>,
-    pub net_in_bps: Gauge<f64, AtomicU64>,
-    pub net_out_bps: Gauge<f64, AtomicU64>,
-    pub blk_in: Gauge<u64, AtomicU64>,
-    pub blk_out: Gauge<u64, AtomicU64>,
-    pub blk_in_byteps: Gauge<f64, AtomicU64>,
-    pub blk_out_byteps: Gauge<f64, AtomicU64>,
+    pub cpu_usage: GaugeFamily<f64>,
+    pub mem_usage: GaugeFamily<u64>,
+    pub mem_limit: GaugeFamily<u64>,
+    pub mem_usage_ratio: GaugeFamily<f64>,
+    pub mem_kernel: GaugeFamily<u64>,
+    pub mem_dirty: GaugeFamily<u64>,
+    pub mem_writeback: GaugeFamily<u64>,
+    pub net_in: GaugeFamily<u64>,
+    pub net_out: GaugeFamily<u64>,
+    pub net_in_bps: GaugeFamily<f64>,
+    pub net_out_bps: GaugeFamily<f64>,
+    pub blk_in: GaugeFamily<u64>,
+    pub blk_out: GaugeFamily<u64>,
+    pub blk_in_byteps: GaugeFamily<f64>,
+    pub blk_out_byteps: GaugeFamily<f64>,
+    pub paused: Family<ContainerLabels, Gauge>,
+    pub net_in_errors: CounterFamily,
+    pub net_out_errors: CounterFamily,
+    pub net_in_dropped: CounterFamily,
+    pub net_out_dropped: CounterFamily,
+    pub net_in_packets: CounterFamily,
+    pub net_out_packets: CounterFamily,
+    pub cpu_usage_seconds: CounterFamily<f64>,
+    pub precpu_available: Family<ContainerLabels, Gauge>,
+    pub collection_duration: GaugeFamily<f64>,
+    pub exit_code: Family<ContainerLabels, Gauge>,
+    pub blk_service_time: GaugeFamily<f64>,
+    pub cpu_limit: GaugeFamily<f64>,
+    pub pids: GaugeFamily<u64>,
+    pub pids_limit: Family<ContainerLabels, Gauge>,
+    pub cpu_shares: Family<ContainerLabels, Gauge>,
+    pub unhealthy: GaugeFamily<f64>,
+    pub time_since_restart: GaugeFamily<f64>,
+    /// 1 per container, labeled by `info_labels`
+    pub info: Family<ContainerLabels, Gauge>,
+    /// 1 per mount, labeled by source, destination and type
+    pub mount_info: Family<ContainerLabels, Gauge>,
+
+    /// per interface traffic, labeled by `interface_labels`
+    pub net_interface_in: GaugeFamily<u64>,
+    pub net_interface_out: GaugeFamily<u64>,
+
+    /// per device blkio, labeled by `device_labels`
+    pub blk_device_in: GaugeFamily<u64>,
+    pub blk_device_out: GaugeFamily<u64>,
+
+    /// distribution of samples taken within polling intervals
+    pub cpu_usage_histogram: HistogramFamily,
+    pub mem_usage_histogram: HistogramFamily,
 }
 impl Default for DockerStatContainerMetrics {
     fn default() -> Self {
         Self {
-            id: Default::default(),
             cpu_usage: Default::default(),
             mem_usage: Default::default(),
             mem_limit: Default::default(),
+            mem_usage_ratio: Default::default(),
+            mem_kernel: Default::default(),
+            mem_dirty: Default::default(),
+            mem_writeback: Default::default(),
             net_in: Default::default(),
             net_out: Default::default(),
             net_in_bps: Default::default(),
@@ -34,89 +342,692 @@ impl Default for DockerStatContainerMetrics {
             blk_out: Default::default(),
             blk_in_byteps: Default::default(),
             blk_out_byteps: Default::default(),
+            paused: Default::default(),
+            net_in_errors: Default::default(),
+            net_out_errors: Default::default(),
+            net_in_dropped: Default::default(),
+            net_out_dropped: Default::default(),
+            net_in_packets: Default::default(),
+            net_out_packets: Default::default(),
+            cpu_usage_seconds: Default::default(),
+            precpu_available: Default::default(),
+            collection_duration: Default::default(),
+            exit_code: Default::default(),
+            blk_service_time: Default::default(),
+            cpu_limit: Default::default(),
+            pids: Default::default(),
+            pids_limit: Default::default(),
+            cpu_shares: Default::default(),
+            unhealthy: Default::default(),
+            time_since_restart: Default::default(),
+            info: Default::default(),
+            mount_info: Default::default(),
+            net_interface_in: Default::default(),
+            net_interface_out: Default::default(),
+            blk_device_in: Default::default(),
+            blk_device_out: Default::default(),
+            cpu_usage_histogram: Family::new_with_constructor(cpu_usage_histogram),
+            mem_usage_histogram: Family::new_with_constructor(mem_usage_histogram),
         }
     }
 }
 
 impl DockerStatContainerMetrics {
-    pub fn new(id: &str) -> Self {
-        Self {
-            id: id.to_owned(),
-            ..Default::default()
-        }
+    /// drop all series of a container
+    pub fn remove(&self, labels: &ContainerLabels) {
+        self.cpu_usage.remove(labels);
+        self.mem_usage.remove(labels);
+        self.mem_limit.remove(labels);
+        self.mem_usage_ratio.remove(labels);
+        self.mem_kernel.remove(labels);
+        self.mem_dirty.remove(labels);
+        self.mem_writeback.remove(labels);
+        self.net_in.remove(labels);
+        self.net_out.remove(labels);
+        self.net_in_bps.remove(labels);
+        self.net_out_bps.remove(labels);
+        self.blk_in.remove(labels);
+        self.blk_out.remove(labels);
+        self.blk_in_byteps.remove(labels);
+        self.blk_out_byteps.remove(labels);
+        self.paused.remove(labels);
+        self.net_in_errors.remove(labels);
+        self.net_out_errors.remove(labels);
+        self.net_in_dropped.remove(labels);
+        self.net_out_dropped.remove(labels);
+        self.net_in_packets.remove(labels);
+        self.net_out_packets.remove(labels);
+        self.cpu_usage_seconds.remove(labels);
+        self.precpu_available.remove(labels);
+        self.collection_duration.remove(labels);
+        self.exit_code.remove(labels);
+        self.blk_service_time.remove(labels);
+        self.cpu_limit.remove(labels);
+        self.pids.remove(labels);
+        self.pids_limit.remove(labels);
+        self.cpu_shares.remove(labels);
+        self.unhealthy.remove(labels);
+        self.time_since_restart.remove(labels);
+        self.cpu_usage_histogram.remove(labels);
+        self.mem_usage_histogram.remove(labels);
     }
 
-    pub fn register_as_sub_registry(&self, registry: &mut Registry, name: &str) -> () {
-        let label_items = [
-            (
-                Cow::from("id"),
-                Cow::from(format!("/system.slice/docker-{}.scope", self.id.to_owned())),
-            ),
-            (Cow::from("name"), Cow::from(name.to_owned())),
-        ];
+    /// drop the series of a network interface of a container
+    pub fn remove_interface(&self, labels: &ContainerLabels, interface: &str) {
+        let labels = interface_labels(labels, interface);
+        self.net_interface_in.remove(&labels);
+        self.net_interface_out.remove(&labels);
+    }
+
+    /// drop the series of a block device of a container, labeled by `device_labels`
+    pub fn remove_device(&self, labels: &ContainerLabels) {
+        self.blk_device_in.remove(labels);
+        self.blk_device_out.remove(labels);
+    }
 
-        let sub_registry = registry.sub_registry_with_labels(label_items.into_iter());
-        sub_registry.register_with_unit(
+    pub fn register(&self, registry: &mut Registry, histograms: bool, units: &MetricUnits) {
+        registry.register_with_unit(
             "cpu_usage",
             "Value of container logical CPU usage",
-            Unit::Ratios,
+            units.cpu_unit(),
             self.cpu_usage.clone(),
         );
-        sub_registry.register_with_unit(
+        registry.register_with_unit(
+            "cpu_usage",
+            "Cumulative CPU time consumed by container",
+            Unit::Seconds,
+            self.cpu_usage_seconds.clone(),
+        );
+        registry.register(
+            "precpu_available",
+            "Whether stats responses of container carry precpu_stats, CPU usage is computed from two polls either way",
+            self.precpu_available.clone(),
+        );
+        registry.register_with_unit(
             "memory_usage",
             "Value of container memory usage in bytes",
             Unit::Bytes,
             self.mem_usage.clone(),
         );
-        sub_registry.register_with_unit(
+        registry.register_with_unit(
             "memory_limit",
             "Value of container memory limitation in bytes",
             Unit::Bytes,
             self.mem_limit.clone(),
         );
-        sub_registry.register_with_unit(
-            "network_receive",
-            "Value of container received data from network data in bytes",
+        registry.register_with_unit(
+            "memory_usage",
+            "Value of container memory usage over its limit, at most 1, 0 without limit",
+            Unit::Ratios,
+            self.mem_usage_ratio.clone(),
+        );
+        registry.register_with_unit(
+            "memory_kernel",
+            "Value of container kernel memory usage in bytes, 0 when unavailable",
+            Unit::Bytes,
+            self.mem_kernel.clone(),
+        );
+        registry.register_with_unit(
+            "memory_dirty",
+            "Value of container page cache waiting to be written back to disk in bytes, 0 when unavailable",
             Unit::Bytes,
+            self.mem_dirty.clone(),
+        );
+        registry.register_with_unit(
+            "memory_writeback",
+            "Value of container page cache being written back to disk in bytes, 0 when unavailable",
+            Unit::Bytes,
+            self.mem_writeback.clone(),
+        );
+        registry.register_with_unit(
+            "network_receive",
+            format!(
+                "Value of container received data from network data in {}",
+                units.network.name()
+            ),
+            units.network.unit(),
             self.net_in.clone(),
         );
-        sub_registry.register_with_unit(
+        registry.register_with_unit(
             "network_transmit",
-            "Value of container sent data from network in bytes",
-            Unit::Bytes,
+            format!(
+                "Value of container sent data from network in {}",
+                units.network.name()
+            ),
+            units.network.unit(),
             self.net_out.clone(),
         );
-        sub_registry.register_with_unit(
+        registry.register_with_unit(
             "blkio_receive",
             "Value of container read data from blkio in bytes",
             Unit::Bytes,
             self.blk_in.clone(),
         );
-        sub_registry.register_with_unit(
+        registry.register_with_unit(
             "blkio_transmit",
             "Value of container write data to blkio in bytes",
             Unit::Bytes,
             self.blk_out.clone(),
         );
-        sub_registry.register(
-            "network_receive_bps",
-            "Value of container network receive throughput in bps",
+        registry.register(
+            format!("network_receive_{}", units.throughput.rate_suffix()),
+            format!(
+                "Value of container network receive throughput in {} per second",
+                units.throughput.name()
+            ),
             self.net_in_bps.clone(),
         );
-        sub_registry.register(
-            "network_transmit_bps",
-            "Value of container network sent throughput in bps",
+        registry.register(
+            format!("network_transmit_{}", units.throughput.rate_suffix()),
+            format!(
+                "Value of container network sent throughput in {} per second",
+                units.throughput.name()
+            ),
             self.net_out_bps.clone(),
         );
-        sub_registry.register(
+        registry.register(
             "blkio_receive_byteps",
             "Value of container blkio receive throughput in byte per second",
             self.blk_in_byteps.clone(),
         );
-        sub_registry.register(
+        registry.register(
             "blkio_transmit_byteps",
             "Value of container blkio sent throughput in byte per second",
             self.blk_out_byteps.clone(),
         );
+        registry.register(
+            "paused",
+            "Whether container is paused, its stats and throughputs do not advance",
+            self.paused.clone(),
+        );
+        registry.register(
+            "network_receive_errors",
+            "Count of container network receive errors",
+            self.net_in_errors.clone(),
+        );
+        registry.register(
+            "network_transmit_errors",
+            "Count of container network transmit errors",
+            self.net_out_errors.clone(),
+        );
+        registry.register(
+            "network_receive_dropped",
+            "Count of container network received packets dropped",
+            self.net_in_dropped.clone(),
+        );
+        registry.register(
+            "network_transmit_dropped",
+            "Count of container network transmitted packets dropped",
+            self.net_out_dropped.clone(),
+        );
+        registry.register(
+            "network_receive_packets",
+            "Count of container network received packets",
+            self.net_in_packets.clone(),
+        );
+        registry.register(
+            "network_transmit_packets",
+            "Count of container network transmitted packets",
+            self.net_out_packets.clone(),
+        );
+        registry.register_with_unit(
+            "stats_collection_duration",
+            "Time taken by docker stats api call of container",
+            Unit::Seconds,
+            self.collection_duration.clone(),
+        );
+        registry.register(
+            "exit_code",
+            "Exit code of exited container",
+            self.exit_code.clone(),
+        );
+        registry.register_with_unit(
+            "blkio_service_time",
+            "Value of container time spent servicing blkio, 0 on cgroup v2",
+            Unit::Seconds,
+            self.blk_service_time.clone(),
+        );
+        registry.register(
+            "cpu_limit_cores",
+            "CPU limit of container in cores, absent when unlimited",
+            self.cpu_limit.clone(),
+        );
+        registry.register(
+            "pids",
+            "Number of processes and threads in container",
+            self.pids.clone(),
+        );
+        registry.register(
+            "pids_limit",
+            "Maximum number of processes and threads in container, absent when unlimited",
+            self.pids_limit.clone(),
+        );
+        registry.register(
+            "cpu_shares",
+            "Relative CPU weight of container against others under contention, absent when not set",
+            self.cpu_shares.clone(),
+        );
+        registry.register_with_unit(
+            "unhealthy",
+            "Time since container turned unhealthy, 0 while it is not, absent without health check",
+            Unit::Seconds,
+            self.unhealthy.clone(),
+        );
+        registry.register_with_unit(
+            "time_since_restart",
+            "Time since the previous run of a restarted container ended, absent until it restarts",
+            Unit::Seconds,
+            self.time_since_restart.clone(),
+        );
+        registry.register(
+            "info",
+            "Info of container, labeled by command when docker reports one",
+            self.info.clone(),
+        );
+        registry.register(
+            "mount_info",
+            "Mount of container, labeled by source, destination and type",
+            self.mount_info.clone(),
+        );
+        registry.register_with_unit(
+            "network_interface_receive",
+            format!(
+                "Value of container received data from network interface in {}",
+                units.network.name()
+            ),
+            units.network.unit(),
+            self.net_interface_in.clone(),
+        );
+        registry.register_with_unit(
+            "network_interface_transmit",
+            format!(
+                "Value of container sent data to network interface in {}",
+                units.network.name()
+            ),
+            units.network.unit(),
+            self.net_interface_out.clone(),
+        );
+        registry.register_with_unit(
+            "blkio_device_read",
+            "Value of container read data from a block device in bytes",
+            Unit::Bytes,
+            self.blk_device_in.clone(),
+        );
+        registry.register_with_unit(
+            "blkio_device_write",
+            "Value of container written data to a block device in bytes",
+            Unit::Bytes,
+            self.blk_device_out.clone(),
+        );
+
+        if histograms {
+            registry.register(
+                CPU_USAGE_HISTOGRAM_NAME,
+                CPU_USAGE_HISTOGRAM_HELP,
+                self.cpu_usage_histogram.clone(),
+            );
+            registry.register(
+                MEM_USAGE_HISTOGRAM_NAME,
+                MEM_USAGE_HISTOGRAM_HELP,
+                self.mem_usage_histogram.clone(),
+            );
+        }
+    }
+
+    /// register histograms of the containers labeled by `labels` only, for
+    /// output rebuilt from a part of the containers
+    pub fn register_histograms_of(&self, registry: &mut Registry, labels: &[ContainerLabels]) {
+        registry.register_collector(Box::new(SelectedHistograms {
+            name: CPU_USAGE_HISTOGRAM_NAME,
+            help: CPU_USAGE_HISTOGRAM_HELP,
+            family: self.cpu_usage_histogram.clone(),
+            labels: labels.to_vec(),
+        }));
+        registry.register_collector(Box::new(SelectedHistograms {
+            name: MEM_USAGE_HISTOGRAM_NAME,
+            help: MEM_USAGE_HISTOGRAM_HELP,
+            family: self.mem_usage_histogram.clone(),
+            labels: labels.to_vec(),
+        }));
+    }
+}
+
+/// host-level aggregates over all monitored containers
+#[derive(Default)]
+pub struct DockerStatTotalMetrics {
+    pub cpu_usage: Gauge<f64, AtomicU64>,
+    pub mem_usage: Gauge<u64, AtomicU64>,
+    pub net_in_bps: Gauge<f64, AtomicU64>,
+    pub net_out_bps: Gauge<f64, AtomicU64>,
+}
+
+impl DockerStatTotalMetrics {
+    pub fn register(&self, registry: &mut Registry, units: &MetricUnits) {
+        registry.register(
+            "total_cpu_usage",
+            if units.cpu_percent {
+                "Sum of logical CPU usage of all monitored containers in percent"
+            } else {
+                "Sum of logical CPU usage of all monitored containers"
+            },
+            self.cpu_usage.clone(),
+        );
+        registry.register(
+            "total_memory_bytes",
+            "Sum of memory usage of all monitored containers in bytes",
+            self.mem_usage.clone(),
+        );
+        registry.register(
+            format!("total_network_receive_{}", units.throughput.rate_suffix()),
+            format!(
+                "Sum of network receive throughput of all monitored containers in {} per second",
+                units.throughput.name()
+            ),
+            self.net_in_bps.clone(),
+        );
+        registry.register(
+            format!("total_network_transmit_{}", units.throughput.rate_suffix()),
+            format!(
+                "Sum of network sent throughput of all monitored containers in {} per second",
+                units.throughput.name()
+            ),
+            self.net_out_bps.clone(),
+        );
+    }
+}
+
+/// daemon overview from docker info api
+#[derive(Default)]
+pub struct DockerDaemonMetrics {
+    /// containers by `state`, running, paused or stopped
+    pub containers: Family<Vec<(String, String)>, Gauge>,
+    pub images: Gauge,
+    pub memory: Gauge,
+    pub cpus: Gauge,
+}
+
+impl DockerDaemonMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "containers_total",
+            "Number of containers on docker daemon by state",
+            self.containers.clone(),
+        );
+        registry.register(
+            "images_total",
+            "Number of images on docker daemon",
+            self.images.clone(),
+        );
+        registry.register_with_unit(
+            "memory",
+            "Total memory of docker host",
+            Unit::Bytes,
+            self.memory.clone(),
+        );
+        registry.register(
+            "cpus",
+            "Number of logical CPUs of docker host",
+            self.cpus.clone(),
+        );
+    }
+}
+
+/// metrics about the exporter itself
+#[derive(Default)]
+pub struct DockerStatSelfMetrics {
+    pub up: Gauge,
+    pub data_age: Gauge<f64, AtomicU64>,
+    pub truncated: Gauge,
+    pub cgroup_version: Gauge,
+    pub polling_interval: Gauge<f64, AtomicU64>,
+    pub containers_added: Counter,
+    pub containers_removed: Counter,
+    pub inflight_collections: Gauge,
+    pub slow_polls: Counter,
+}
+
+impl DockerStatSelfMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "up",
+            "Whether polling docker stats succeeds, 0 after consecutive failures reach the threshold",
+            self.up.clone(),
+        );
+        registry.register_with_unit(
+            "data_age",
+            "Time since the served docker stats were collected",
+            Unit::Seconds,
+            self.data_age.clone(),
+        );
+        registry.register(
+            "truncated",
+            "Count of containers left out of metrics by the container cap",
+            self.truncated.clone(),
+        );
+        registry.register(
+            "cgroup_version",
+            "Cgroup version of the host detected from stats responses, 0 until detected",
+            self.cgroup_version.clone(),
+        );
+        registry.register_with_unit(
+            "polling_interval",
+            "Configured interval between docker stats polls",
+            Unit::Seconds,
+            self.polling_interval.clone(),
+        );
+        registry.register(
+            "containers_added",
+            "Containers appeared since the previous poll, summed over polls",
+            self.containers_added.clone(),
+        );
+        registry.register(
+            "containers_removed",
+            "Containers gone since the previous poll, summed over polls",
+            self.containers_removed.clone(),
+        );
+        registry.register(
+            "inflight_collections",
+            "Number of container stats calls currently awaiting the docker api",
+            self.inflight_collections.clone(),
+        );
+        registry.register(
+            "slow_polls",
+            "Polls whose collection and parsing took longer than the polling interval",
+            self.slow_polls.clone(),
+        );
+    }
+}
+
+/// resource usage of the exporter process, registered where `/proc` exists
+#[derive(Default)]
+pub struct DockerStatProcessMetrics {
+    pub resident_memory: Gauge,
+    pub cpu_seconds: Counter<f64, AtomicU64>,
+}
+
+impl DockerStatProcessMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "process_resident_memory",
+            "Resident memory of the exporter process",
+            Unit::Bytes,
+            self.resident_memory.clone(),
+        );
+        registry.register_with_unit(
+            "process_cpu",
+            "User and system CPU time spent by the exporter process",
+            Unit::Seconds,
+            self.cpu_seconds.clone(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::encoding::text;
+
+    use super::*;
+
+    const ID: &str = "3f4e8a2b9c1d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f";
+
+    fn encode(metrics: &DockerStatContainerMetrics, histograms: bool) -> String {
+        let mut registry = Registry::with_prefix("container");
+        metrics.register(&mut registry, histograms, &MetricUnits::default());
+        let mut body = String::new();
+        text::encode(&mut body, &registry).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_default_labels() {
+        let metrics = DockerStatContainerMetrics::default();
+        let labels = container_labels(ID, "web", &ContainerLabelOptions::default());
+        metrics.cpu_usage.get_or_create(&labels).set(0.5);
+
+        let body = encode(&metrics, false);
+        assert!(body.contains(&format!("id=\"/system.slice/docker-{}.scope\"", ID)));
+        assert!(body.contains("name=\"web\""));
+        assert!(!body.contains("short_id"));
+    }
+
+    #[test]
+    fn test_short_id_label() {
+        let metrics = DockerStatContainerMetrics::default();
+        let options = ContainerLabelOptions {
+            short_id_label: true,
+            ..Default::default()
+        };
+        metrics
+            .cpu_usage
+            .get_or_create(&container_labels(ID, "web", &options))
+            .set(0.5);
+
+        let body = encode(&metrics, false);
+        assert!(body.contains(&format!("id=\"/system.slice/docker-{}.scope\"", ID)));
+        assert!(body.contains("short_id=\"3f4e8a2b9c1d\""));
+    }
+
+    #[test]
+    fn test_cgroup_driver_id_label() {
+        let id_label = |cgroup_driver| {
+            let options = ContainerLabelOptions {
+                cgroup_driver,
+                ..Default::default()
+            };
+            container_labels(ID, "web", &options)[0].1.clone()
+        };
+        assert_eq!(
+            id_label(CgroupDriver::Systemd),
+            format!("/system.slice/docker-{}.scope", ID)
+        );
+        assert_eq!(id_label(CgroupDriver::Cgroupfs), format!("/docker/{}", ID));
+    }
+
+    #[test]
+    fn test_histograms() {
+        let metrics = DockerStatContainerMetrics::default();
+        let labels = container_labels(ID, "web", &ContainerLabelOptions::default());
+        metrics
+            .cpu_usage_histogram
+            .get_or_create(&labels)
+            .observe(0.3);
+        metrics
+            .cpu_usage_histogram
+            .get_or_create(&labels)
+            .observe(1.5);
+        metrics
+            .mem_usage_histogram
+            .get_or_create(&labels)
+            .observe(1e8);
+
+        let body = encode(&metrics, true);
+        assert!(body.contains("# TYPE container_cpu_usage_ratio_histogram histogram"));
+        assert!(body.contains("container_cpu_usage_ratio_histogram_count{"));
+        assert!(body.contains("# TYPE container_memory_usage_bytes_histogram histogram"));
+        assert!(!encode(&metrics, false).contains("histogram"));
+
+        // histograms of other containers are left out
+        let db = container_labels(
+            "b".repeat(64).as_str(),
+            "db",
+            &ContainerLabelOptions::default(),
+        );
+        metrics.cpu_usage_histogram.get_or_create(&db).observe(0.1);
+        let mut registry = Registry::with_prefix("container");
+        metrics.register_histograms_of(&mut registry, &[labels]);
+        let mut body = String::new();
+        text::encode(&mut body, &registry).unwrap();
+        assert!(body.contains("# TYPE container_cpu_usage_ratio_histogram histogram"));
+        assert!(body.contains("container_cpu_usage_ratio_histogram_count{id="));
+        assert!(body.contains("name=\"web\"} 2"));
+        assert!(body.contains("container_memory_usage_bytes_histogram_count{"));
+        assert!(!body.contains("name=\"db\""));
+    }
+
+    #[test]
+    fn test_collection_duration() {
+        let metrics = DockerStatContainerMetrics::default();
+        let labels = container_labels(ID, "web", &ContainerLabelOptions::default());
+        metrics
+            .collection_duration
+            .get_or_create(&labels)
+            .set(0.125);
+
+        let body = encode(&metrics, false);
+        assert!(body.contains("# UNIT container_stats_collection_duration_seconds seconds"));
+        assert!(body.contains(&format!(
+            "container_stats_collection_duration_seconds{{id=\"/system.slice/docker-{}.scope\",name=\"web\"}} 0.125",
+            ID
+        )));
+    }
+
+    #[test]
+    fn test_name_regex_capture() {
+        let options = ContainerLabelOptions {
+            name_regex: Some(Regex::new(r"^[^-]+-(.+)-\d+$").unwrap()),
+            ..Default::default()
+        };
+        let name = |container_name: &str| {
+            container_labels(ID, container_name, &options)
+                .into_iter()
+                .find(|(k, _)| k == "name")
+                .unwrap()
+                .1
+        };
+
+        assert_eq!(name("shop-web-1"), "web");
+        assert_eq!(name("shop-payment-api-2"), "payment-api");
+        // not a compose name
+        assert_eq!(name("standalone"), "standalone");
+    }
+
+    #[test]
+    fn test_set_counter() {
+        let family = CounterFamily::default();
+        let labels = container_labels(ID, "web", &ContainerLabelOptions::default());
+
+        set_counter(&family, &labels, 10);
+        set_counter(&family, &labels, 25);
+        assert_eq!(family.get_or_create(&labels).get(), 25);
+
+        // source reset
+        set_counter(&family, &labels, 4);
+        assert_eq!(family.get_or_create(&labels).get(), 4);
+    }
+
+    #[test]
+    fn test_remove() {
+        let metrics = DockerStatContainerMetrics::default();
+        let options = ContainerLabelOptions::default();
+        let web = container_labels(ID, "web", &options);
+        let db = container_labels("0123456789ab", "db", &options);
+        metrics.mem_usage.get_or_create(&web).set(100);
+        metrics.mem_usage.get_or_create(&db).set(200);
+
+        metrics.remove(&db);
+
+        let body = encode(&metrics, false);
+        assert!(body.contains("name=\"web\""));
+        assert!(!body.contains("name=\"db\""));
     }
 }
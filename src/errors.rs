@@ -0,0 +1,135 @@
+use std::{fmt, io, time::Duration};
+
+use actix_web::{HttpResponse, ResponseError, http::StatusCode, http::header::ContentType};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    /// docker daemon is unreachable or refused the connection
+    #[error("connect docker failed: {0}")]
+    Connect(#[source] bollard::errors::Error),
+
+    /// docker host argument can not be connected to
+    #[error("invalid docker host: {0}")]
+    InvalidHost(String),
+
+    #[error("list containers failed: {0}")]
+    ListContainers(#[source] bollard::errors::Error),
+
+    /// inspect api failed for the container of `--container`
+    #[error("inspect container failed: {0}")]
+    Inspect(#[source] bollard::errors::Error),
+
+    #[error("list containers timed out after {0:?}")]
+    ListTimeout(Duration),
+
+    /// stats api failed or returned incomplete data
+    #[error("container stats unavailable: {0}")]
+    Stats(String),
+
+    #[error("encode metrics failed: {0}")]
+    Encode(#[from] fmt::Error),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    /// docker version api failed
+    #[error("docker version failed: {0}")]
+    Version(#[source] bollard::errors::Error),
+
+    #[error("state file failed: {0}")]
+    StateFile(#[source] io::Error),
+
+    #[error("dump file failed: {0}")]
+    DumpFile(#[source] io::Error),
+
+    /// state or stats failed to serialize to or parse from JSON
+    #[error("serialize failed: {0}")]
+    Serialize(#[source] serde_json::Error),
+
+    /// receiver of `--remote-write` failed or timed out
+    #[error("remote write failed: {0}")]
+    RemoteWrite(String),
+
+    /// file of `--relabel-config` is unreadable or has an invalid rule
+    #[error("relabel config invalid: {0}")]
+    RelabelConfig(String),
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Connect(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::ListContainers(_)
+            | AppError::Inspect(_)
+            | AppError::Version(_)
+            | AppError::Stats(_)
+            | AppError::RemoteWrite(_) => StatusCode::BAD_GATEWAY,
+            AppError::ListTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::InvalidHost(_)
+            | AppError::Encode(_)
+            | AppError::StateFile(_)
+            | AppError::DumpFile(_)
+            | AppError::Serialize(_)
+            | AppError::RelabelConfig(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type(ContentType::plaintext())
+            .body(self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_code() {
+        let connect = AppError::Connect(bollard::errors::Error::SocketNotFoundError(
+            "/var/run/docker.sock".to_owned(),
+        ));
+        assert_eq!(connect.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let list = AppError::ListContainers(bollard::errors::Error::RequestTimeoutError);
+        assert_eq!(list.status_code(), StatusCode::BAD_GATEWAY);
+        let inspect = AppError::Inspect(bollard::errors::Error::RequestTimeoutError);
+        assert_eq!(inspect.status_code(), StatusCode::BAD_GATEWAY);
+        let version = AppError::Version(bollard::errors::Error::RequestTimeoutError);
+        assert_eq!(version.status_code(), StatusCode::BAD_GATEWAY);
+        let serialize = AppError::Serialize(serde_json::from_str::<u8>("x").unwrap_err());
+        assert_eq!(serialize.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            AppError::ListTimeout(Duration::from_secs(10)).status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            AppError::Stats("no usage".to_owned()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            AppError::RemoteWrite("status 400".to_owned()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            AppError::Encode(fmt::Error).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            AppError::NotFound("id".to_owned()).status_code(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        let resp = AppError::NotFound("container 3f4e8a2b9c1d".to_owned()).error_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+    }
+}
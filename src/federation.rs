@@ -0,0 +1,331 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use futures_util::future::join_all;
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Uri, body::Bytes, header};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use tracing::warn;
+
+const OPENMETRICS_ACCEPT: &str =
+    "application/openmetrics-text; version=1.0.0, text/plain; version=0.0.4;q=0.5";
+
+/// URL of a peer exporter, `http` only, `/metrics` when the path is omitted
+pub fn parse_peer_url(s: &str) -> Result<Uri, String> {
+    let uri = s.parse::<Uri>().map_err(|e| e.to_string())?;
+    if uri.scheme_str() != Some("http") {
+        return Err("only http:// peers are supported".to_owned());
+    }
+    let authority = uri.authority().ok_or("missing host")?;
+    let path = match uri.path_and_query().map(|p| p.as_str()) {
+        None | Some("/") => "/metrics",
+        Some(path) => path,
+    };
+    format!("http://{}{}", authority, path)
+        .parse()
+        .map_err(|e: hyper::http::uri::InvalidUri| e.to_string())
+}
+
+/// `source` label value of a peer exporter, its host and port
+fn peer_source(peer: &Uri) -> String {
+    peer.authority().map(|a| a.to_string()).unwrap_or_default()
+}
+
+/// peer exporters whose `/metrics` are merged into ours with a `source` label
+#[derive(Debug)]
+pub struct Federation {
+    client: Client<HttpConnector, Empty<Bytes>>,
+    peers: Vec<Uri>,
+    timeout: Duration,
+}
+
+impl Federation {
+    pub fn new(peers: Vec<Uri>, timeout: Duration) -> Self {
+        Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            peers,
+            timeout,
+        }
+    }
+
+    /// metrics text of a peer, or why fetching it failed, which is only logged
+    /// as a failed peer never fails the scrape
+    async fn fetch(&self, peer: &Uri) -> Result<String, String> {
+        let request = Request::get(peer.clone())
+            .header(header::ACCEPT, OPENMETRICS_ACCEPT)
+            .body(Empty::new())
+            .map_err(|e| e.to_string())?;
+        let fetch = async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("status {}", response.status()));
+            }
+            let body = response
+                .into_body()
+                .collect()
+                .await
+                .map_err(|e| e.to_string())?
+                .to_bytes();
+            String::from_utf8(body.to_vec()).map_err(|e| e.to_string())
+        };
+        tokio::time::timeout(self.timeout, fetch)
+            .await
+            .map_err(|_| format!("timed out after {:?}", self.timeout))?
+    }
+
+    /// `own` metrics text with the series of all peers fetched concurrently,
+    /// a failed peer is left out and reported by `docker_stats_federate_up`
+    pub async fn merge(&self, own: &str) -> String {
+        let fetched = join_all(self.peers.iter().map(|peer| self.fetch(peer))).await;
+
+        let mut exposition = Exposition::default();
+        exposition.add(own, None);
+        let mut up = String::from(
+            "# HELP docker_stats_federate_up Whether the last fetch of a federated peer exporter succeeded.\n\
+             # TYPE docker_stats_federate_up gauge\n",
+        );
+        for (peer, result) in self.peers.iter().zip(fetched) {
+            let source = peer_source(peer);
+            let ok = match result {
+                Ok(text) => {
+                    exposition.add(&text, Some(&source));
+                    true
+                }
+                Err(e) => {
+                    warn!("federate {} failed, error: {}", peer, e);
+                    false
+                }
+            };
+            let _ = writeln!(
+                up,
+                "docker_stats_federate_up{{source=\"{}\"}} {}",
+                escape_label_value(&source),
+                ok as u8
+            );
+        }
+        exposition.add(&up, None);
+        exposition.encode()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// sample line with a `source` label added, kept as it is when it has one
+fn with_source_label(sample: &str, source: &str) -> String {
+    let name_end = sample.find(['{', ' ']).unwrap_or(sample.len());
+    let (name, rest) = sample.split_at(name_end);
+    let label = format!("source=\"{}\"", escape_label_value(source));
+    match rest.strip_prefix('{') {
+        Some(labels) if labels.starts_with("source=") || labels.contains(",source=") => {
+            sample.to_owned()
+        }
+        Some(labels) if labels.starts_with('}') => format!("{}{{{}{}", name, label, labels),
+        Some(labels) => format!("{}{{{},{}", name, label, labels),
+        None => format!("{}{{{}}}{}", name, label, rest),
+    }
+}
+
+/// metadata and sample lines of a metric family
+#[derive(Debug, Default)]
+struct MetricFamily {
+    name: String,
+    metadata: Vec<String>,
+    samples: Vec<String>,
+}
+
+/// metric families of several metrics texts, in order of first appearance,
+/// each with the metadata of the text it first appeared in
+#[derive(Debug, Default)]
+struct Exposition {
+    families: Vec<MetricFamily>,
+    index: HashMap<String, usize>,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str) -> &mut MetricFamily {
+        let index = *self.index.entry(name.to_owned()).or_insert_with(|| {
+            self.families.push(MetricFamily {
+                name: name.to_owned(),
+                ..Default::default()
+            });
+            self.families.len() - 1
+        });
+        &mut self.families[index]
+    }
+
+    /// add families of a metrics text, labeling samples with `source`
+    fn add(&mut self, text: &str, source: Option<&str>) {
+        let mut current: Option<String> = None;
+        let mut described = Vec::new();
+        for line in text.lines() {
+            if line.is_empty() || line == "# EOF" {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut parts = comment.splitn(3, ' ');
+                let name = match (parts.next(), parts.next()) {
+                    (Some("HELP" | "TYPE" | "UNIT"), Some(name)) => name,
+                    _ => continue,
+                };
+                current = Some(name.to_owned());
+                let family = self.family(name);
+                // the first text describing a family owns its metadata
+                if family.metadata.is_empty() || described.contains(&family.name) {
+                    described.push(family.name.clone());
+                    family.metadata.push(line.to_owned());
+                }
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let sample_name = &line[..line.find(['{', ' ']).unwrap_or(line.len())];
+            let family_name = match &current {
+                Some(name) if sample_name.starts_with(name.as_str()) => name.clone(),
+                _ => sample_name.to_owned(),
+            };
+            let sample = match source {
+                Some(source) => with_source_label(line, source),
+                None => line.to_owned(),
+            };
+            self.family(&family_name).samples.push(sample);
+        }
+    }
+
+    /// OpenMetrics text of all families, terminated by `# EOF`
+    fn encode(&self) -> String {
+        let mut text = String::new();
+        for family in self.families.iter() {
+            for line in family.metadata.iter().chain(family.samples.iter()) {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_parse_peer_url() {
+        assert_eq!(
+            parse_peer_url("http://host-a:12096").unwrap().to_string(),
+            "http://host-a:12096/metrics"
+        );
+        assert_eq!(
+            parse_peer_url("http://host-a:12096/custom")
+                .unwrap()
+                .to_string(),
+            "http://host-a:12096/custom"
+        );
+        assert!(parse_peer_url("https://host-a:12096").is_err());
+        assert!(parse_peer_url("host-a:12096").is_err());
+    }
+
+    #[test]
+    fn test_with_source_label() {
+        assert_eq!(
+            with_source_label("up{job=\"a\"} 1", "host-a:12096"),
+            "up{source=\"host-a:12096\",job=\"a\"} 1"
+        );
+        assert_eq!(
+            with_source_label("up{} 1", "host-a"),
+            "up{source=\"host-a\"} 1"
+        );
+        assert_eq!(
+            with_source_label("up 1", "host-a"),
+            "up{source=\"host-a\"} 1"
+        );
+        assert_eq!(
+            with_source_label("up{job=\"a\",source=\"b\"} 1", "host-a"),
+            "up{job=\"a\",source=\"b\"} 1"
+        );
+    }
+
+    /// peer answering one request with `body`
+    async fn mock_peer(body: &'static str) -> Uri {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/openmetrics-text\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        parse_peer_url(&format!("http://{}", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_merge() {
+        let own = "# HELP container_pids Number of processes.\n\
+                   # TYPE container_pids gauge\n\
+                   container_pids{name=\"web\"} 3\n\
+                   # EOF\n";
+        let peer = mock_peer(
+            "# HELP container_pids Number of processes.\n\
+             # TYPE container_pids gauge\n\
+             container_pids{name=\"db\"} 7\n\
+             # HELP peer_only Only on peer.\n\
+             # TYPE peer_only counter\n\
+             peer_only_total 2\n\
+             # EOF\n",
+        )
+        .await;
+        // nothing listens on a dropped listener's port
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            parse_peer_url(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+        };
+        let federation = Federation::new(vec![peer.clone(), down.clone()], Duration::from_secs(5));
+
+        let merged = federation.merge(own).await;
+        assert_eq!(
+            merged,
+            format!(
+                "# HELP container_pids Number of processes.\n\
+                 # TYPE container_pids gauge\n\
+                 container_pids{{name=\"web\"}} 3\n\
+                 container_pids{{source=\"{peer}\",name=\"db\"}} 7\n\
+                 # HELP peer_only Only on peer.\n\
+                 # TYPE peer_only counter\n\
+                 peer_only_total{{source=\"{peer}\"}} 2\n\
+                 # HELP docker_stats_federate_up Whether the last fetch of a federated peer exporter succeeded.\n\
+                 # TYPE docker_stats_federate_up gauge\n\
+                 docker_stats_federate_up{{source=\"{peer}\"}} 1\n\
+                 docker_stats_federate_up{{source=\"{down}\"}} 0\n\
+                 # EOF\n",
+                peer = peer_source(&peer),
+                down = peer_source(&down),
+            )
+        );
+    }
+}
@@ -1,19 +1,133 @@
-use std::sync::Arc;
+use std::sync::{Arc, LazyLock};
 
 use actix_web::{
-    HttpResponse, Responder, Scope, get,
-    http::header::ContentType,
+    HttpMessage, HttpRequest, HttpResponse, Responder, Scope,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::{self, ContentType, HeaderValue},
+    middleware::Next,
     web::{self, Data, Query},
 };
-use prometheus_client::encoding::text;
+use futures_util::{StreamExt, future, stream};
 use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RequestId, RootSpanBuilder};
 
-use crate::usecases::DockerStatPollingWorker;
+use crate::{
+    errors::AppError,
+    federation::Federation,
+    metrics_json::metric_families,
+    usecases::{
+        ContainerFilter, DockerStatPollingWorker, LastDockerStats, SortOrder, StatsSortKey,
+        encode_sorted,
+    },
+};
+
+/// JSON schema of `/docker/stats` response, generated once
+static DOCKER_STATS_SCHEMA: LazyLock<String> =
+    LazyLock::new(|| serde_json::to_string(&schemars::schema_for!(LastDockerStats)).unwrap());
+
+/// Grafana dashboard of container metrics, `__PREFIX__` stands for the metric prefix
+const DASHBOARD_TEMPLATE: &str = include_str!("dashboard.json");
 
 #[derive(Debug)]
 pub struct SharedAppData {
     pub host: String,
     pub worker: Arc<DockerStatPollingWorker>,
+    /// content type of `/metrics` responses regardless of `Accept` header
+    pub metrics_content_type: Option<String>,
+    /// containers listed by `/docker/stats` at most
+    pub max_json_containers: Option<usize>,
+    /// peer exporters merged into unfiltered `/metrics`
+    pub federation: Option<Arc<Federation>>,
+}
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// longer `X-Request-Id` values are replaced by a generated one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// request id of the current request, echoed back as `X-Request-Id`
+#[derive(Debug, Clone)]
+struct XRequestId(String);
+
+/// root span builder recording `X-Request-Id` of the request, or a generated one
+pub struct RequestIdRootSpan;
+
+impl RootSpanBuilder for RequestIdRootSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_owned)
+            .or_else(|| {
+                request
+                    .extensions()
+                    .get::<RequestId>()
+                    .map(|id| id.to_string())
+            })
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        request
+            .extensions_mut()
+            .insert(XRequestId(request_id.clone()));
+        tracing_actix_web::root_span!(request, x_request_id = %request_id)
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// echo the request id picked by `RequestIdRootSpan` in the response
+pub async fn echo_request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let request_id = req.extensions().get::<XRequestId>().cloned();
+    let mut res = next.call(req).await?;
+    if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(&id.0).ok()) {
+        res.headers_mut()
+            .insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const PROMETHEUS_TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// media types of an `Accept` header, without their parameters
+fn accepted_media_types(accept: Option<&str>) -> Vec<&str> {
+    accept
+        .unwrap_or_default()
+        .split(',')
+        .map(|v| v.split(';').next().unwrap_or_default().trim())
+        .collect()
+}
+
+/// OpenMetrics unless the scraper only accepts `text/plain`
+fn negotiate_metrics_content_type(accept: Option<&str>) -> &'static str {
+    let media_types = accepted_media_types(accept);
+    if !media_types.contains(&"application/openmetrics-text") && media_types.contains(&"text/plain")
+    {
+        PROMETHEUS_TEXT_CONTENT_TYPE
+    } else {
+        OPENMETRICS_CONTENT_TYPE
+    }
+}
+
+/// JSON metric families for consumers asking for `application/json` only,
+/// scrapers listing a metrics text type keep getting text
+fn wants_json_metrics(accept: Option<&str>) -> bool {
+    let media_types = accepted_media_types(accept);
+    media_types.contains(&"application/json")
+        && !media_types.contains(&"application/openmetrics-text")
+        && !media_types.contains(&"text/plain")
 }
 
 #[get("/health")]
@@ -21,30 +135,174 @@ async fn health() -> impl Responder {
     HttpResponse::Ok()
 }
 
+#[derive(Debug, Deserialize)]
+struct GetDockerStatsQuery {
+    /// indent the response for reading by humans, compact by default
+    #[serde(default)]
+    pretty: bool,
+    /// sort containers server-side, listing order by default
+    sort: Option<StatsSortKey>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
 #[get("/docker/stats")]
-async fn get_docker_stats(app: Data<SharedAppData>) -> HttpResponse {
-    let stats = app.worker.get_last_container_stats().await;
+async fn get_docker_stats(
+    app: Data<SharedAppData>,
+    query: Query<GetDockerStatsQuery>,
+) -> HttpResponse {
+    let mut stats = app.worker.get_last_container_stats().await;
+    if let Some(key) = query.sort {
+        stats.sort(key, query.order);
+    }
+    if let Some(max) = app.max_json_containers {
+        stats.truncate(max);
+    }
+    let body = if query.pretty {
+        serde_json::to_string_pretty(&stats)
+    } else {
+        serde_json::to_string(&stats)
+    };
     HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(serde_json::to_string(&stats).unwrap())
-}
-
-#[get("/metrics")]
-async fn get_metrics(app: Data<SharedAppData>) -> HttpResponse {
-    let registry = app.worker.get_last_container_stats_registry().await;
-    let mut body = String::new();
-    match text::encode(&mut body, &registry) {
-        Ok(_) => {
-            return HttpResponse::Ok()
-                .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
-                .body(body);
+        .body(body.unwrap())
+}
+
+/// server-sent event of stats, cut to `max` containers
+fn stats_event(stats: &LastDockerStats, max: Option<usize>) -> web::Bytes {
+    let json = match max {
+        Some(max) => {
+            let mut stats = stats.clone();
+            stats.truncate(max);
+            serde_json::to_string(&stats)
         }
-        Err(e) => {
-            return HttpResponse::InternalServerError()
-                .content_type(ContentType::plaintext())
-                .body(e.to_string());
+        None => serde_json::to_string(stats),
+    };
+    web::Bytes::from(format!("data: {}\n\n", json.unwrap()))
+}
+
+/// last stats right away, then after every poll until the client disconnects
+#[get("/docker/stats/sse")]
+async fn get_docker_stats_sse(app: Data<SharedAppData>) -> HttpResponse {
+    let max = app.max_json_containers;
+    let receiver = app.worker.subscribe_stats();
+    let first = stats_event(&app.worker.get_last_container_stats().await, max);
+    let events = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(stats) => return Some((stats_event(&stats, max), receiver)),
+                // missed stats are superseded by the next ones
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
         }
-    }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // compressing would hold events back in the encoder
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(
+            stream::once(future::ready(first))
+                .chain(events)
+                .map(Ok::<_, actix_web::Error>),
+        )
+}
+
+/// containers of last stats without their stats, for cheap enumeration
+#[get("/docker/containers")]
+async fn get_docker_containers(app: Data<SharedAppData>) -> HttpResponse {
+    let containers = app.worker.get_last_containers().await;
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&containers).unwrap())
+}
+
+#[get("/docker/stats/schema")]
+async fn get_docker_stats_schema() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(DOCKER_STATS_SCHEMA.as_str())
+}
+
+#[derive(Debug, Deserialize)]
+struct GetMetricsQuery {
+    name: Option<String>,
+    id: Option<String>,
+}
+
+async fn get_metrics(
+    req: HttpRequest,
+    app: Data<SharedAppData>,
+    query: Query<GetMetricsQuery>,
+) -> Result<HttpResponse, AppError> {
+    let query = query.into_inner();
+    let filter = ContainerFilter {
+        name: query.name,
+        id: query.id,
+    };
+    let body = if filter.is_empty() {
+        let body = app.worker.get_encoded_metrics().await?;
+        match &app.federation {
+            Some(federation) => federation.merge(&body).await,
+            None => body,
+        }
+    } else {
+        let registry = app
+            .worker
+            .get_filtered_container_stats_registry(&filter)
+            .await;
+        encode_sorted(&registry)?
+    };
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    // a configured content type replaces negotiation, JSON included
+    let content_type = match &app.metrics_content_type {
+        Some(c) => c.clone(),
+        None if wants_json_metrics(accept) => {
+            let families =
+                serde_json::to_string(&metric_families(&body)).map_err(AppError::Serialize)?;
+            return Ok(HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .body(families));
+        }
+        None => negotiate_metrics_content_type(accept).to_owned(),
+    };
+    Ok(HttpResponse::Ok().content_type(content_type).body(body))
+}
+
+#[get("/metrics/influx")]
+async fn get_influx_metrics(app: Data<SharedAppData>) -> HttpResponse {
+    let stats = app.worker.get_last_container_stats().await;
+    HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(stats.to_influx_lines())
+}
+
+#[get("/dashboard.json")]
+async fn get_dashboard(app: Data<SharedAppData>) -> HttpResponse {
+    let prefix = app.worker.metric_prefix().await;
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(DASHBOARD_TEMPLATE.replace("__PREFIX__", &prefix))
+}
+
+#[get("/version")]
+async fn get_version(app: Data<SharedAppData>) -> Result<HttpResponse, AppError> {
+    let version = app.worker.get_version().await?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&version).unwrap()))
+}
+
+#[get("/debug/connection")]
+async fn get_debug_connection(app: Data<SharedAppData>) -> HttpResponse {
+    let state = app.worker.connection_state().await;
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&state).unwrap())
 }
 
 #[derive(Debug, Deserialize)]
@@ -53,22 +311,503 @@ struct GetCgroupStatsQuery {
 }
 
 #[get("/cgroupv2")]
+#[tracing::instrument(
+    skip_all,
+    fields(container_id = %query.id, container_name = tracing::field::Empty)
+)]
 async fn get_cgroup_stats(
     app: Data<SharedAppData>,
     query: Query<GetCgroupStatsQuery>,
-) -> HttpResponse {
-    match app.worker.get_cgroup2_data(&query.id).await {
-        Ok(s) => HttpResponse::Ok()
-            .content_type(ContentType::json())
-            .body(serde_json::to_string(&s).unwrap()),
-        Err(_) => HttpResponse::NotFound().finish(),
+) -> Result<HttpResponse, AppError> {
+    let stats = app.worker.get_cgroup2_data(&query.id).await?;
+    tracing::Span::current().record("container_name", stats.name().trim_start_matches('/'));
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(serde_json::to_string(&stats).unwrap()))
+}
+
+/// services mounted by `get_scopes`, set from CLI args
+#[derive(Debug, Clone)]
+pub struct ScopeConfig {
+    /// path of prometheus metrics endpoint
+    pub metrics_path: String,
+
+    /// mount `/docker/stats`, `/docker/stats/schema`, `/docker/containers` and `/cgroupv2`
+    pub json_api: bool,
+}
+impl Default for ScopeConfig {
+    fn default() -> Self {
+        Self {
+            metrics_path: "/metrics".to_owned(),
+            json_api: true,
+        }
     }
 }
 
-pub fn get_scopes(path: &str) -> Scope {
-    web::scope(path)
+/// `metrics_path` is registered at runtime, so it can be set from CLI args
+pub fn get_scopes(path: &str, config: &ScopeConfig) -> Scope {
+    let scope = web::scope(path)
         .service(health)
+        .route(&config.metrics_path, web::get().to(get_metrics))
+        .service(get_influx_metrics)
+        .service(get_dashboard)
+        .service(get_version);
+    if !config.json_api {
+        return scope;
+    }
+    scope
         .service(get_docker_stats)
-        .service(get_metrics)
+        .service(get_docker_stats_sse)
+        .service(get_docker_stats_schema)
+        .service(get_docker_containers)
         .service(get_cgroup_stats)
+        .service(get_debug_connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, test as actix_test};
+
+    use super::*;
+    use crate::usecases::{DockerContainerStat, DockerStatPollingOptions};
+
+    fn app_data() -> Data<SharedAppData> {
+        Data::new(SharedAppData {
+            host: "unix:///var/run/docker.sock".to_owned(),
+            worker: Arc::new(DockerStatPollingWorker::new(
+                "unix:///var/run/docker.sock",
+                2000,
+                DockerStatPollingOptions::default(),
+            )),
+            metrics_content_type: None,
+            max_json_containers: None,
+            federation: None,
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_docker_stats_gzip() {
+        use std::io::Read;
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .wrap(actix_web::middleware::Compress::default())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "gzip"
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body = actix_test::read_body(resp).await;
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&body[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert!(serde_json::from_str::<serde_json::Value>(&json).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .wrap(actix_web::middleware::from_fn(echo_request_id))
+                .wrap(tracing_actix_web::TracingLogger::<RequestIdRootSpan>::new())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/health")
+            .insert_header((REQUEST_ID_HEADER, "abc-123"))
+            .to_request();
+        let res = actix_test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+
+        let req = actix_test::TestRequest::get().uri("/health").to_request();
+        let res = actix_test::call_service(&app, req).await;
+        let generated = res.headers().get(REQUEST_ID_HEADER).unwrap();
+        assert!(uuid::Uuid::parse_str(generated.to_str().unwrap()).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_json() {
+        let data = app_data();
+        data.worker
+            .set_last_stats(vec![DockerContainerStat {
+                id: "aaaaaaaaaaaa".to_owned(),
+                name: "/web".to_owned(),
+                cpu_usage: 0.25,
+                cpu_total_usage_ns: 1_500_000_000,
+                ..Default::default()
+            }])
+            .await;
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(data)
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        let body: serde_json::Value = actix_test::read_body_json(resp).await;
+        let family = |name: &str| {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .find(|f| f["name"] == name)
+                .unwrap()
+                .clone()
+        };
+        let up = family("docker_stats_up");
+        assert_eq!(up["type"], "gauge");
+        assert_eq!(up["samples"][0]["name"], "docker_stats_up");
+
+        let cpu = family("container_cpu_usage_ratios");
+        assert_eq!(cpu["type"], "gauge");
+        assert_eq!(cpu["unit"], "ratios");
+        assert_eq!(cpu["help"], "Value of container logical CPU usage.");
+        assert_eq!(cpu["samples"].as_array().unwrap().len(), 1);
+        assert_eq!(cpu["samples"][0]["name"], "container_cpu_usage_ratios");
+        assert_eq!(cpu["samples"][0]["labels"]["name"], "web");
+        assert_eq!(cpu["samples"][0]["value"], 0.25);
+        let cpu_seconds = family("container_cpu_usage_seconds");
+        assert_eq!(cpu_seconds["type"], "counter");
+        assert_eq!(
+            cpu_seconds["samples"][0]["name"],
+            "container_cpu_usage_seconds_total"
+        );
+        assert_eq!(cpu_seconds["samples"][0]["value"], 1.5);
+
+        // scrapers listing a text type keep getting text
+        assert!(!wants_json_metrics(Some(
+            "application/openmetrics-text;version=1.0.0,application/json;q=0.1"
+        )));
+        assert!(!wants_json_metrics(None));
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_content_type() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+
+        for (accept, content_type) in [
+            (None, OPENMETRICS_CONTENT_TYPE),
+            (Some("*/*"), OPENMETRICS_CONTENT_TYPE),
+            (Some("text/plain"), PROMETHEUS_TEXT_CONTENT_TYPE),
+            (
+                Some("text/plain;version=0.0.4;q=0.5,*/*;q=0.1"),
+                PROMETHEUS_TEXT_CONTENT_TYPE,
+            ),
+            (
+                Some("application/openmetrics-text;version=1.0.0,text/plain;q=0.5"),
+                OPENMETRICS_CONTENT_TYPE,
+            ),
+        ] {
+            let mut req = actix_test::TestRequest::get().uri("/metrics");
+            if let Some(accept) = accept {
+                req = req.insert_header((header::ACCEPT, accept));
+            }
+            let resp = actix_test::call_service(&app, req.to_request()).await;
+            assert_eq!(
+                resp.headers().get(header::CONTENT_TYPE).unwrap(),
+                content_type,
+                "{:?}",
+                accept
+            );
+        }
+
+        // a configured content type is served even to JSON consumers
+        let data = app_data();
+        let data = Data::new(SharedAppData {
+            metrics_content_type: Some(PROMETHEUS_TEXT_CONTENT_TYPE.to_owned()),
+            host: data.host.clone(),
+            worker: data.worker.clone(),
+            max_json_containers: None,
+            federation: None,
+        });
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(data)
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get()
+            .uri("/metrics")
+            .insert_header((header::ACCEPT, "application/json"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            PROMETHEUS_TEXT_CONTENT_TYPE
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_custom_metrics_path() {
+        let app = actix_test::init_service(App::new().app_data(app_data()).service(get_scopes(
+            "",
+            &ScopeConfig {
+                metrics_path: "/prometheus".to_owned(),
+                ..Default::default()
+            },
+        )))
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/prometheus")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let body = String::from_utf8(actix_test::read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("docker_stats_up"));
+
+        let req = actix_test::TestRequest::get().uri("/metrics").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_disable_json_api() {
+        // without `id` query, a mounted `/cgroupv2` answers 400 rather than 404
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get().uri("/cgroupv2").to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let app = actix_test::init_service(App::new().app_data(app_data()).service(get_scopes(
+            "",
+            &ScopeConfig {
+                json_api: false,
+                ..Default::default()
+            },
+        )))
+        .await;
+
+        for uri in [
+            "/docker/stats",
+            "/docker/stats/schema",
+            "/docker/containers",
+            "/cgroupv2",
+        ] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert_eq!(
+                resp.status(),
+                actix_web::http::StatusCode::NOT_FOUND,
+                "{}",
+                uri
+            );
+        }
+        for uri in ["/health", "/metrics"] {
+            let req = actix_test::TestRequest::get().uri(uri).to_request();
+            let resp = actix_test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_docker_stats_pretty() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats")
+            .to_request();
+        let compact = actix_test::call_and_read_body(&app, req).await;
+        assert!(!compact.contains(&b'\n'));
+
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats?pretty=true")
+            .to_request();
+        let pretty = actix_test::call_and_read_body(&app, req).await;
+        assert!(pretty.starts_with(b"{\n  \"timestamp\""));
+
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats?pretty=yes")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats?sort=mem&order=desc")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats?sort=uptime")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_dashboard() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get()
+            .uri("/dashboard.json")
+            .to_request();
+        let body = actix_test::call_and_read_body(&app, req).await;
+        let body = std::str::from_utf8(&body).unwrap();
+
+        let dashboard: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            dashboard["panels"][0]["targets"][0]["expr"],
+            "container_cpu_usage_ratios{name=~\"$name\"}"
+        );
+        assert!(!body.contains("__PREFIX__"));
+    }
+
+    #[actix_web::test]
+    async fn test_docker_stats_sse() {
+        let data = app_data();
+        let worker = data.worker.clone();
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(data)
+                .wrap(actix_web::middleware::Compress::default())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get()
+            .uri("/docker/stats/sse")
+            .insert_header((header::ACCEPT_ENCODING, "gzip"))
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(
+            resp.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(
+            resp.headers().get(header::CONTENT_ENCODING).unwrap(),
+            "identity"
+        );
+
+        let mut body = std::pin::pin!(resp.into_body());
+        let mut next_event = async || {
+            let chunk = future::poll_fn(|cx| body.as_mut().poll_next(cx)).await;
+            let chunk = chunk.unwrap().unwrap();
+            let event = std::str::from_utf8(&chunk).unwrap().to_owned();
+            let json = event.strip_prefix("data: ").unwrap().strip_suffix("\n\n");
+            serde_json::from_str::<serde_json::Value>(json.unwrap()).unwrap()
+        };
+        // current stats on connect, then published ones
+        assert!(next_event().await["stats"].is_array());
+        worker.publish_stats().await;
+        assert!(next_event().await["stats"].is_array());
+    }
+
+    #[actix_web::test]
+    async fn test_debug_connection() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+        let req = actix_test::TestRequest::get()
+            .uri("/debug/connection")
+            .to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["host"], "unix:///var/run/docker.sock");
+        assert_eq!(body["scheme"], "unix");
+        assert!(body["fallback"].is_null());
+        assert!(body["last_ping_ok"].is_boolean());
+    }
+
+    /// span fields recorded by the handlers, by field name
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanFields {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = self.0.lock().unwrap();
+            attrs.record(
+                &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    fields.push((field.name().to_owned(), format!("{:?}", value)));
+                },
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_cgroup_stats_span_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let span_fields = SpanFields::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::Registry::default().with(span_fields.clone()),
+        );
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(app_data())
+                .service(get_scopes("", &ScopeConfig::default())),
+        )
+        .await;
+
+        let req = actix_test::TestRequest::get()
+            .uri("/cgroupv2?id=3f4e8a2b9c1d")
+            .to_request();
+        let resp = actix_test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+        assert!(
+            span_fields
+                .0
+                .lock()
+                .unwrap()
+                .contains(&("container_id".to_owned(), "3f4e8a2b9c1d".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_docker_stats_schema() {
+        let schema: serde_json::Value = serde_json::from_str(&DOCKER_STATS_SCHEMA).unwrap();
+        assert_eq!(schema["title"], "LastDockerStats");
+        assert!(schema["properties"]["stats"].is_object());
+        let stat = &schema["$defs"]["DockerContainerStat"]["properties"];
+        assert!(stat["cpu_usage"].is_object());
+        assert!(stat["mem_usage_mib"].is_object());
+    }
 }
@@ -1,20 +1,53 @@
 pub mod docker_stat_metrics;
+pub mod errors;
+pub mod federation;
 pub mod http_handlers;
+pub mod metrics_json;
+pub mod openmetrics;
+pub mod relabel;
+pub mod remote_write;
 pub mod usecases;
 
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 // use rayon::prelude::*;
 use actix_web::{
     App, HttpServer,
+    http::KeepAlive,
     web::{self},
 };
+use arc_swap::ArcSwap;
 use clap::Parser;
+use hyper::{
+    Uri,
+    header::{HeaderName, HeaderValue},
+};
 use prometheus_client::metrics::gauge::Gauge;
-use tracing::level_filters::LevelFilter;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni},
+    sign::CertifiedKey,
+};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, level_filters::LevelFilter, warn};
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::{Layer, layer::SubscriberExt};
 
-use crate::{http_handlers::SharedAppData, usecases::DockerStatPollingWorker};
+use crate::{
+    docker_stat_metrics::{CgroupDriver, ContainerLabelOptions, DataUnit, MetricUnits},
+    federation::Federation,
+    http_handlers::{ScopeConfig, SharedAppData},
+    remote_write::RemoteWriter,
+    usecases::{
+        ContainerSortKey, DockerStatPollingOptions, DockerStatPollingWorker, NameStrategy,
+        PrintFormat, self_container_id,
+    },
+};
 
 #[derive(Debug, clap::Parser)]
 struct CliArgs {
@@ -27,7 +60,7 @@ struct CliArgs {
     )]
     host: String,
 
-    /// HTTP/HTTPS server bind host
+    /// HTTP/HTTPS server bind host, unused when socket activated by systemd
     #[arg(short = 'b', long, default_value = "0.0.0.0:12096")]
     bind: String,
 
@@ -43,9 +76,468 @@ struct CliArgs {
     #[arg(long = "tls_cert", default_value = "./server.crt")]
     tls_cert_path: Option<String>,
 
-    /// polling interval in milliseconds
-    #[arg(short = 'i', long = "polling_interval", default_value_t = 2000)]
+    /// polling interval as a duration (eg. `2s`, `500ms`, `1m`) or in milliseconds
+    #[arg(short = 'i', long = "polling_interval", default_value_t = 2000, value_parser = parse_millis)]
     polling_millis: u64,
+
+    /// add a `short_id` label (first 12 chars of container id) to metrics
+    #[arg(long, default_value_t = false)]
+    short_id_label: bool,
+
+    /// expose CPU/memory histograms from sub-samples taken within each polling interval
+    #[arg(long, default_value_t = false)]
+    histograms: bool,
+
+    /// number of samples taken within each polling interval in histogram mode
+    #[arg(long, default_value_t = 4)]
+    histogram_samples: u32,
+
+    /// exclude containers created less than this many seconds ago
+    #[arg(long, default_value_t = 0)]
+    min_age_seconds: u64,
+
+    /// consecutive failed polls before `docker_stats_up` turns to 0
+    #[arg(long, default_value_t = 1)]
+    failure_threshold: u32,
+
+    /// value of `node` label attached to every metric, empty to omit [default: OS hostname]
+    #[arg(long)]
+    node_label: Option<String>,
+
+    /// read memory limit from cgroup files (/sys/fs/cgroup) instead of docker API
+    #[arg(long, default_value_t = false)]
+    cgroup_direct: bool,
+
+    /// random delay up to this duration or milliseconds added to every polling interval
+    #[arg(long, default_value_t = 0, value_parser = parse_millis)]
+    poll_jitter_ms: u64,
+
+    /// wait this duration or milliseconds before the first poll, eg. for a docker daemon starting alongside
+    #[arg(long, default_value_t = 0, value_parser = parse_millis)]
+    initial_delay_ms: u64,
+
+    /// directory of docker client TLS key.pem, cert.pem and ca.pem, enables TLS for tcp:// docker hosts
+    #[arg(long)]
+    docker_tls_cert_dir: Option<PathBuf>,
+
+    /// HTTP path of prometheus metrics endpoint
+    #[arg(long, default_value = "/metrics")]
+    metrics_path: String,
+
+    /// exit at startup if docker daemon is unreachable, instead of retrying in background
+    #[arg(long, default_value_t = false)]
+    require_docker: bool,
+
+    /// HTTPS certificate per SNI hostname as `host=cert.pem,key.pem`, repeatable,
+    /// replaces --tls_cert and --tls_key when given
+    #[arg(long, value_parser = parse_tls_sni)]
+    tls_sni: Vec<TlsSniCert>,
+
+    /// file to keep counter totals across exporter restarts, loaded at startup and written every poll
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// export metrics of at most this many containers, ranked by --max-containers-sort
+    #[arg(long)]
+    max_containers: Option<usize>,
+
+    /// ranking of containers kept under --max-containers
+    #[arg(long, value_enum, default_value_t = ContainerSortKey::Cpu)]
+    max_containers_sort: ContainerSortKey,
+
+    /// also monitor exited containers, exposing their exit code
+    #[arg(long, default_value_t = false)]
+    include_stopped: bool,
+
+    /// print container stats to stdout after every poll
+    #[arg(long, value_enum)]
+    print_format: Option<PrintFormat>,
+
+    /// timeout of listing containers as a duration or in milliseconds, a poll fails and retries after it, 0 to wait forever
+    #[arg(long, default_value_t = 10000, value_parser = parse_millis)]
+    list_timeout_ms: u64,
+
+    /// containers sampled per poll in rotation, others keep their last values, 0 to sample all
+    #[arg(long, default_value_t = 0)]
+    batch_size: usize,
+
+    /// reuse encoded /metrics output for this duration or milliseconds unless new stats are polled, 0 to disable
+    #[arg(long, default_value_t = 0, value_parser = parse_millis)]
+    metrics_cache_ttl_ms: u64,
+
+    /// regex whose first capture group of container name becomes the `name` label, eg. `^[^-]+-(.+)-\d+$`
+    #[arg(long, value_parser = regex::Regex::new)]
+    name_regex_capture: Option<regex::Regex>,
+
+    /// write last collected stats as JSON to this file on graceful shutdown
+    #[arg(long)]
+    dump_on_exit: Option<PathBuf>,
+
+    /// content type of /metrics responses, overrides negotiation by Accept header
+    #[arg(long)]
+    metrics_content_type: Option<String>,
+
+    /// also expose network traffic per interface with an `interface` label
+    #[arg(long)]
+    network_interface_label: bool,
+
+    /// also expose blkio per block device with `device` and `device_name` labels
+    #[arg(long)]
+    blkio_per_device: bool,
+
+    /// do not serve raw JSON endpoints /docker/stats and /cgroupv2
+    #[arg(long)]
+    disable_json_api: bool,
+
+    /// read stats from a stream per container held open across polls, instead of a request per poll
+    #[arg(long)]
+    stream_mode: bool,
+
+    /// list at most this many containers in /docker/stats, marking the response `truncated`
+    #[arg(long)]
+    max_json_containers: Option<usize>,
+
+    /// cgroup driver of docker daemon, sets the cgroup path of `id` label and `--cgroup-direct`
+    #[arg(long, value_enum, default_value_t = CgroupDriver::Systemd)]
+    cgroup_driver: CgroupDriver,
+
+    /// unit of network data metrics
+    #[arg(long, value_enum, default_value_t = DataUnit::Bytes)]
+    network_unit: DataUnit,
+
+    /// unit of network throughput metrics
+    #[arg(long, value_enum, default_value_t = DataUnit::Bits)]
+    throughput_unit: DataUnit,
+
+    /// export CPU usage in percent of one CPU (0-100 per CPU) as `cpu_usage_percent`, also in JSON, instead of ratio
+    #[arg(long)]
+    cpu_as_percent: bool,
+
+    /// sum gauges of compose service replicas into one series per service
+    #[arg(long)]
+    group_by_service: bool,
+
+    /// retries of each failed docker API call (list, stats, inspect) within a poll
+    #[arg(long, default_value_t = 0)]
+    docker_retries: u32,
+
+    /// average network and blkio throughputs over this many polling intervals, 1 for consecutive polls
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_window: u32,
+
+    /// export mounts of containers as `container_mount_info` series
+    #[arg(long)]
+    collect_mounts: bool,
+
+    /// write a self-signed server.crt and server.key for localhost to this directory and exit, fails when either exists
+    #[arg(long)]
+    generate_cert: Option<PathBuf>,
+
+    /// sum stats of swarm tasks into one series per swarm service on this node
+    #[arg(long)]
+    swarm: bool,
+
+    /// do not monitor the container the exporter itself runs in
+    #[arg(long)]
+    exclude_self: bool,
+
+    /// keep the last stats of a running container while its stats request fails or returns nothing
+    #[arg(long)]
+    retain_last_stat: bool,
+
+    /// monitor only this container, by name or id, instead of listing all containers
+    #[arg(long)]
+    container: Option<String>,
+
+    /// keep exporting containers missing from the latest poll with their last values and a `stale="true"` label for this duration or milliseconds, 0 to drop them at once
+    #[arg(long, default_value_t = 0, value_parser = parse_millis)]
+    explicit_stale: u64,
+
+    /// characters of the container command kept in the `command` label of `container_info`, 0 keeps it whole
+    #[arg(long, default_value_t = 128)]
+    command_label_max_len: usize,
+
+    /// name of containers with several names (eg. legacy links) used as `name` label
+    #[arg(long, value_enum, default_value_t = NameStrategy::First)]
+    name_strategy: NameStrategy,
+
+    /// report the last non-zero CPU usage and throughputs of paused containers instead of 0
+    #[arg(long)]
+    hold_rates_on_pause: bool,
+
+    /// seconds idle HTTP connections are kept open, 0 to disable keep-alive [default: 5]
+    #[arg(long)]
+    http_keep_alive_secs: Option<u64>,
+
+    /// seconds a client has to send request headers before the connection is closed [default: 5]
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    http_client_timeout_secs: Option<u64>,
+
+    /// log at info level and above only, leaving out debug logs
+    #[arg(short = 'q', long)]
+    quiet: bool,
+
+    /// log at this level and above, eg. `trace` for per-poll timing logs, replaces --quiet
+    #[arg(long, conflicts_with = "quiet")]
+    log_level: Option<LevelFilter>,
+
+    /// also fetch /metrics of this peer exporter (http://host:port[/path]) on every scrape and merge its series with a `source` label, repeatable
+    #[arg(long, value_parser = federation::parse_peer_url)]
+    federate: Vec<Uri>,
+
+    /// deadline of fetching each peer exporter as a duration or milliseconds
+    #[arg(long, default_value_t = 5000, value_parser = parse_millis)]
+    federate_timeout_ms: u64,
+
+    /// push metrics to this Prometheus remote-write receiver (http[s]://host[:port]/path) after every poll
+    #[arg(long, value_parser = remote_write::parse_remote_write_url)]
+    remote_write: Option<Uri>,
+
+    /// header sent with every remote-write request as `Name: value`, eg. `Authorization: Bearer <token>`, repeatable
+    #[arg(long, value_parser = remote_write::parse_remote_write_header)]
+    remote_write_header: Vec<(HeaderName, HeaderValue)>,
+
+    /// JSON file of Prometheus style relabel rules (replace, keep, drop, labeldrop) applied to the labels of every container series
+    #[arg(long)]
+    relabel_config: Option<PathBuf>,
+
+    /// monitor only containers labeled with this key set to `true`, eg. `prometheus.scrape`
+    #[arg(long, value_parser = parse_label_key)]
+    opt_in_label: Option<String>,
+}
+
+/// certificate and key served for a SNI hostname
+#[derive(Debug, Clone, PartialEq)]
+struct TlsSniCert {
+    host: String,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+/// keep-alive of HTTP connections, disabled with 0 seconds
+fn http_keep_alive(secs: u64) -> KeepAlive {
+    match secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    }
+}
+
+/// whether systemd passed listening sockets to this process, `LISTEN_PID`
+/// (when set) has to be our pid as the variables are inherited by children
+fn socket_activated(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> bool {
+    let for_us = listen_pid.is_none_or(|p| p.parse::<u32>().is_ok_and(|p| p == pid));
+    for_us && listen_fds.is_some_and(|n| n.parse::<u32>().is_ok_and(|n| n > 0))
+}
+
+/// first socket passed by systemd socket activation, `None` to bind `--bind`
+fn systemd_listener() -> Option<std::net::TcpListener> {
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    let listen_fds = std::env::var("LISTEN_FDS").ok();
+    if !socket_activated(
+        listen_pid.as_deref(),
+        listen_fds.as_deref(),
+        std::process::id(),
+    ) {
+        return None;
+    }
+    match listenfd::ListenFd::from_env().take_tcp_listener(0) {
+        Ok(Some(listener)) => {
+            info!(
+                "listening on socket from systemd {:?}",
+                listener.local_addr()
+            );
+            Some(listener)
+        }
+        Ok(None) => {
+            warn!("no TCP socket from systemd, binding --bind instead");
+            None
+        }
+        Err(e) => {
+            warn!(
+                "socket from systemd unusable, binding --bind instead, error: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// milliseconds of a duration like `2s`, `500ms` or `1m 30s`, or of a plain number of milliseconds
+fn parse_millis(s: &str) -> Result<u64, String> {
+    if let Ok(millis) = s.parse() {
+        return Ok(millis);
+    }
+    let duration = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    u64::try_from(duration.as_millis()).map_err(|e| e.to_string())
+}
+
+/// container label key, without `=` as docker label filters take `key=value`
+fn parse_label_key(s: &str) -> Result<String, String> {
+    let key = s.trim();
+    if key.is_empty() {
+        return Err("empty label key".to_owned());
+    }
+    if key.contains('=') {
+        return Err(format!("label key must not contain '=', got {}", key));
+    }
+    Ok(key.to_owned())
+}
+
+fn parse_tls_sni(s: &str) -> Result<TlsSniCert, String> {
+    let (host, paths) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected host=cert.pem,key.pem, got {}", s))?;
+    let (cert_path, key_path) = paths
+        .split_once(',')
+        .ok_or_else(|| format!("expected cert.pem,key.pem after {}=", host))?;
+    if host.is_empty() || cert_path.is_empty() || key_path.is_empty() {
+        return Err(format!("expected host=cert.pem,key.pem, got {}", s));
+    }
+    Ok(TlsSniCert {
+        host: host.to_owned(),
+        cert_path: PathBuf::from(cert_path),
+        key_path: PathBuf::from(key_path),
+    })
+}
+
+/// load PEM certificate chain and PKCS#8 key
+fn load_cert_and_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let mut certs_file = BufReader::new(File::open(cert_path)?);
+    let mut key_file = BufReader::new(File::open(key_path)?);
+
+    let tls_certs = rustls_pemfile::certs(&mut certs_file).collect::<Result<Vec<_>, _>>()?;
+    let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_file)
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no PKCS#8 key in {}", key_path.display()),
+            )
+        })??;
+    Ok((tls_certs, PrivateKeyDer::Pkcs8(tls_key)))
+}
+
+/// certificate chain and key ready to be served
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let (tls_certs, tls_key) = load_cert_and_key(cert_path, key_path)?;
+    let signing_key =
+        rustls::crypto::aws_lc_rs::sign::any_supported_type(&tls_key).map_err(io::Error::other)?;
+    Ok(CertifiedKey::new(tls_certs, signing_key))
+}
+
+/// server certificate reloaded from its files on SIGHUP, so rotated
+/// certificates are served without a restart
+#[derive(Debug)]
+struct ReloadableCertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: ArcSwap<CertifiedKey>,
+}
+
+impl ReloadableCertResolver {
+    fn new(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let certified_key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            certified_key: ArcSwap::from_pointee(certified_key),
+        })
+    }
+
+    /// swap in the certificate on disk, a failed load keeps the current one
+    fn reload(&self) -> io::Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        self.certified_key.store(Arc::new(certified_key));
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        self.certified_key.load_full()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// reload the certificate of `resolver` on every SIGHUP
+fn spawn_cert_reload_on_sighup(resolver: Arc<ReloadableCertResolver>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!(
+                "listen SIGHUP failed, TLS certificate reload disabled, error: {}",
+                e
+            );
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(_) => info!("reloaded TLS certificate {}", resolver.cert_path.display()),
+                Err(e) => error!(
+                    "reload TLS certificate {} failed, keep serving the current one, error: {}",
+                    resolver.cert_path.display(),
+                    e
+                ),
+            }
+        }
+    });
+}
+
+/// subject alternative names of `--generate-cert` certificates
+const SELF_SIGNED_SANS: [&str; 2] = ["localhost", "127.0.0.1"];
+
+/// create `path`, failing when it already exists, with the path in the error
+fn create_new_file(path: &Path) -> io::Result<File> {
+    File::create_new(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// write a self-signed certificate and its PKCS#8 key as `server.crt` and
+/// `server.key` into `dir`, for local HTTPS testing only, existing files are
+/// never overwritten
+fn generate_self_signed_cert(dir: &Path) -> io::Result<(PathBuf, PathBuf)> {
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", dir.display()),
+        ));
+    }
+
+    let sans = SELF_SIGNED_SANS.map(|san| san.to_owned()).to_vec();
+    let certified = rcgen::generate_simple_self_signed(sans).map_err(io::Error::other)?;
+    let cert_path = dir.join("server.crt");
+    let key_path = dir.join("server.key");
+    let mut cert_file = create_new_file(&cert_path)?;
+    let mut key_file = match create_new_file(&key_path) {
+        Ok(f) => f,
+        Err(e) => {
+            // leave no certificate without its key behind
+            drop(cert_file);
+            let _ = fs::remove_file(&cert_path);
+            return Err(e);
+        }
+    };
+    cert_file.write_all(certified.cert.pem().as_bytes())?;
+    key_file.write_all(certified.signing_key.serialize_pem().as_bytes())?;
+    Ok((cert_path, key_path))
+}
+
+/// resolve server certificate by SNI hostname of client hello
+fn sni_cert_resolver(certs: &[TlsSniCert]) -> ResolvesServerCertUsingSni {
+    let mut resolver = ResolvesServerCertUsingSni::new();
+    for sni in certs {
+        let certified_key = load_certified_key(&sni.cert_path, &sni.key_path).unwrap();
+        resolver.add(&sni.host, certified_key).unwrap();
+    }
+    resolver
 }
 
 #[test]
@@ -64,66 +556,375 @@ pub fn test_clone_gauge() {
     assert!(true);
 }
 
+#[test]
+pub fn test_http_timeouts() {
+    assert_eq!(http_keep_alive(0), KeepAlive::Disabled);
+    assert_eq!(
+        http_keep_alive(75),
+        KeepAlive::Timeout(Duration::from_secs(75))
+    );
+
+    let args =
+        CliArgs::try_parse_from(["docker-stat-prom", "--http-keep-alive-secs", "0"]).unwrap();
+    assert_eq!(args.http_keep_alive_secs, Some(0));
+    assert_eq!(args.http_client_timeout_secs, None);
+    assert!(
+        CliArgs::try_parse_from(["docker-stat-prom", "--http-client-timeout-secs", "0"]).is_err()
+    );
+    assert!(CliArgs::try_parse_from(["docker-stat-prom", "--http-keep-alive-secs", "-1"]).is_err());
+}
+
+#[test]
+pub fn test_log_level() {
+    let args = CliArgs::try_parse_from(["docker-stat-prom", "--log-level", "trace"]).unwrap();
+    assert_eq!(args.log_level, Some(LevelFilter::TRACE));
+    assert!(CliArgs::try_parse_from(["docker-stat-prom", "--log-level", "loud"]).is_err());
+    assert!(
+        CliArgs::try_parse_from(["docker-stat-prom", "--log-level", "warn", "--quiet"]).is_err()
+    );
+}
+
+#[test]
+pub fn test_socket_activated() {
+    assert!(socket_activated(Some("4242"), Some("1"), 4242));
+    // LISTEN_PID is optional, as with systemfd --no-pid
+    assert!(socket_activated(None, Some("2"), 4242));
+    // inherited from a parent
+    assert!(!socket_activated(Some("1"), Some("1"), 4242));
+    assert!(!socket_activated(Some("4242"), Some("0"), 4242));
+    assert!(!socket_activated(Some("4242"), None, 4242));
+    assert!(!socket_activated(None, None, 4242));
+    assert!(!socket_activated(Some("abc"), Some("1"), 4242));
+}
+
+#[test]
+pub fn test_parse_millis() {
+    assert_eq!(parse_millis("2000"), Ok(2000));
+    assert_eq!(parse_millis("0"), Ok(0));
+    assert_eq!(parse_millis("2s"), Ok(2000));
+    assert_eq!(parse_millis("500ms"), Ok(500));
+    assert_eq!(parse_millis("1m"), Ok(60_000));
+    assert_eq!(parse_millis("1m 30s"), Ok(90_000));
+    assert!(parse_millis("2 seconds ago").is_err());
+    assert!(parse_millis("-1").is_err());
+}
+
+#[test]
+pub fn test_parse_label_key() {
+    assert_eq!(
+        parse_label_key("prometheus.scrape"),
+        Ok("prometheus.scrape".to_owned())
+    );
+    assert!(parse_label_key("").is_err());
+    assert!(parse_label_key("prometheus.scrape=true").is_err());
+
+    let args = CliArgs::try_parse_from(["docker-stat-prom", "--opt-in-label", "prometheus.scrape"]);
+    assert_eq!(
+        args.unwrap().opt_in_label.as_deref(),
+        Some("prometheus.scrape")
+    );
+}
+
+#[test]
+pub fn test_reload_cert() {
+    let dir = std::env::temp_dir().join(format!("docker-stat-prom-{}", uuid::Uuid::new_v4()));
+    fs::create_dir(&dir).unwrap();
+    let (cert_path, key_path) = generate_self_signed_cert(&dir).unwrap();
+    let resolver = ReloadableCertResolver::new(cert_path.clone(), key_path.clone()).unwrap();
+    let before = resolver.current();
+
+    // rotated on disk, served after reload only
+    fs::remove_file(&cert_path).unwrap();
+    fs::remove_file(&key_path).unwrap();
+    generate_self_signed_cert(&dir).unwrap();
+    assert_eq!(resolver.current().cert, before.cert);
+    resolver.reload().unwrap();
+    let after = resolver.current();
+    assert_ne!(after.cert, before.cert);
+    let (certs, _) = load_cert_and_key(&cert_path, &key_path).unwrap();
+    assert_eq!(after.cert, certs);
+
+    // a broken file keeps the current certificate
+    fs::write(&key_path, "not a key").unwrap();
+    assert!(resolver.reload().is_err());
+    assert_eq!(resolver.current().cert, after.cert);
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+pub fn test_parse_tls_sni() {
+    assert_eq!(
+        parse_tls_sni("a.example.com=/tls/a.crt,/tls/a.key"),
+        Ok(TlsSniCert {
+            host: "a.example.com".to_owned(),
+            cert_path: PathBuf::from("/tls/a.crt"),
+            key_path: PathBuf::from("/tls/a.key"),
+        })
+    );
+    assert!(parse_tls_sni("a.example.com").is_err());
+    assert!(parse_tls_sni("a.example.com=/tls/a.crt").is_err());
+    assert!(parse_tls_sni("=/tls/a.crt,/tls/a.key").is_err());
+}
+
+#[test]
+pub fn test_generate_self_signed_cert() {
+    let dir = std::env::temp_dir().join(format!("docker-stat-prom-{}", uuid::Uuid::new_v4()));
+    fs::create_dir(&dir).unwrap();
+
+    let (cert_path, key_path) = generate_self_signed_cert(&dir).unwrap();
+    let (certs, _) = load_cert_and_key(&cert_path, &key_path).unwrap();
+    assert_eq!(certs.len(), 1);
+
+    // existing files are kept
+    let err = generate_self_signed_cert(&dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    let (kept, _) = load_cert_and_key(&cert_path, &key_path).unwrap();
+    assert_eq!(kept, certs);
+    fs::remove_file(&cert_path).unwrap();
+    let err = generate_self_signed_cert(&dir).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert!(!cert_path.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(generate_self_signed_cert(&dir).is_err());
+}
+
 #[tokio::main]
 async fn main() {
-    let stdout_log = tracing_subscriber::fmt::layer().with_filter(LevelFilter::DEBUG);
+    let args = CliArgs::parse();
+
+    let log_level = match args.log_level {
+        Some(level) => level,
+        None if args.quiet => LevelFilter::INFO,
+        None => LevelFilter::DEBUG,
+    };
+    let stdout_log = tracing_subscriber::fmt::layer().with_filter(log_level);
 
     let _ = tracing::subscriber::set_global_default(
         tracing_subscriber::Registry::default().with(stdout_log),
     );
 
-    let args = CliArgs::parse();
+    if let Some(dir) = &args.generate_cert {
+        match generate_self_signed_cert(dir) {
+            Ok((cert_path, key_path)) => {
+                info!(
+                    "generated self-signed {} and {} for {}",
+                    cert_path.display(),
+                    key_path.display(),
+                    SELF_SIGNED_SANS.join(", ")
+                );
+                return;
+            }
+            Err(e) => {
+                error!("generate certificate failed, error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let node_label = args
+        .node_label
+        .or_else(|| gethostname::gethostname().into_string().ok())
+        .filter(|node| !node.is_empty());
+
+    let exclude_container = if args.exclude_self {
+        let cgroup = fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+        let id = self_container_id(&cgroup, std::env::var("HOSTNAME").ok().as_deref());
+        match &id {
+            Some(id) => info!("excluding own container {}", id),
+            None => warn!("own container not found, --exclude-self has no effect"),
+        }
+        id
+    } else {
+        None
+    };
+
+    let relabel_rules = match &args.relabel_config {
+        Some(path) => match relabel::load_relabel_config(path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Vec::new(),
+    };
 
     let polling_stat_worker = Arc::new(DockerStatPollingWorker::new(
         &args.host,
         args.polling_millis,
+        DockerStatPollingOptions {
+            label_options: ContainerLabelOptions {
+                short_id_label: args.short_id_label,
+                name_regex: args.name_regex_capture,
+                cgroup_driver: args.cgroup_driver,
+            },
+            histograms: args.histograms,
+            histogram_samples: args.histogram_samples,
+            min_age: Duration::from_secs(args.min_age_seconds),
+            failure_threshold: args.failure_threshold,
+            node_label,
+            cgroup_direct: args.cgroup_direct,
+            poll_jitter: Duration::from_millis(args.poll_jitter_ms),
+            docker_tls_cert_dir: args.docker_tls_cert_dir,
+            state_file: args.state_file,
+            max_containers: args.max_containers,
+            max_containers_sort: args.max_containers_sort,
+            include_stopped: args.include_stopped,
+            print_format: args.print_format,
+            list_timeout: Duration::from_millis(args.list_timeout_ms),
+            batch_size: args.batch_size,
+            metrics_cache_ttl: Duration::from_millis(args.metrics_cache_ttl_ms),
+            network_interface_label: args.network_interface_label,
+            blkio_per_device: args.blkio_per_device,
+            stream_mode: args.stream_mode,
+            units: MetricUnits {
+                network: args.network_unit,
+                throughput: args.throughput_unit,
+                cpu_percent: args.cpu_as_percent,
+            },
+            group_by_service: args.group_by_service || args.swarm,
+            docker_retries: args.docker_retries,
+            rate_window: args.rate_window,
+            collect_mounts: args.collect_mounts,
+            swarm: args.swarm,
+            exclude_container,
+            retain_last_stat: args.retain_last_stat,
+            container: args.container.clone(),
+            explicit_stale: Duration::from_millis(args.explicit_stale),
+            command_label_max_len: args.command_label_max_len,
+            name_strategy: args.name_strategy,
+            hold_rates_on_pause: args.hold_rates_on_pause,
+            initial_delay: Duration::from_millis(args.initial_delay_ms),
+            relabel_rules,
+            opt_in_label: args.opt_in_label,
+        },
     ));
+    if let Err(e) = polling_stat_worker.load_state().await {
+        warn!("load state file failed, counters start over, error: {}", e);
+    }
+    match polling_stat_worker.ping().await {
+        Ok(version) => info!(
+            "connected to docker daemon {} (API {})",
+            version.daemon_version.as_deref().unwrap_or("unknown"),
+            version.api_version
+        ),
+        Err(e) if args.require_docker => {
+            error!(
+                "docker daemon at {} is unreachable, error: {}",
+                args.host, e
+            );
+            std::process::exit(1);
+        }
+        Err(e) => warn!(
+            "docker daemon at {} is unreachable, keep retrying, error: {}",
+            args.host, e
+        ),
+    }
     polling_stat_worker.spawn_polling_stat_task(polling_stat_worker.clone());
+    if let Some(url) = args.remote_write.clone() {
+        match RemoteWriter::new(url, args.remote_write_header.clone()) {
+            Ok(writer) => {
+                Arc::new(writer).spawn(polling_stat_worker.clone());
+            }
+            Err(e) => {
+                error!("remote write setup failed, error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
     let docker_host_4_servr = args.host.clone();
     let worker_4_server = polling_stat_worker.clone();
+    let scope_config = ScopeConfig {
+        metrics_path: args.metrics_path.clone(),
+        json_api: !args.disable_json_api,
+    };
+    let metrics_content_type = args.metrics_content_type.clone();
+    let federation = (!args.federate.is_empty()).then(|| {
+        Arc::new(Federation::new(
+            args.federate.clone(),
+            Duration::from_millis(args.federate_timeout_ms),
+        ))
+    });
     let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(SharedAppData {
                 host: docker_host_4_servr.clone(),
                 worker: worker_4_server.clone(),
+                metrics_content_type: metrics_content_type.clone(),
+                max_json_containers: args.max_json_containers,
+                federation: federation.clone(),
             }))
-            .wrap(TracingLogger::default())
-            .service(http_handlers::get_scopes(""))
+            .wrap(actix_web::middleware::Compress::default())
+            .wrap(actix_web::middleware::from_fn(
+                http_handlers::echo_request_id,
+            ))
+            .wrap(TracingLogger::<http_handlers::RequestIdRootSpan>::new())
+            .service(http_handlers::get_scopes("", &scope_config))
     })
     .workers(4);
+    // actix defaults unless set
+    let http_server = match args.http_keep_alive_secs {
+        Some(secs) => http_server.keep_alive(http_keep_alive(secs)),
+        None => http_server,
+    };
+    let http_server = match args.http_client_timeout_secs {
+        Some(secs) => http_server.client_request_timeout(Duration::from_secs(secs)),
+        None => http_server,
+    };
 
+    let listener = systemd_listener();
     let server = if args.bind_secure {
         rustls::crypto::aws_lc_rs::default_provider()
             .install_default()
             .unwrap();
 
-        let mut certs_file = BufReader::new(File::open(args.tls_cert_path.unwrap()).unwrap());
-        let mut key_file = BufReader::new(File::open(args.tls_key_path.unwrap()).unwrap());
-
-        // load TLS certs and key
-        // to create a self-signed temporary cert for testing:
-        let tls_certs = rustls_pemfile::certs(&mut certs_file)
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
-        let tls_key = rustls_pemfile::pkcs8_private_keys(&mut key_file)
-            .next()
-            .unwrap()
-            .unwrap();
-
         // set up TLS config options
-        let tls_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(tls_certs, rustls::pki_types::PrivateKeyDer::Pkcs8(tls_key))
-            .unwrap();
+        let tls_config = if args.tls_sni.is_empty() {
+            // load TLS certs and key, reloaded on SIGHUP
+            let resolver = Arc::new(
+                ReloadableCertResolver::new(
+                    PathBuf::from(args.tls_cert_path.unwrap()),
+                    PathBuf::from(args.tls_key_path.unwrap()),
+                )
+                .unwrap(),
+            );
+            spawn_cert_reload_on_sighup(resolver.clone());
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(resolver)
+        } else {
+            rustls::ServerConfig::builder()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(sni_cert_resolver(&args.tls_sni)))
+        };
 
-        http_server
-            .bind_rustls_0_23(args.bind, tls_config)
-            .unwrap()
-            .run()
+        match listener {
+            Some(listener) => http_server.listen_rustls_0_23(listener, tls_config),
+            None => http_server.bind_rustls_0_23(args.bind, tls_config),
+        }
+        .unwrap()
+        .run()
     } else {
-        http_server.bind(args.bind).unwrap().run()
+        match listener {
+            Some(listener) => http_server.listen(listener),
+            None => http_server.bind(args.bind),
+        }
+        .unwrap()
+        .run()
     };
 
     let _ = tokio::spawn(server).await;
+
+    if let Err(e) = polling_stat_worker.save_state().await {
+        error!("save state file failed, error: {}", e);
+    }
+    if let Some(path) = &args.dump_on_exit {
+        match polling_stat_worker.dump_last_stats(path).await {
+            Ok(_) => info!("dumped last stats to {}", path.display()),
+            Err(e) => error!("dump last stats to {} failed, error: {}", path.display(), e),
+        }
+    }
 }
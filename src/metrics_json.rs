@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::openmetrics::{parse_sample, sample_suffixes, unescape_help};
+
+/// sample of a metric family, eg. `container_cpu_usage_seconds_total` of the
+/// `container_cpu_usage_seconds` counter
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SampleJson {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+/// metric family of `/metrics` for `Accept: application/json`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricFamilyJson {
+    pub name: String,
+    pub help: String,
+    #[serde(rename = "type")]
+    pub metric_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    pub samples: Vec<SampleJson>,
+}
+
+impl MetricFamilyJson {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            help: String::new(),
+            metric_type: "unknown".to_owned(),
+            unit: None,
+            samples: Vec::new(),
+        }
+    }
+
+    /// whether a sample named `name` belongs to this family, by the sample
+    /// names its type allows
+    fn has_sample(&self, name: &str) -> bool {
+        name.strip_prefix(self.name.as_str()).is_some_and(|suffix| {
+            suffix.is_empty() || sample_suffixes(&self.metric_type).contains(&suffix)
+        })
+    }
+}
+
+/// metric families of an OpenMetrics text, in order of appearance, samples
+/// without metadata of their own family form a family of unknown type
+pub fn metric_families(text: &str) -> Vec<MetricFamilyJson> {
+    let mut families: Vec<MetricFamilyJson> = Vec::new();
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (kind, name, value) = match (parts.next(), parts.next()) {
+                (Some(kind), Some(name)) => (kind, name, parts.next().unwrap_or_default()),
+                _ => continue,
+            };
+            if !matches!(kind, "HELP" | "TYPE" | "UNIT") {
+                continue;
+            }
+            if families.last().is_none_or(|f| f.name != name) {
+                families.push(MetricFamilyJson::new(name));
+            }
+            let family = families.last_mut().unwrap();
+            match kind {
+                "HELP" => family.help = unescape_help(value),
+                "TYPE" => family.metric_type = value.to_owned(),
+                _ => family.unit = Some(value.to_owned()),
+            }
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, labels, value)) = parse_sample(line) else {
+            continue;
+        };
+        if families.last().is_none_or(|f| !f.has_sample(&name)) {
+            families.push(MetricFamilyJson::new(&name));
+        }
+        families.last_mut().unwrap().samples.push(SampleJson {
+            name,
+            labels: labels.into_iter().collect(),
+            value,
+        });
+    }
+    families
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_families() {
+        let text = "# HELP container_cpu_usage_seconds Cumulative CPU time consumed by container.\n\
+                    # TYPE container_cpu_usage_seconds counter\n\
+                    # UNIT container_cpu_usage_seconds seconds\n\
+                    container_cpu_usage_seconds_total{id=\"/docker/aaaaaaaaaaaa\",name=\"web\"} 12.5\n\
+                    # HELP docker_stats_up Whether polling docker stats succeeds.\n\
+                    # TYPE docker_stats_up gauge\n\
+                    docker_stats_up 1\n\
+                    docker_stats_up_extra 2\n\
+                    # EOF\n";
+        let families = metric_families(text);
+        assert_eq!(
+            serde_json::to_value(&families).unwrap(),
+            serde_json::json!([
+                {
+                    "name": "container_cpu_usage_seconds",
+                    "help": "Cumulative CPU time consumed by container.",
+                    "type": "counter",
+                    "unit": "seconds",
+                    "samples": [{
+                        "name": "container_cpu_usage_seconds_total",
+                        "labels": {"id": "/docker/aaaaaaaaaaaa", "name": "web"},
+                        "value": 12.5
+                    }]
+                },
+                {
+                    "name": "docker_stats_up",
+                    "help": "Whether polling docker stats succeeds.",
+                    "type": "gauge",
+                    "samples": [{"name": "docker_stats_up", "labels": {}, "value": 1.0}]
+                },
+                {
+                    "name": "docker_stats_up_extra",
+                    "help": "",
+                    "type": "unknown",
+                    "samples": [{"name": "docker_stats_up_extra", "labels": {}, "value": 2.0}]
+                }
+            ])
+        );
+    }
+}
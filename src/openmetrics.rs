@@ -0,0 +1,117 @@
+/// label value of a sample line up to its closing quote, unescaped
+fn parse_label_value(chars: &mut std::str::Chars) -> Option<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+}
+
+/// `HELP` text with `\\` and `\n` escapes resolved in one pass, so an escaped
+/// backslash before `n` stays a backslash
+pub fn unescape_help(text: &str) -> String {
+    let mut help = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => help.push('\n'),
+            ('\\', Some('\\')) => help.push('\\'),
+            _ => {
+                help.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    help
+}
+
+/// metric name, labels and value of a sample line
+pub type ParsedSample = (String, Vec<(String, String)>, f64);
+
+/// metric name, labels and value of an OpenMetrics sample line
+pub fn parse_sample(line: &str) -> Option<ParsedSample> {
+    let name_end = line.find(['{', ' '])?;
+    let (name, mut rest) = line.split_at(name_end);
+    let mut labels = Vec::new();
+    if let Some(label_text) = rest.strip_prefix('{') {
+        let mut chars = label_text.chars();
+        loop {
+            let mut label_name = String::new();
+            loop {
+                match chars.next()? {
+                    '=' => break,
+                    '}' if label_name.is_empty() => break,
+                    ',' if label_name.is_empty() => continue,
+                    c => label_name.push(c),
+                }
+            }
+            if label_name.is_empty() {
+                break;
+            }
+            if chars.next()? != '"' {
+                return None;
+            }
+            let value = parse_label_value(&mut chars)?;
+            labels.push((label_name, value));
+        }
+        rest = chars.as_str();
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some((name.to_owned(), labels, value))
+}
+
+/// suffixes of sample names of a metric family of `metric_type` besides the
+/// family name itself
+pub fn sample_suffixes(metric_type: &str) -> &'static [&'static str] {
+    match metric_type {
+        "counter" => &["_total", "_created"],
+        "histogram" => &["_bucket", "_count", "_sum", "_created"],
+        "gaugehistogram" => &["_bucket", "_gcount", "_gsum"],
+        "summary" => &["_count", "_sum", "_created"],
+        "info" => &["_info"],
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sample() {
+        assert_eq!(
+            parse_sample(
+                "container_cpu_usage_ratios{id=\"/docker/aaaaaaaaaaaa\",name=\"web \\\"1\\\"\"} 0.25"
+            ),
+            Some((
+                "container_cpu_usage_ratios".to_owned(),
+                vec![
+                    ("id".to_owned(), "/docker/aaaaaaaaaaaa".to_owned()),
+                    ("name".to_owned(), "web \"1\"".to_owned()),
+                ],
+                0.25
+            ))
+        );
+        assert_eq!(
+            parse_sample("docker_stats_up 1"),
+            Some(("docker_stats_up".to_owned(), Vec::new(), 1.))
+        );
+        assert_eq!(parse_sample("docker_stats_up"), None);
+    }
+
+    #[test]
+    fn test_unescape_help() {
+        assert_eq!(unescape_help("line\\nnext"), "line\nnext");
+        assert_eq!(unescape_help("back\\\\slash"), "back\\slash");
+        // escaped backslash followed by `n` is no newline
+        assert_eq!(unescape_help("C:\\\\new"), "C:\\new");
+        assert_eq!(unescape_help("trailing\\"), "trailing\\");
+    }
+}
@@ -0,0 +1,218 @@
+use std::{fs, path::Path};
+
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{docker_stat_metrics::ContainerLabels, errors::AppError};
+
+/// what a relabel rule does with the label set of a container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelabelAction {
+    /// set `target_label` to `replacement` expanded with the captures of
+    /// `regex`, removed when it expands empty
+    #[default]
+    Replace,
+    /// leave out the container unless `regex` matches
+    Keep,
+    /// leave out the container when `regex` matches
+    Drop,
+    /// remove labels whose name matches `regex`
+    Labeldrop,
+}
+
+/// rule of `--relabel-config` as written in the file, field names follow
+/// Prometheus `relabel_configs`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRelabelRule {
+    #[serde(default)]
+    source_labels: Vec<String>,
+    #[serde(default = "default_separator")]
+    separator: String,
+    #[serde(default = "default_regex")]
+    regex: String,
+    target_label: Option<String>,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+    #[serde(default)]
+    action: RelabelAction,
+}
+
+fn default_separator() -> String {
+    ";".to_owned()
+}
+
+fn default_regex() -> String {
+    "(.*)".to_owned()
+}
+
+fn default_replacement() -> String {
+    "$1".to_owned()
+}
+
+/// relabel rule applied to the label set of every container series
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawRelabelRule")]
+pub struct RelabelRule {
+    source_labels: Vec<String>,
+    separator: String,
+    /// anchored at both ends like in Prometheus
+    regex: Regex,
+    target_label: Option<String>,
+    replacement: String,
+    action: RelabelAction,
+}
+
+impl TryFrom<RawRelabelRule> for RelabelRule {
+    type Error = String;
+
+    fn try_from(raw: RawRelabelRule) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&format!("^(?:{})$", raw.regex)).map_err(|e| e.to_string())?;
+        if raw.action == RelabelAction::Replace && raw.target_label.is_none() {
+            return Err("replace needs a target_label".to_owned());
+        }
+        Ok(Self {
+            source_labels: raw.source_labels,
+            separator: raw.separator,
+            regex,
+            target_label: raw.target_label,
+            replacement: raw.replacement,
+            action: raw.action,
+        })
+    }
+}
+
+impl RelabelRule {
+    /// values of `source_labels` joined by `separator`, empty for missing labels
+    fn source_value(&self, labels: &ContainerLabels) -> String {
+        self.source_labels
+            .iter()
+            .map(|name| {
+                labels
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map_or("", |(_, v)| v.as_str())
+            })
+            .collect::<Vec<_>>()
+            .join(&self.separator)
+    }
+
+    /// label set after this rule, `None` when the container is left out
+    fn apply(&self, mut labels: ContainerLabels) -> Option<ContainerLabels> {
+        match self.action {
+            RelabelAction::Keep if !self.regex.is_match(&self.source_value(&labels)) => None,
+            RelabelAction::Drop if self.regex.is_match(&self.source_value(&labels)) => None,
+            RelabelAction::Keep | RelabelAction::Drop => Some(labels),
+            RelabelAction::Labeldrop => {
+                labels.retain(|(name, _)| !self.regex.is_match(name));
+                Some(labels)
+            }
+            RelabelAction::Replace => {
+                let source = self.source_value(&labels);
+                let captures = match self.regex.captures(&source) {
+                    Some(c) => c,
+                    None => return Some(labels),
+                };
+                let mut value = String::new();
+                captures.expand(&self.replacement, &mut value);
+                let target = self.target_label.as_deref().unwrap_or_default();
+                labels.retain(|(name, _)| name != target);
+                if !value.is_empty() {
+                    labels.push((target.to_owned(), value));
+                }
+                Some(labels)
+            }
+        }
+    }
+}
+
+/// label set of a container after all `rules` in order, `None` when a rule
+/// leaves the container out
+pub fn relabel(rules: &[RelabelRule], labels: ContainerLabels) -> Option<ContainerLabels> {
+    rules
+        .iter()
+        .try_fold(labels, |labels, rule| rule.apply(labels))
+}
+
+/// rules of a `--relabel-config` file, a JSON array of rules
+pub fn load_relabel_config(path: &Path) -> Result<Vec<RelabelRule>, AppError> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| AppError::RelabelConfig(format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&text)
+        .map_err(|e| AppError::RelabelConfig(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(json: &str) -> Vec<RelabelRule> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn web_labels() -> ContainerLabels {
+        vec![
+            ("id".to_owned(), "/docker/aaaaaaaaaaaa".to_owned()),
+            ("name".to_owned(), "shop-web-1".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn test_relabel_keep_drop() {
+        let keep = rules(r#"[{"source_labels": ["name"], "regex": "shop-.*", "action": "keep"}]"#);
+        assert_eq!(relabel(&keep, web_labels()), Some(web_labels()));
+        // anchored, a partial match is no match
+        let keep = rules(r#"[{"source_labels": ["name"], "regex": "web", "action": "keep"}]"#);
+        assert_eq!(relabel(&keep, web_labels()), None);
+
+        let drop =
+            rules(r#"[{"source_labels": ["name"], "regex": ".*-web-.*", "action": "drop"}]"#);
+        assert_eq!(relabel(&drop, web_labels()), None);
+        let drop = rules(r#"[{"source_labels": ["name"], "regex": "db", "action": "drop"}]"#);
+        assert_eq!(relabel(&drop, web_labels()), Some(web_labels()));
+    }
+
+    #[test]
+    fn test_relabel_replace() {
+        // set a label from a capture of another, then rename `name` to `container`
+        let rules = rules(
+            r#"[
+                {"source_labels": ["name"], "regex": "([^-]+)-.*", "target_label": "project"},
+                {"source_labels": ["name"], "target_label": "container"},
+                {"regex": "name", "action": "labeldrop"}
+            ]"#,
+        );
+        assert_eq!(
+            relabel(&rules, web_labels()),
+            Some(vec![
+                ("id".to_owned(), "/docker/aaaaaaaaaaaa".to_owned()),
+                ("project".to_owned(), "shop".to_owned()),
+                ("container".to_owned(), "shop-web-1".to_owned()),
+            ])
+        );
+
+        // joined source labels, and an empty replacement removes the target
+        let rules = self::rules(
+            r#"[
+                {"source_labels": ["name", "id"], "separator": "@", "regex": "(.*)@/docker/(.{4}).*", "replacement": "$1.$2", "target_label": "name"},
+                {"target_label": "id", "replacement": ""}
+            ]"#,
+        );
+        assert_eq!(
+            relabel(&rules, web_labels()),
+            Some(vec![("name".to_owned(), "shop-web-1.aaaa".to_owned())])
+        );
+    }
+
+    #[test]
+    fn test_relabel_config_invalid() {
+        assert!(serde_json::from_str::<Vec<RelabelRule>>(r#"[{"action": "replace"}]"#).is_err());
+        assert!(
+            serde_json::from_str::<Vec<RelabelRule>>(r#"[{"regex": "(", "action": "drop"}]"#)
+                .is_err()
+        );
+        assert!(serde_json::from_str::<Vec<RelabelRule>>(r#"[{"action": "hashmod"}]"#).is_err());
+        assert!(load_relabel_config(Path::new("/nonexistent/relabel.json")).is_err());
+    }
+}
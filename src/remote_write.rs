@@ -0,0 +1,269 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Request, Uri,
+    body::Bytes,
+    header::{self, HeaderName, HeaderValue},
+};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{Client, connect::HttpConnector},
+    rt::TokioExecutor,
+};
+use prost::Message;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle};
+use tracing::{trace, warn};
+
+use crate::{errors::AppError, openmetrics::parse_sample, usecases::DockerStatPollingWorker};
+
+/// deadline of each remote-write request
+const REMOTE_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `WriteRequest` of the remote-write 1.0 protobuf schema, metadata left out
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    /// sorted by name, `__name__` holds the metric name
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// milliseconds since unix epoch
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// URL of a remote-write receiver, `http` or `https`
+pub fn parse_remote_write_url(s: &str) -> Result<Uri, String> {
+    let uri = s.parse::<Uri>().map_err(|e| e.to_string())?;
+    match uri.scheme_str() {
+        Some("http" | "https") if uri.authority().is_some() => Ok(uri),
+        Some("http" | "https") => Err("missing host".to_owned()),
+        _ => Err("only http:// and https:// receivers are supported".to_owned()),
+    }
+}
+
+/// header sent with every remote-write request, `Name: value`
+pub fn parse_remote_write_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected Name: value, got {}", s))?;
+    let name = HeaderName::try_from(name.trim()).map_err(|e| e.to_string())?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|e| e.to_string())?;
+    Ok((name, value))
+}
+
+/// write request of all samples of an OpenMetrics text, taken at `timestamp`
+pub fn write_request(text: &str, timestamp: SystemTime) -> WriteRequest {
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as i64);
+    let timeseries = text
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(parse_sample)
+        .map(|(name, labels, value)| {
+            let mut labels = labels
+                .into_iter()
+                .chain([("__name__".to_owned(), name)])
+                .map(|(name, value)| Label { name, value })
+                .collect::<Vec<_>>();
+            labels.sort_by(|a, b| a.name.cmp(&b.name));
+            TimeSeries {
+                labels,
+                samples: vec![Sample { value, timestamp }],
+            }
+        })
+        .collect();
+    WriteRequest { timeseries }
+}
+
+/// receiver that metrics are pushed to after every poll
+#[derive(Debug)]
+pub struct RemoteWriter {
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    url: Uri,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl RemoteWriter {
+    pub fn new(url: Uri, headers: Vec<(HeaderName, HeaderValue)>) -> Result<Self, AppError> {
+        let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AppError::RemoteWrite(e.to_string()))?
+        .with_root_certificates(rustls::RootCertStore::from_iter(
+            webpki_roots::TLS_SERVER_ROOTS.iter().cloned(),
+        ))
+        .with_no_client_auth();
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            url,
+            headers,
+        })
+    }
+
+    /// push all samples of `text` as snappy compressed protobuf
+    pub async fn write(&self, text: &str, timestamp: SystemTime) -> Result<(), AppError> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&write_request(text, timestamp).encode_to_vec())
+            .map_err(|e| AppError::RemoteWrite(e.to_string()))?;
+        let mut request = Request::post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
+            .header(header::CONTENT_ENCODING, "snappy")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .header(
+                header::USER_AGENT,
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+            );
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        let request = request
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|e| AppError::RemoteWrite(e.to_string()))?;
+        let send = async {
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| AppError::RemoteWrite(e.to_string()))?;
+            let status = response.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let body = response
+                .into_body()
+                .collect()
+                .await
+                .map(|b| String::from_utf8_lossy(&b.to_bytes()).trim().to_owned())
+                .unwrap_or_default();
+            Err(AppError::RemoteWrite(format!("status {} {}", status, body)))
+        };
+        tokio::time::timeout(REMOTE_WRITE_TIMEOUT, send)
+            .await
+            .map_err(|_| {
+                AppError::RemoteWrite(format!("timed out after {:?}", REMOTE_WRITE_TIMEOUT))
+            })?
+    }
+
+    /// push metrics after every poll of `worker`, polls ended while pushes lag
+    /// far behind are skipped
+    pub fn spawn(self: Arc<Self>, worker: Arc<DockerStatPollingWorker>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut events = worker.subscribe_stats();
+            loop {
+                let stats = match events.recv().await {
+                    Ok(stats) => stats,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let text = match worker.get_encoded_metrics().await {
+                    Ok(text) => text,
+                    Err(e) => {
+                        warn!("encode metrics for remote write failed, error: {}", e);
+                        continue;
+                    }
+                };
+                match self.write(&text, stats.timestamp).await {
+                    Ok(()) => trace!("remote write to {} done", self.url),
+                    Err(e) => warn!("remote write to {} failed, error: {}", self.url, e),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_write_header() {
+        let (name, value) = parse_remote_write_header("Authorization: Bearer abc").unwrap();
+        assert_eq!(name, header::AUTHORIZATION);
+        assert_eq!(value, "Bearer abc");
+        assert!(parse_remote_write_header("Authorization").is_err());
+        assert!(parse_remote_write_url("https://prom.example/api/v1/write").is_ok());
+        assert!(parse_remote_write_url("unix:///prom.sock").is_err());
+    }
+
+    #[test]
+    fn test_write_request() {
+        let text = "# HELP container_cpu_usage_ratios Value of container logical CPU usage.\n\
+                    # TYPE container_cpu_usage_ratios gauge\n\
+                    # UNIT container_cpu_usage_ratios ratios\n\
+                    container_cpu_usage_ratios{id=\"/docker/aaaaaaaaaaaa\",name=\"web \\\"1\\\"\"} 0.25\n\
+                    # EOF\n";
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let request = write_request(text, timestamp);
+        assert_eq!(
+            request,
+            WriteRequest {
+                timeseries: vec![TimeSeries {
+                    labels: vec![
+                        Label {
+                            name: "__name__".to_owned(),
+                            value: "container_cpu_usage_ratios".to_owned(),
+                        },
+                        Label {
+                            name: "id".to_owned(),
+                            value: "/docker/aaaaaaaaaaaa".to_owned(),
+                        },
+                        Label {
+                            name: "name".to_owned(),
+                            value: "web \"1\"".to_owned(),
+                        },
+                    ],
+                    samples: vec![Sample {
+                        value: 0.25,
+                        timestamp: 1_700_000_000_123,
+                    }],
+                }],
+            }
+        );
+
+        // protobuf and snappy round trip
+        let compressed = snap::raw::Encoder::new()
+            .compress_vec(&request.encode_to_vec())
+            .unwrap();
+        let decoded = snap::raw::Decoder::new()
+            .decompress_vec(&compressed)
+            .unwrap();
+        assert_eq!(WriteRequest::decode(decoded.as_slice()).unwrap(), request);
+
+        // series without labels
+        let request = write_request("docker_stats_up 1\n", timestamp);
+        assert_eq!(request.timeseries[0].labels.len(), 1);
+        assert_eq!(request.timeseries[0].samples[0].value, 1.);
+    }
+}
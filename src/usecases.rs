@@ -1,34 +1,60 @@
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
     io,
-    sync::Arc,
-    time::{Duration, SystemTime},
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant, SystemTime},
 };
 
 use actix_web::http::Uri;
 use bollard::{
     API_DEFAULT_VERSION, Docker,
-    query_parameters::{ListContainersOptionsBuilder, StatsOptionsBuilder},
+    query_parameters::{
+        InspectContainerOptions, ListContainersOptionsBuilder, StatsOptionsBuilder,
+    },
     secret::{
-        ContainerBlkioStats, ContainerCpuStats, ContainerMemoryStats, ContainerNetworkStats,
-        ContainerStatsResponse,
+        ContainerBlkioStats, ContainerCpuStats, ContainerInspectResponse, ContainerMemoryStats,
+        ContainerNetworkStats, ContainerState, ContainerStatsResponse, ContainerSummary,
+        ContainerSummaryStateEnum, HealthStatusEnum, HostConfig, MountPoint, SystemInfo,
     },
 };
 use futures_util::TryStreamExt;
-use prometheus_client::registry::Registry;
-use serde::Serialize;
-use tokio::{sync::Mutex, task::JoinHandle};
+use prometheus_client::{encoding::text, metrics::gauge::Gauge, registry::Registry};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Mutex, broadcast},
+    task::JoinHandle,
+};
 use tracing::*;
 
-use crate::docker_stat_metrics::DockerStatContainerMetrics;
+use crate::{
+    docker_stat_metrics::{
+        CgroupDriver, ContainerLabelOptions, ContainerLabels, DockerDaemonMetrics,
+        DockerStatContainerMetrics, DockerStatProcessMetrics, DockerStatSelfMetrics,
+        DockerStatTotalMetrics, MetricUnits, container_cgroup_path, container_labels,
+        device_labels, info_labels, interface_labels, mount_labels, service_labels, set_counter,
+    },
+    errors::AppError,
+    relabel::{RelabelRule, relabel},
+};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct DockerContainerStat {
     pub id: String,
     pub name: String,
     pub cpu_usage: f64,
+    /// cumulative CPU time of container in nanoseconds
+    pub cpu_total_usage_ns: u64,
+    /// stats response carried `precpu_stats`, `cpu_usage` is computed from
+    /// two polls either way
+    pub precpu_available: bool,
     pub mem_usage: u64,
     pub mem_limit: u64,
+    /// `mem_usage` over `mem_limit`, at most 1 when usage is reported above
+    /// the limit, 0 without limit
+    pub mem_usage_ratio: f64,
     pub net_in: u64,
     pub net_out: u64,
     pub net_in_bps: f64,
@@ -37,6 +63,65 @@ pub struct DockerContainerStat {
     pub blk_out: u64,
     pub blk_in_byteps: f64,
     pub blk_out_byteps: f64,
+    pub net_in_errors: u64,
+    pub net_out_errors: u64,
+    pub net_in_dropped: u64,
+    pub net_out_dropped: u64,
+    pub net_in_packets: u64,
+    pub net_out_packets: u64,
+    /// container state from list api, eg. `running`, `paused`
+    pub state: Option<String>,
+    /// metric name prefix from `prometheus.prefix` container label
+    #[serde(skip)]
+    pub metric_prefix: Option<String>,
+    /// compose project and service from container labels
+    #[serde(skip)]
+    pub compose_service: Option<ComposeService>,
+    /// mounts of the container, only with `collect_mounts`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mounts: Vec<MountInfo>,
+    /// command of the container from list api, truncated to
+    /// `command_label_max_len` characters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// time taken by the stats api call of this container
+    pub collection_duration_seconds: f64,
+    /// exit code of exited containers, only with `include_stopped`
+    pub exit_code: Option<i64>,
+    /// time spent servicing IO, 0 on cgroup v2
+    pub blk_service_time_seconds: f64,
+    /// kernel and kernel TCP memory, 0 when the daemon does not report them
+    pub mem_kernel: u64,
+    /// page cache waiting to be written back to disk
+    pub mem_dirty: u64,
+    /// page cache being written back to disk
+    pub mem_writeback: u64,
+    /// CPU limit in cores from container host config, `None` when unlimited
+    pub cpu_limit: Option<f64>,
+    /// number of processes and threads in the container
+    pub pids: u64,
+    /// maximum of `pids` from container host config, `None` when unlimited
+    pub pids_limit: Option<i64>,
+    /// relative CPU weight from container host config, `None` when not set
+    pub cpu_shares: Option<i64>,
+    /// health check status, eg. `healthy`, `None` without health check
+    pub health: Option<String>,
+    /// time since the container turned unhealthy, 0 while it is not,
+    /// `None` without health check
+    pub unhealthy_seconds: Option<f64>,
+    /// time since the previous run of a restarted container ended, `None`
+    /// until it restarts or without inspect details
+    pub time_since_restart_seconds: Option<f64>,
+    /// traffic per network interface, only with `network_interface_label`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub network_interfaces: Vec<NetworkInterfaceStat>,
+    /// blkio per block device, only with `blkio_per_device`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blkio_devices: Vec<BlkioDeviceStat>,
+    /// last stat of a container missing from the latest poll, exported with a
+    /// `stale="true"` label with `explicit_stale`
+    #[serde(skip)]
+    pub vanished: bool,
 }
 impl Default for DockerContainerStat {
     fn default() -> Self {
@@ -44,8 +129,11 @@ impl Default for DockerContainerStat {
             id: Default::default(),
             name: Default::default(),
             cpu_usage: Default::default(),
+            cpu_total_usage_ns: Default::default(),
+            precpu_available: Default::default(),
             mem_usage: Default::default(),
             mem_limit: Default::default(),
+            mem_usage_ratio: Default::default(),
             net_in: Default::default(),
             net_out: Default::default(),
             net_in_bps: Default::default(),
@@ -54,16 +142,154 @@ impl Default for DockerContainerStat {
             blk_out: Default::default(),
             blk_in_byteps: Default::default(),
             blk_out_byteps: Default::default(),
+            net_in_errors: Default::default(),
+            net_out_errors: Default::default(),
+            net_in_dropped: Default::default(),
+            net_out_dropped: Default::default(),
+            net_in_packets: Default::default(),
+            net_out_packets: Default::default(),
+            state: Default::default(),
+            metric_prefix: Default::default(),
+            compose_service: Default::default(),
+            mounts: Default::default(),
+            command: Default::default(),
+            collection_duration_seconds: Default::default(),
+            exit_code: Default::default(),
+            blk_service_time_seconds: Default::default(),
+            mem_kernel: Default::default(),
+            mem_dirty: Default::default(),
+            mem_writeback: Default::default(),
+            cpu_limit: Default::default(),
+            pids: Default::default(),
+            pids_limit: Default::default(),
+            cpu_shares: Default::default(),
+            health: Default::default(),
+            unhealthy_seconds: Default::default(),
+            time_since_restart_seconds: Default::default(),
+            network_interfaces: Default::default(),
+            blkio_devices: Default::default(),
+            vanished: Default::default(),
         }
     }
 }
 
+impl DockerContainerStat {
+    /// record of `group_by_service` summing the replicas of a compose service
+    fn is_service_group(&self) -> bool {
+        self.id.is_empty() && self.compose_service.is_some()
+    }
+}
+
+/// volume, bind or tmpfs mount of a container
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct MountInfo {
+    /// eg. `bind`, `volume`, `tmpfs`
+    pub mount_type: String,
+    /// host path, empty for tmpfs
+    pub source: String,
+    pub destination: String,
+}
+
+impl MountInfo {
+    fn labels(&self, labels: &ContainerLabels) -> ContainerLabels {
+        mount_labels(labels, &self.source, &self.destination, &self.mount_type)
+    }
+}
+
+/// traffic of a network interface of a container
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct NetworkInterfaceStat {
+    /// key of the `networks` map in docker stats, eg. `eth0`
+    pub interface: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// blkio of a block device of a container
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct BlkioDeviceStat {
+    /// `major:minor` device number, eg. `8:0`
+    pub device: String,
+    /// kernel name of the device, eg. `sda`, empty when unknown
+    pub device_name: String,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+impl BlkioDeviceStat {
+    fn labels(&self, labels: &ContainerLabels) -> ContainerLabels {
+        device_labels(labels, &self.device, &self.device_name)
+    }
+}
+
+/// sums of per-container values over all monitored containers
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DockerContainerStatTotals {
+    pub cpu_usage: f64,
+    pub mem_usage: u64,
+    pub net_in_bps: f64,
+    pub net_out_bps: f64,
+}
+
+fn sum_container_stats(stats: &[DockerContainerStat]) -> DockerContainerStatTotals {
+    stats
+        .iter()
+        .fold(DockerContainerStatTotals::default(), |mut totals, stat| {
+            totals.cpu_usage += stat.cpu_usage;
+            totals.mem_usage += stat.mem_usage;
+            totals.net_in_bps += stat.net_in_bps;
+            totals.net_out_bps += stat.net_out_bps;
+            totals
+        })
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TimedContainerStatsResponse {
     id: String,
     name: String,
+    state: Option<String>,
+    #[serde(skip)]
+    metric_prefix: Option<String>,
+    #[serde(skip)]
+    compose_service: Option<ComposeService>,
+    #[serde(skip)]
+    mounts: Vec<MountInfo>,
+    #[serde(skip)]
+    command: Option<String>,
     stat: Option<ContainerStatsResponse>,
     time: SystemTime,
+    #[serde(skip)]
+    collection_duration: Duration,
+    /// exit code of exited containers
+    exit_code: Option<i64>,
+    /// resource limits of running containers
+    #[serde(flatten)]
+    limits: ContainerLimits,
+    /// end of the previous run of restarted running containers
+    #[serde(skip)]
+    restarted_at: Option<SystemTime>,
+    /// health check status from list api
+    health: Option<String>,
+    /// false for containers left out of this batch, which have no stat
+    #[serde(skip)]
+    sampled: bool,
+}
+
+impl TimedContainerStatsResponse {
+    /// container name with initial slash
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// whether a stats response carried usable `precpu_stats`, missing on some
+/// hosts such as raspberry pi, and empty in one-shot responses and the first
+/// frame of a stream
+fn has_precpu_stats(stat: &ContainerStatsResponse) -> bool {
+    stat.precpu_stats
+        .as_ref()
+        .and_then(|p| p.system_cpu_usage)
+        .is_some_and(|u| u > 0)
 }
 
 /// raspberry pi did not have precpu_stats data, we need to get CPU usage by hand
@@ -90,8 +316,15 @@ fn get_cpu_usage(first: &ContainerCpuStats, second: &ContainerCpuStats, time_del
         0
     };
 
+    // some kernels do not report online_cpus, count per-cpu usage instead
     let online_cpus = if let Some(u) = second.online_cpus {
         u
+    } else if let Some(percpu_usage) = second
+        .cpu_usage
+        .as_ref()
+        .and_then(|u| u.percpu_usage.as_ref())
+    {
+        percpu_usage.len() as u32
     } else {
         0
     };
@@ -103,22 +336,159 @@ fn get_cpu_usage(first: &ContainerCpuStats, second: &ContainerCpuStats, time_del
     (cpu_delta / system_cpu_delta) * online_cpus as f64 * time_delta
 }
 
-fn get_mem(mem: &ContainerMemoryStats) -> Result<u64, io::Error> {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CgroupVersion {
+    V1,
+    V2,
+}
+
+/// cgroup v1 memory stats carry hierarchical `total_*` counters and `cache`,
+/// cgroup v2 only has flat keys such as `inactive_file` and `file`
+fn detect_memory_cgroup_version(stats: &HashMap<String, u64>) -> CgroupVersion {
+    if stats.contains_key("total_inactive_file") || stats.contains_key("cache") {
+        CgroupVersion::V1
+    } else {
+        CgroupVersion::V2
+    }
+}
+
+/// cgroup version of the host from the shape of a stats response, memory stats
+/// keys first, then blkio stats which only cgroup v1 fills beyond service bytes,
+/// `None` when neither is reported (eg. stopped containers)
+fn detect_cgroup_version(stat: &ContainerStatsResponse) -> Option<CgroupVersion> {
+    let memory_stats = stat
+        .memory_stats
+        .as_ref()
+        .and_then(|mem| mem.stats.as_ref())
+        .filter(|stats| !stats.is_empty());
+    if let Some(stats) = memory_stats {
+        return Some(detect_memory_cgroup_version(stats));
+    }
+
+    let blkio = stat.blkio_stats.as_ref()?;
+    if blkio.io_serviced_recursive.is_some() {
+        Some(CgroupVersion::V1)
+    } else if blkio.io_service_bytes_recursive.is_some() {
+        Some(CgroupVersion::V2)
+    } else {
+        None
+    }
+}
+
+/// page cache keys subtracted from memory usage, in order of preference,
+/// the keys of the other cgroup version are tried last for odd daemons
+const V1_FILE_KEYS: [&str; 4] = ["total_inactive_file", "cache", "inactive_file", "file"];
+const V2_FILE_KEYS: [&str; 4] = ["inactive_file", "file", "total_inactive_file", "cache"];
+
+/// working set memory, same as `docker stats` shows
+/// v1: usage - total_inactive_file (or cache)
+/// v2: usage - inactive_file (or file)
+/// raw usage when no page cache key is reported
+fn get_mem(mem: &ContainerMemoryStats) -> Result<u64, AppError> {
     let usage = if let Some(u) = mem.usage {
         u
     } else {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no usage"));
+        return Err(AppError::Stats("no memory usage".to_owned()));
     };
 
-    if let Some(stats) = &mem.stats {
-        if let Some(file) = stats.get("file") {
-            return Ok(usage - file);
+    let stats = match &mem.stats {
+        Some(s) => s,
+        None => {
+            debug!("no memory stats, using raw memory usage");
+            return Ok(usage);
+        }
+    };
+    let keys = match detect_memory_cgroup_version(stats) {
+        CgroupVersion::V1 => V1_FILE_KEYS,
+        CgroupVersion::V2 => V2_FILE_KEYS,
+    };
+    match keys.iter().find_map(|key| stats.get(*key)) {
+        Some(file) => Ok(usage.saturating_sub(*file)),
+        None => {
+            debug!("no memory file stat, using raw memory usage");
+            Ok(usage)
         }
+    }
+}
+
+/// kernel memory of the container from `kernel` and `kernel_tcp` stats keys,
+/// 0 when unavailable (the API `kernel_usage` field is not exposed by bollard)
+fn get_mem_kernel(mem: &ContainerMemoryStats) -> u64 {
+    let stats = match &mem.stats {
+        Some(s) => s,
+        None => return 0,
+    };
+    ["kernel", "kernel_tcp"]
+        .iter()
+        .filter_map(|key| stats.get(*key))
+        .sum()
+}
+
+/// dirty and writeback page cache of the container, from `dirty` and
+/// `writeback` stats keys on cgroup v1 or `file_dirty` and `file_writeback` on
+/// cgroup v2, 0 when unavailable
+fn get_mem_dirty(mem: &ContainerMemoryStats) -> (u64, u64) {
+    let stats = match &mem.stats {
+        Some(s) => s,
+        None => return (0, 0),
+    };
+    let value = |keys: [&str; 2]| keys.iter().find_map(|key| stats.get(*key)).copied();
+    (
+        value(["dirty", "file_dirty"]).unwrap_or(0),
+        value(["writeback", "file_writeback"]).unwrap_or(0),
+    )
+}
+
+/// mount point of cgroup v2 unified hierarchy
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// parse content of cgroup v2 `memory.max`, `max` means unlimited and gives `None`
+fn parse_cgroup_memory_max(content: &str) -> Option<u64> {
+    match content.trim() {
+        "max" => None,
+        v => v.parse().ok(),
+    }
+}
 
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no file"));
+/// read memory limit of a container from its cgroup directly,
+/// `None` when unlimited or the file is unreadable
+async fn read_cgroup_memory_max(id: &str, driver: CgroupDriver) -> Option<u64> {
+    let path = format!(
+        "{}{}/memory.max",
+        CGROUP_ROOT,
+        container_cgroup_path(id, driver)
+    );
+    match tokio::fs::read_to_string(&path).await {
+        Ok(content) => parse_cgroup_memory_max(&content),
+        Err(e) => {
+            debug!("read {} failed, error: {}", path, e);
+            None
+        }
     }
+}
+
+fn bytes_to_mib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 20) as f64
+}
+
+fn bytes_to_gib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 30) as f64
+}
 
-    return Err(io::Error::new(io::ErrorKind::InvalidInput, "no stat"));
+/// memory usage over limit, clamped to 1 as accounting races can report usage
+/// above the limit, raw values are left as they are
+fn mem_usage_ratio(usage: u64, limit: u64) -> f64 {
+    if limit == 0 {
+        return 0.;
+    }
+    if usage > limit {
+        debug!(
+            "memory usage {} above limit {}, ratio clamped to 1",
+            usage, limit
+        );
+        return 1.;
+    }
+    usage as f64 / limit as f64
 }
 
 fn get_net_io(networks: &HashMap<String, ContainerNetworkStats>) -> (u64, u64) {
@@ -133,6 +503,54 @@ fn get_net_io(networks: &HashMap<String, ContainerNetworkStats>) -> (u64, u64) {
     return (net_in, net_out);
 }
 
+/// received and transmitted packets summed over all interfaces
+fn get_net_packets(networks: &HashMap<String, ContainerNetworkStats>) -> (u64, u64) {
+    networks.values().fold((0, 0), |(rx, tx), net| {
+        (
+            rx + net.rx_packets.unwrap_or(0),
+            tx + net.tx_packets.unwrap_or(0),
+        )
+    })
+}
+
+/// traffic per interface sorted by interface name, docker reports interfaces
+/// only, not address families, so dual-stack traffic is not split further
+fn get_net_interfaces(
+    networks: &HashMap<String, ContainerNetworkStats>,
+) -> Vec<NetworkInterfaceStat> {
+    let mut interfaces: Vec<_> = networks
+        .iter()
+        .map(|(interface, net)| NetworkInterfaceStat {
+            interface: interface.clone(),
+            rx_bytes: net.rx_bytes.unwrap_or(0),
+            tx_bytes: net.tx_bytes.unwrap_or(0),
+        })
+        .collect();
+    interfaces.sort_by(|a, b| a.interface.cmp(&b.interface));
+    interfaces
+}
+
+/// errors and dropped packets summed over all interfaces
+#[derive(Debug, Clone, Default, PartialEq)]
+struct NetworkErrorStats {
+    rx_errors: u64,
+    tx_errors: u64,
+    rx_dropped: u64,
+    tx_dropped: u64,
+}
+
+fn get_net_errors(networks: &HashMap<String, ContainerNetworkStats>) -> NetworkErrorStats {
+    networks
+        .values()
+        .fold(NetworkErrorStats::default(), |mut errors, net| {
+            errors.rx_errors += net.rx_errors.unwrap_or(0);
+            errors.tx_errors += net.tx_errors.unwrap_or(0);
+            errors.rx_dropped += net.rx_dropped.unwrap_or(0);
+            errors.tx_dropped += net.tx_dropped.unwrap_or(0);
+            errors
+        })
+}
+
 fn get_blk_io(networks: &ContainerBlkioStats) -> (u64, u64) {
     let mut net_in = 0;
     let mut net_out = 0;
@@ -155,417 +573,6023 @@ fn get_blk_io(networks: &ContainerBlkioStats) -> (u64, u64) {
     return (net_in, net_out);
 }
 
-async fn docker_stat_oneshot(host: &str) -> Result<Vec<TimedContainerStatsResponse>, io::Error> {
-    let docker = if host == "unix:///var/run/docker.sock" {
-        match Docker::connect_with_defaults() {
-            Ok(d) => d,
-            Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
-        }
-    } else {
-        match host.parse::<Uri>() {
-            Ok(u) => {
-                let docker_result = match u.scheme_str() {
-                    Some("http") => Docker::connect_with_http(host, 4, API_DEFAULT_VERSION),
-                    // Some("https") => {
-                    //     let _ = rustls::crypto::CryptoProvider::install_default(aws_lc_rs::default_provider());
-                    //     let uri_parts = u.into_parts();
-                    //     let addr = format!("tcp://{}{}",
-                    //         uri_parts.authority.map(|a| a.to_string()).unwrap_or("".to_owned()),
-                    //         uri_parts.path_and_query.map(|pq| pq.to_string()).unwrap_or("".to_owned()));
-                    //     Docker::connect_with_ssl(&addr, Path::new("./key.pem"), Path::new("./cert.pem"), Path::new("./ca.pem"), 4, API_DEFAULT_VERSION)
-                    //     Docker::connect_with_unix(path, timeout, client_version)
-                    // },
-                    _ => {
-                        warn!("not supported docker uri scheme, fallback to defaults");
-                        Docker::connect_with_defaults()
-                    }
-                };
+/// kernel name of a block device from `/sys/dev/block/<major>:<minor>`,
+/// a link to the device directory named after it
+fn block_device_name(device: &str) -> Option<String> {
+    std::fs::read_link(Path::new("/sys/dev/block").join(device))
+        .ok()?
+        .file_name()?
+        .to_str()
+        .map(|name| name.to_owned())
+}
 
-                match docker_result {
-                    Ok(d) => d,
-                    Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
-                }
-            }
-            Err(_) => {
-                warn!("invalid docker uri, fallback to defaults");
-                match Docker::connect_with_defaults() {
-                    Ok(d) => d,
-                    Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
-                }
+/// read and written bytes per block device sorted by device number,
+/// `device_name` resolves `major:minor` to a kernel name
+fn get_blk_devices(
+    blkio: &ContainerBlkioStats,
+    device_name: impl Fn(&str) -> Option<String>,
+) -> Vec<BlkioDeviceStat> {
+    let mut devices: Vec<BlkioDeviceStat> = Vec::new();
+    for blk in blkio.io_service_bytes_recursive.iter().flatten() {
+        let (major, minor) = match (blk.major, blk.minor) {
+            (Some(major), Some(minor)) => (major, minor),
+            _ => continue,
+        };
+        let device = format!("{}:{}", major, minor);
+        let index = match devices.iter().position(|d| d.device == device) {
+            Some(index) => index,
+            None => {
+                devices.push(BlkioDeviceStat {
+                    device_name: device_name(&device).unwrap_or_default(),
+                    device,
+                    ..Default::default()
+                });
+                devices.len() - 1
             }
+        };
+        let value = blk.value.unwrap_or(0);
+        match blk.op.as_deref() {
+            Some("read") => devices[index].read_bytes += value,
+            Some("write") => devices[index].write_bytes += value,
+            _ => {}
         }
-    };
+    }
+    devices.sort_by_key(|d| {
+        let (major, minor) = d.device.split_once(':').unwrap_or_default();
+        (major.parse::<u64>().ok(), minor.parse::<u64>().ok())
+    });
+    devices
+}
 
-    let mut filters = HashMap::new();
-    filters.insert(
-        "status".to_owned(),
-        vec!["running".to_owned(), "paused".to_owned()],
-    );
+/// nanoseconds spent servicing read and write IO, summed over devices,
+/// only reported on cgroup v1
+fn get_blk_service_time(blkio: &ContainerBlkioStats) -> u64 {
+    blkio
+        .io_service_time_recursive
+        .iter()
+        .flatten()
+        .filter(|blk| matches!(blk.op.as_deref(), Some("read") | Some("write")))
+        .filter_map(|blk| blk.value)
+        .sum()
+}
 
-    let list_containers_options = Some(
-        ListContainersOptionsBuilder::new()
-            .all(true)
-            .filters(&filters)
-            .build(),
-    );
+/// container label overriding metric name prefix of the container
+const METRIC_PREFIX_LABEL: &str = "prometheus.prefix";
 
-    let start_at = SystemTime::now();
-    let containers = match docker.list_containers(list_containers_options).await {
-        Ok(v) => v,
-        Err(e) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, e)),
-    };
-    debug!(
-        "containers listed from api in {} μs",
-        SystemTime::now()
-            .duration_since(start_at)
-            .unwrap()
-            .as_micros()
-    );
+/// command cut to at most `max_len` characters, whole when `max_len` is 0
+fn truncate_command(command: &str, max_len: usize) -> String {
+    match command.char_indices().nth(max_len) {
+        Some((end, _)) if max_len > 0 => command[..end].to_owned(),
+        _ => command.to_owned(),
+    }
+}
 
-    let mut stats: Vec<TimedContainerStatsResponse> = Vec::new();
+/// mounts from list api `Mounts`, sorted by destination
+fn mounts_from_summary(mounts: Option<&[MountPoint]>) -> Vec<MountInfo> {
+    let mut mounts: Vec<_> = mounts
+        .unwrap_or_default()
+        .iter()
+        .map(|m| MountInfo {
+            mount_type: m.typ.map(|t| t.to_string()).unwrap_or_default(),
+            source: m.source.clone().unwrap_or_default(),
+            destination: m.destination.clone().unwrap_or_default(),
+        })
+        .collect();
+    mounts.sort_by(|a, b| a.destination.cmp(&b.destination));
+    mounts
+}
 
-    let start_at = SystemTime::now();
-    for container in containers.iter() {
-        let id = if let Some(s) = &container.id {
-            s
-        } else {
-            continue;
-        };
-        let name = if let Some(v) = &container.names {
-            if let Some(s) = v.first() {
-                s
-            } else {
+/// compose project and service of a container, replicas of a scaled service
+/// share it, swarm tasks have their stack and service in it with `swarm`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ComposeService {
+    pub project: String,
+    pub service: String,
+}
+
+const COMPOSE_PROJECT_LABEL: &str = "com.docker.compose.project";
+const COMPOSE_SERVICE_LABEL: &str = "com.docker.compose.service";
+/// labels docker sets on swarm task containers, on managers and workers alike
+const SWARM_STACK_LABEL: &str = "com.docker.stack.namespace";
+const SWARM_SERVICE_LABEL: &str = "com.docker.swarm.service.name";
+
+/// compose service from `com.docker.compose.*` container labels, or swarm
+/// service from `com.docker.swarm.*` labels with `swarm`, the project is empty
+/// when its label is missing
+fn compose_service_from_labels(
+    labels: Option<&HashMap<String, String>>,
+    swarm: bool,
+) -> Option<ComposeService> {
+    let labels = labels?;
+    let (project_label, service_label) = if swarm {
+        (SWARM_STACK_LABEL, SWARM_SERVICE_LABEL)
+    } else {
+        (COMPOSE_PROJECT_LABEL, COMPOSE_SERVICE_LABEL)
+    };
+    let service = labels.get(service_label)?.clone();
+    // `docker stack deploy` prefixes service names with the stack
+    let project = labels.get(project_label).cloned().unwrap_or_default();
+    let service = match service.strip_prefix(&format!("{}_", project)) {
+        Some(s) if swarm && !project.is_empty() => s.to_owned(),
+        _ => service,
+    };
+    Some(ComposeService { project, service })
+}
+
+/// stats of compose service replicas summed into one record per service with
+/// an empty id, in order of first replica, containers without compose service
+/// are kept as is. Only gauges are summed: counters would go down when a
+/// replica is replaced, which reads as a counter reset, and limits and
+/// collection durations mean nothing as sums, so they are left at zero and
+/// not exported for services
+fn group_by_service(stats: &[DockerContainerStat]) -> Vec<DockerContainerStat> {
+    let mut grouped: Vec<DockerContainerStat> = Vec::new();
+    let mut group_index = HashMap::new();
+    for stat in stats.iter() {
+        let service = match &stat.compose_service {
+            Some(s) => s,
+            None => {
+                grouped.push(stat.clone());
                 continue;
             }
-        } else {
-            continue;
         };
-
-        let stats_option = Some(
-            StatsOptionsBuilder::new()
-                .stream(false)
-                .one_shot(true)
-                .build(),
-        );
-        let stats_stream = docker.stats(&id, stats_option);
-        match stats_stream.try_collect::<Vec<_>>().await {
-            Ok(v) => {
-                let time = SystemTime::now();
-                stats.push(TimedContainerStatsResponse {
-                    id: id.clone(),
-                    name: name.clone(),
-                    stat: v.first().map(|e| e.clone()),
-                    time: time,
+        let index = *group_index
+            .entry((service.clone(), stat.metric_prefix.clone()))
+            .or_insert_with(|| {
+                grouped.push(DockerContainerStat {
+                    name: service.service.clone(),
+                    state: stat.state.clone(),
+                    metric_prefix: stat.metric_prefix.clone(),
+                    compose_service: Some(service.clone()),
+                    ..Default::default()
                 });
-            }
-            Err(e) => {
-                error!("stats error: {}", e);
-            }
-        };
+                grouped.len() - 1
+            });
+        let group = &mut grouped[index];
+        group.cpu_usage += stat.cpu_usage;
+        group.mem_usage += stat.mem_usage;
+        // the replica closest to its limit
+        group.mem_usage_ratio = group.mem_usage_ratio.max(stat.mem_usage_ratio);
+        group.mem_kernel += stat.mem_kernel;
+        group.mem_dirty += stat.mem_dirty;
+        group.mem_writeback += stat.mem_writeback;
+        group.net_in += stat.net_in;
+        group.net_out += stat.net_out;
+        group.net_in_bps += stat.net_in_bps;
+        group.net_out_bps += stat.net_out_bps;
+        group.blk_in += stat.blk_in;
+        group.blk_out += stat.blk_out;
+        group.blk_in_byteps += stat.blk_in_byteps;
+        group.blk_out_byteps += stat.blk_out_byteps;
+        group.blk_service_time_seconds += stat.blk_service_time_seconds;
+        group.pids += stat.pids;
+        if let Some(limit) = stat.cpu_limit {
+            group.cpu_limit = Some(group.cpu_limit.unwrap_or(0.) + limit);
+        }
+        if let Some(seconds) = stat.unhealthy_seconds {
+            group.unhealthy_seconds = Some(group.unhealthy_seconds.unwrap_or(0.).max(seconds));
+        }
+        // the latest restart of any replica
+        if let Some(seconds) = stat.time_since_restart_seconds {
+            group.time_since_restart_seconds = Some(
+                group
+                    .time_since_restart_seconds
+                    .map_or(seconds, |s| s.min(seconds)),
+            );
+        }
+        // a service is paused only when all of its replicas are
+        if group.state != stat.state {
+            group.state = None;
+        }
     }
-    debug!(
-        "stats of all containers from api in {} μs",
-        SystemTime::now()
-            .duration_since(start_at)
-            .unwrap()
-            .as_micros()
-    );
+    grouped
+}
 
-    Ok(stats)
+/// records of `vanished` containers grouped like `group_by_service` and
+/// marked vanished, a service with a replica left in `grouped` is not stale so
+/// it is left out
+fn group_vanished_by_service(
+    grouped: &[DockerContainerStat],
+    vanished: &[DockerContainerStat],
+) -> Vec<DockerContainerStat> {
+    group_by_service(vanished)
+        .into_iter()
+        .filter(|group| {
+            group.compose_service.is_none()
+                || !grouped.iter().any(|g| {
+                    g.compose_service == group.compose_service
+                        && g.metric_prefix == group.metric_prefix
+                })
+        })
+        .map(|group| DockerContainerStat {
+            vanished: true,
+            ..group
+        })
+        .collect()
 }
 
-#[derive(Debug, Clone)]
-struct LastDockerAPIContainersStats {
-    pub timestamp: SystemTime,
-    pub stats: HashMap<String, TimedContainerStatsResponse>,
+/// ids of containers already warned about for an invalid `METRIC_PREFIX_LABEL`,
+/// so the warning is logged once per container instead of on every poll
+static WARNED_INVALID_PREFIXES: LazyLock<std::sync::Mutex<HashSet<String>>> =
+    LazyLock::new(Default::default);
+
+/// metric prefix from container labels, ignored unless it is a valid metric name
+fn metric_prefix_from_labels(id: &str, labels: Option<&HashMap<String, String>>) -> Option<String> {
+    let prefix = labels?.get(METRIC_PREFIX_LABEL)?;
+    let mut chars = prefix.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        if WARNED_INVALID_PREFIXES
+            .lock()
+            .unwrap()
+            .insert(id.to_owned())
+        {
+            warn!(
+                "ignored invalid {} label of container {}: {:?}",
+                METRIC_PREFIX_LABEL, id, prefix
+            );
+        }
+        return None;
+    }
+    Some(prefix.clone())
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct LastDockerStats {
-    pub timestamp: SystemTime,
-    pub stats: Vec<DockerContainerStat>,
+/// filters of container listing, by `status` and by `opt_in_label` set to `true`
+fn list_filters(statuses: &[String], opt_in_label: Option<&str>) -> HashMap<String, Vec<String>> {
+    let mut filters = HashMap::new();
+    filters.insert("status".to_owned(), statuses.to_vec());
+    if let Some(key) = opt_in_label {
+        filters.insert("label".to_owned(), vec![format!("{}=true", key)]);
+    }
+    filters
 }
 
-#[derive(Debug)]
-pub struct DockerStatPollingWorker {
-    docker_host: String,
-    prom_registry_prefix: Arc<Mutex<String>>,
-    delay_ms: Arc<Mutex<u64>>,
+/// container carries label `key` set to `true`
+fn is_opted_in(labels: Option<&HashMap<String, String>>, key: &str) -> bool {
+    labels
+        .and_then(|labels| labels.get(key))
+        .is_some_and(|value| value == "true")
+}
 
-    /// last collected docker stats record
-    last_stats: Arc<Mutex<LastDockerStats>>,
+/// containers younger than `min_age` are skipped to avoid flapping series of
+/// ephemeral containers, containers without creation time are always kept
+fn is_container_old_enough(created: Option<i64>, now: SystemTime, min_age: Duration) -> bool {
+    if min_age.is_zero() {
+        return true;
+    }
 
-    /// last records of `GET /container/{id}/stats` api
-    last_docker_stats: Arc<Mutex<LastDockerAPIContainersStats>>,
+    let created = match created {
+        Some(c) => SystemTime::UNIX_EPOCH + Duration::from_secs(c.max(0) as u64),
+        None => return true,
+    };
+
+    match now.duration_since(created) {
+        Ok(age) => age >= min_age,
+        Err(_) => false,
+    }
 }
 
-impl DockerStatPollingWorker {
-    async fn task_handler(&self) {
-        loop {
-            // get last docker stats from api
-            let last_api_stats = match docker_stat_oneshot(&self.docker_host).await {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("docker_stat_oneshot failed, error: {}", e);
-                    continue;
-                }
-            };
-            let whole_start_at = SystemTime::now();
+/// a full container id, 64 hex chars
+fn is_container_id(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
 
-            let mut parsed_stat = Vec::new();
+/// id of the container this process runs in, from a cgroup path of
+/// `/proc/self/cgroup` like `/system.slice/docker-<id>.scope` or `/docker/<id>`,
+/// else from `hostname`, which docker sets to the short id unless overridden
+pub fn self_container_id(cgroup: &str, hostname: Option<&str>) -> Option<String> {
+    let from_cgroup = cgroup
+        .lines()
+        .filter_map(|line| line.rsplit(':').next())
+        .flat_map(|path| path.split('/'))
+        .map(|segment| {
+            let segment = segment.strip_prefix("docker-").unwrap_or(segment);
+            segment.strip_suffix(".scope").unwrap_or(segment)
+        })
+        .find(|segment| is_container_id(segment));
+    if let Some(id) = from_cgroup {
+        return Some(id.to_owned());
+    }
 
-            let start_at = SystemTime::now();
-            for container_api_stat in last_api_stats.iter() {
-                let mut stat = if let Some(ref s) = container_api_stat.stat {
-                    let cpu_usage = if let Some(cpu_stats) = &s.cpu_stats {
-                        let system_cpu_usage = cpu_stats.system_cpu_usage.unwrap_or(0) as f64;
-                        let total_usage = if let Some(u) = &cpu_stats.cpu_usage {
-                            u.total_usage.unwrap_or(0) as f64
-                        } else {
-                            0.
-                        };
-                        total_usage / system_cpu_usage
-                    } else {
-                        0.
-                    };
+    hostname
+        .filter(|h| h.len() == 12 && h.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|h| h.to_owned())
+}
 
-                    let (mem_usage, mem_limit) = if let Some(mem_stats) = &s.memory_stats {
-                        let limit = mem_stats.limit.unwrap_or(0);
-                        let usage = match get_mem(&mem_stats) {
-                            Ok(u) => u,
-                            Err(e) => {
-                                warn!("get_mem failed, error: {}", e);
-                                0
-                            }
-                        };
-                        (usage, limit)
-                    } else {
-                        (0, 0)
-                    };
+/// socket path of a `unix://`, `unix:` or bare absolute path docker host without
+/// query, eg. `/run/user/1000/docker.sock` of rootless docker
+fn unix_socket_path(host: &str) -> Option<&str> {
+    let path = if let Some(path) = host.strip_prefix("unix://") {
+        path
+    } else if let Some(path) = host.strip_prefix("unix:") {
+        path
+    } else if host.starts_with('/') {
+        host
+    } else {
+        return None;
+    };
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    Some(path).filter(|path| !path.is_empty())
+}
 
-                    // net io
-                    let (net_in, net_out) = if let Some(networks) = &s.networks {
-                        get_net_io(networks)
-                    } else {
-                        (0, 0)
-                    };
+/// address of a `tcp://` docker host with the port docker uses by convention
+/// when omitted, 2376 with TLS and 2375 without
+fn docker_tcp_address(host: &str, tls: bool) -> Option<String> {
+    let uri = host.parse::<Uri>().ok()?;
+    if uri.scheme_str() != Some("tcp") {
+        return None;
+    }
 
-                    // blk io
-                    let (blk_in, blk_out) = if let Some(blkio) = &s.blkio_stats {
-                        get_blk_io(blkio)
-                    } else {
-                        (0, 0)
-                    };
+    let hostname = uri.host().filter(|h| !h.is_empty())?;
+    let port = uri.port_u16().unwrap_or(if tls { 2376 } else { 2375 });
+    Some(format!("tcp://{}:{}", hostname, port))
+}
 
-                    DockerContainerStat {
-                        id: container_api_stat.id.clone(),
-                        name: container_api_stat.name.clone(),
-                        cpu_usage,
-                        mem_usage,
-                        mem_limit,
-                        net_in,
-                        net_out,
+const FALLBACK_UNSUPPORTED_SCHEME: &str = "not supported docker uri scheme, fallback to defaults";
+const FALLBACK_INVALID_URI: &str = "invalid docker uri, fallback to defaults";
+
+/// docker host argument parsed into the way `connect_docker` connects to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DockerEndpoint<'a> {
+    /// default socket, connected like the docker cli does
+    DefaultSocket,
+    Unix(&'a str),
+    Http,
+    Tcp,
+    /// unsupported scheme or invalid uri, connected with defaults for this reason
+    Fallback(&'static str),
+}
+
+impl<'a> DockerEndpoint<'a> {
+    fn parse(host: &'a str) -> Self {
+        if host == "unix:///var/run/docker.sock" {
+            return Self::DefaultSocket;
+        }
+        if let Some(path) = unix_socket_path(host) {
+            return Self::Unix(path);
+        }
+        match host.parse::<Uri>() {
+            Ok(u) => match u.scheme_str() {
+                Some("http") => Self::Http,
+                Some("tcp") => Self::Tcp,
+                _ => Self::Fallback(FALLBACK_UNSUPPORTED_SCHEME),
+            },
+            Err(_) => Self::Fallback(FALLBACK_INVALID_URI),
+        }
+    }
+}
+
+/// scheme `connect_docker` connects to a docker host with, and the reason when
+/// it falls back to defaults
+fn connection_scheme(host: &str, tls: bool) -> (&'static str, Option<&'static str>) {
+    match DockerEndpoint::parse(host) {
+        DockerEndpoint::DefaultSocket | DockerEndpoint::Unix(_) => ("unix", None),
+        DockerEndpoint::Http => ("http", None),
+        DockerEndpoint::Tcp if tls => ("tcp+tls", None),
+        DockerEndpoint::Tcp => ("tcp", None),
+        DockerEndpoint::Fallback(reason) => ("default", Some(reason)),
+    }
+}
+
+/// docker connectivity reported by `/debug/connection`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DockerConnectionState {
+    /// docker host argument
+    pub host: String,
+    /// `unix`, `http`, `tcp`, `tcp+tls`, or `default` when falling back to defaults
+    pub scheme: String,
+    /// why the docker host fell back to defaults
+    pub fallback: Option<String>,
+    /// result of last ping, `None` before the first one
+    pub last_ping_ok: Option<bool>,
+    pub daemon_version: Option<String>,
+    /// last error connecting to or calling the daemon, kept after recovery
+    pub last_error: Option<String>,
+}
+
+fn connect_docker(host: &str, options: &DockerStatPollingOptions) -> Result<Docker, AppError> {
+    let tls_cert_dir = options.docker_tls_cert_dir.as_ref();
+    let docker_result = match DockerEndpoint::parse(host) {
+        DockerEndpoint::DefaultSocket => Docker::connect_with_defaults(),
+        DockerEndpoint::Unix(path) => Docker::connect_with_unix(path, 4, API_DEFAULT_VERSION),
+        DockerEndpoint::Http => Docker::connect_with_http(host, 4, API_DEFAULT_VERSION),
+        DockerEndpoint::Tcp => {
+            let addr = match docker_tcp_address(host, tls_cert_dir.is_some()) {
+                Some(a) => a,
+                None => {
+                    return Err(AppError::InvalidHost(host.to_owned()));
+                }
+            };
+            match tls_cert_dir {
+                Some(dir) => {
+                    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+                    Docker::connect_with_ssl(
+                        &addr,
+                        &dir.join("key.pem"),
+                        &dir.join("cert.pem"),
+                        &dir.join("ca.pem"),
+                        4,
+                        API_DEFAULT_VERSION,
+                    )
+                }
+                None => Docker::connect_with_http(&addr, 4, API_DEFAULT_VERSION),
+            }
+        }
+        // Some("https") => {
+        //     let _ = rustls::crypto::CryptoProvider::install_default(aws_lc_rs::default_provider());
+        //     let uri_parts = u.into_parts();
+        //     let addr = format!("tcp://{}{}",
+        //         uri_parts.authority.map(|a| a.to_string()).unwrap_or("".to_owned()),
+        //         uri_parts.path_and_query.map(|pq| pq.to_string()).unwrap_or("".to_owned()));
+        //     Docker::connect_with_ssl(&addr, Path::new("./key.pem"), Path::new("./cert.pem"), Path::new("./ca.pem"), 4, API_DEFAULT_VERSION)
+        //     Docker::connect_with_unix(path, timeout, client_version)
+        // },
+        DockerEndpoint::Fallback(reason) => {
+            warn!("{}", reason);
+            Docker::connect_with_defaults()
+        }
+    };
+
+    docker_result.map_err(AppError::Connect)
+}
+
+/// exit code of an exited container from inspect api, cached until the
+/// container is no longer listed as exited
+async fn exited_container_exit_code(
+    docker: &Docker,
+    id: &str,
+    exit_codes: &Mutex<HashMap<String, i64>>,
+    retries: u32,
+) -> Option<i64> {
+    if let Some(code) = exit_codes.lock().await.get(id) {
+        return Some(*code);
+    }
+
+    let inspect = with_retries(retries, "inspect container", || {
+        docker.inspect_container(id, None::<InspectContainerOptions>)
+    });
+    match inspect.await {
+        Ok(inspect) => {
+            let code = inspect.state.and_then(|s| s.exit_code)?;
+            exit_codes.lock().await.insert(id.to_owned(), code);
+            Some(code)
+        }
+        Err(e) => {
+            warn!("inspect container {} failed, error: {}", id, e);
+            None
+        }
+    }
+}
+
+/// health check status in list api `Status`, eg. `Up 3 minutes (unhealthy)`,
+/// `None` for containers without health check
+fn health_from_status(status: Option<&str>) -> Option<String> {
+    let status = status?;
+    let health = status.strip_suffix(')')?.rsplit_once('(')?.1;
+    let health = health.strip_prefix("health: ").unwrap_or(health);
+    match health {
+        "healthy" | "unhealthy" | "starting" => Some(health.to_owned()),
+        _ => None,
+    }
+}
+
+/// set `unhealthy_seconds` from the time each container first reported
+/// unhealthy, forgetting containers that recovered or are gone
+fn track_unhealthy(
+    unhealthy_since: &mut HashMap<String, SystemTime>,
+    stats: &mut [DockerContainerStat],
+    now: SystemTime,
+) {
+    let mut still_unhealthy = HashMap::new();
+    for stat in stats.iter_mut() {
+        stat.unhealthy_seconds = match stat.health.as_deref() {
+            Some("unhealthy") => {
+                let since = unhealthy_since.get(&stat.id).copied().unwrap_or(now);
+                still_unhealthy.insert(stat.id.clone(), since);
+                Some(now.duration_since(since).unwrap_or_default().as_secs_f64())
+            }
+            Some(_) => Some(0.),
+            None => None,
+        };
+    }
+    *unhealthy_since = still_unhealthy;
+}
+
+/// last non-zero rates of a running container, held while it is paused
+/// with `hold_rates_on_pause`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ActiveRates {
+    cpu_usage: f64,
+    net_in_bps: f64,
+    net_out_bps: f64,
+    blk_in_byteps: f64,
+    blk_out_byteps: f64,
+}
+
+/// set rates of paused containers to their last non-zero values while
+/// running, forgetting containers that are gone
+fn hold_paused_rates(active: &mut HashMap<String, ActiveRates>, stats: &mut [DockerContainerStat]) {
+    let mut current = HashMap::new();
+    for stat in stats.iter_mut() {
+        let mut rates = active.remove(&stat.id).unwrap_or_default();
+        let fields = [
+            (&mut stat.cpu_usage, &mut rates.cpu_usage),
+            (&mut stat.net_in_bps, &mut rates.net_in_bps),
+            (&mut stat.net_out_bps, &mut rates.net_out_bps),
+            (&mut stat.blk_in_byteps, &mut rates.blk_in_byteps),
+            (&mut stat.blk_out_byteps, &mut rates.blk_out_byteps),
+        ];
+        if stat.state.as_deref() == Some("paused") {
+            for (value, held) in fields {
+                *value = *held;
+            }
+        } else {
+            for (value, held) in fields {
+                if *value != 0. {
+                    *held = *value;
+                }
+            }
+        }
+        current.insert(stat.id.clone(), rates);
+    }
+    *active = current;
+}
+
+/// CPU limit in cores, `NanoCpus` (`--cpus`) takes precedence over
+/// `CpuQuota`/`CpuPeriod`, `None` when unlimited
+fn cpu_limit_cores(host_config: &HostConfig) -> Option<f64> {
+    if let Some(nano_cpus) = host_config.nano_cpus.filter(|n| *n > 0) {
+        return Some(nano_cpus as f64 / 1_000_000_000.);
+    }
+    let quota = host_config.cpu_quota.filter(|q| *q > 0)?;
+    // docker applies the default CFS period of 100ms when only quota is set
+    let period = host_config.cpu_period.filter(|p| *p > 0).unwrap_or(100_000);
+    Some(quota as f64 / period as f64)
+}
+
+/// resource limits of a container from its host config, `None` when unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+struct ContainerLimits {
+    /// CPU limit in cores
+    #[serde(rename = "cpu_limit")]
+    cpu: Option<f64>,
+    /// maximum number of processes, `--pids-limit`
+    #[serde(rename = "pids_limit")]
+    pids: Option<i64>,
+    /// relative CPU weight, `--cpu-shares`
+    #[serde(rename = "cpu_shares")]
+    shares: Option<i64>,
+}
+
+impl ContainerLimits {
+    fn from_host_config(host_config: &HostConfig) -> Self {
+        Self {
+            cpu: cpu_limit_cores(host_config),
+            // 0 and -1 both mean unlimited
+            pids: host_config.pids_limit.filter(|l| *l > 0),
+            // 0 is unset, which weighs as 1024
+            shares: host_config.cpu_shares.filter(|s| *s > 0),
+        }
+    }
+}
+
+/// details of a running container from inspect api
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct InspectedContainer {
+    limits: ContainerLimits,
+    /// end of the previous run, `None` until the container restarts
+    restarted_at: Option<SystemTime>,
+}
+
+impl InspectedContainer {
+    fn from_inspect(inspect: &ContainerInspectResponse) -> Self {
+        Self {
+            limits: inspect
+                .host_config
+                .as_ref()
+                .map(ContainerLimits::from_host_config)
+                .unwrap_or_default(),
+            restarted_at: inspect.state.as_ref().and_then(restarted_at),
+        }
+    }
+}
+
+/// `FinishedAt` of a running container that ran before, docker reports
+/// `0001-01-01T00:00:00Z` until a run finishes
+fn restarted_at(state: &ContainerState) -> Option<SystemTime> {
+    let parse = |time: Option<&String>| {
+        time.and_then(|t| humantime::parse_rfc3339_weak(t).ok())
+            .filter(|t| *t > SystemTime::UNIX_EPOCH)
+    };
+    let started_at = parse(state.started_at.as_ref())?;
+    parse(state.finished_at.as_ref()).filter(|finished_at| *finished_at <= started_at)
+}
+
+/// cumulative CPU time went backwards, so the container restarted in between
+fn cpu_counter_restarted(
+    previous: &ContainerStatsResponse,
+    current: &ContainerStatsResponse,
+) -> bool {
+    let total_usage = |stat: &ContainerStatsResponse| {
+        stat.cpu_stats
+            .as_ref()
+            .and_then(|c| c.cpu_usage.as_ref())
+            .and_then(|u| u.total_usage)
+    };
+    matches!(
+        (total_usage(previous), total_usage(current)),
+        (Some(previous), Some(current)) if current < previous
+    )
+}
+
+/// inspect details of a running container, cached until the container is no
+/// longer listed or restarts, so changes by `docker update` are not seen
+async fn inspect_running_container(
+    docker: &Docker,
+    id: &str,
+    inspected: &Mutex<HashMap<String, InspectedContainer>>,
+    retries: u32,
+) -> InspectedContainer {
+    if let Some(container) = inspected.lock().await.get(id) {
+        return *container;
+    }
+
+    let inspect = with_retries(retries, "inspect container", || {
+        docker.inspect_container(id, None::<InspectContainerOptions>)
+    });
+    match inspect.await {
+        Ok(inspect) => {
+            let container = InspectedContainer::from_inspect(&inspect);
+            inspected.lock().await.insert(id.to_owned(), container);
+            container
+        }
+        Err(e) => {
+            warn!("inspect container {} failed, error: {}", id, e);
+            InspectedContainer::default()
+        }
+    }
+}
+
+/// delay before retrying a failed docker api call
+const DOCKER_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// call `request` again up to `retries` times while it fails, so a flaky
+/// socket does not fail the whole poll
+async fn with_retries<T, E, F, Fut>(retries: u32, what: &str, mut request: F) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                debug!(
+                    "{} failed, retry {}/{}, error: {}",
+                    what, attempt, retries, e
+                );
+                tokio::time::sleep(DOCKER_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// container of a poll, from list api or inspect api with `--container`
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ListedContainer {
+    summary: ContainerSummary,
+    /// health check status, `None` for containers without health check
+    health: Option<String>,
+}
+
+impl From<ContainerSummary> for ListedContainer {
+    /// health check status as list api reports it in `status`
+    fn from(summary: ContainerSummary) -> Self {
+        let health = health_from_status(summary.status.as_deref());
+        Self { summary, health }
+    }
+}
+
+/// the only container monitored with `--container`, listed like `list_containers`
+/// would, empty when it does not exist or is not in one of `statuses`
+async fn inspect_single_container(
+    docker: &Docker,
+    container: &str,
+    statuses: &[String],
+    retries: u32,
+    timeout: Duration,
+) -> Result<Vec<ListedContainer>, AppError> {
+    let inspect = with_retries(retries, "inspect container", || {
+        list_with_timeout(
+            docker.inspect_container(container, None::<InspectContainerOptions>),
+            timeout,
+            AppError::Inspect,
+        )
+    });
+    let listed = match inspect.await {
+        Ok(inspect) => summary_from_inspect(inspect),
+        Err(AppError::Inspect(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            ..
+        })) => {
+            warn!("container {} not found, no stats collected", container);
+            return Ok(Vec::new());
+        }
+        Err(e) => return Err(e),
+    };
+    let in_statuses = listed
+        .summary
+        .state
+        .is_some_and(|state| statuses.iter().any(|s| *s == state.to_string()));
+    Ok(if in_statuses {
+        vec![listed]
+    } else {
+        Vec::new()
+    })
+}
+
+/// container summary from inspect api like `list_containers` reports it, with
+/// its health check status
+fn summary_from_inspect(inspect: ContainerInspectResponse) -> ListedContainer {
+    let state = inspect.state.unwrap_or_default();
+    let health = state
+        .health
+        .and_then(|h| h.status)
+        .filter(|s| *s != HealthStatusEnum::NONE && *s != HealthStatusEnum::EMPTY)
+        .map(|s| s.to_string());
+    let summary = ContainerSummary {
+        id: inspect.id,
+        names: inspect.name.map(|name| vec![name]),
+        created: inspect
+            .created
+            .and_then(|c| humantime::parse_rfc3339_weak(&c).ok())
+            .and_then(|c| c.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|c| c.as_secs() as i64),
+        labels: inspect.config.and_then(|c| c.labels),
+        state: state.status.and_then(|s| s.to_string().parse().ok()),
+        command: inspect.path.filter(|path| !path.is_empty()).map(|path| {
+            std::iter::once(path)
+                .chain(inspect.args.unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }),
+        mounts: inspect.mounts,
+        ..Default::default()
+    };
+    ListedContainer { summary, health }
+}
+
+/// clock ticks per second of CPU times in `/proc/<pid>/stat`, `USER_HZ` is
+/// 100 on all mainstream architectures
+const USER_HZ: f64 = 100.;
+
+/// resource usage of the exporter process itself
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ProcessStats {
+    resident_memory: u64,
+    cpu_seconds: f64,
+}
+
+impl ProcessStats {
+    /// usage from `/proc/self/stat` and `/proc/self/status`, `None` where they
+    /// are missing like outside of Linux
+    async fn read() -> Option<Self> {
+        let stat = tokio::fs::read_to_string("/proc/self/stat").await.ok()?;
+        let status = tokio::fs::read_to_string("/proc/self/status").await.ok()?;
+        Self::parse(&stat, &status)
+    }
+
+    fn parse(stat: &str, status: &str) -> Option<Self> {
+        // the command name in parentheses may contain spaces, so fields are
+        // counted from its end, `utime` and `stime` are fields 14 and 15
+        let fields = stat[stat.rfind(')')? + 1..]
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let utime = fields.get(11)?.parse::<u64>().ok()?;
+        let stime = fields.get(12)?.parse::<u64>().ok()?;
+        let rss_kb = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()?;
+        Some(Self {
+            resident_memory: rss_kb * 1024,
+            cpu_seconds: (utime + stime) as f64 / USER_HZ,
+        })
+    }
+
+    fn set_metrics(&self, metrics: &DockerStatProcessMetrics) {
+        metrics.resident_memory.set(self.resident_memory as i64);
+        metrics.cpu_seconds.inc_by(self.cpu_seconds);
+    }
+}
+
+/// daemon overview from docker info api, missing counts are 0
+#[derive(Debug, Clone, Default, PartialEq)]
+struct DaemonInfo {
+    containers_running: i64,
+    containers_paused: i64,
+    containers_stopped: i64,
+    images: i64,
+    mem_total: i64,
+    ncpu: i64,
+}
+
+impl From<&SystemInfo> for DaemonInfo {
+    fn from(info: &SystemInfo) -> Self {
+        Self {
+            containers_running: info.containers_running.unwrap_or(0),
+            containers_paused: info.containers_paused.unwrap_or(0),
+            containers_stopped: info.containers_stopped.unwrap_or(0),
+            images: info.images.unwrap_or(0),
+            mem_total: info.mem_total.unwrap_or(0),
+            ncpu: info.ncpu.unwrap_or(0),
+        }
+    }
+}
+
+impl DaemonInfo {
+    fn set_metrics(&self, metrics: &DockerDaemonMetrics) {
+        for (state, count) in [
+            ("running", self.containers_running),
+            ("paused", self.containers_paused),
+            ("stopped", self.containers_stopped),
+        ] {
+            metrics
+                .containers
+                .get_or_create(&vec![("state".to_owned(), state.to_owned())])
+                .set(count);
+        }
+        metrics.images.set(self.images);
+        metrics.memory.set(self.mem_total);
+        metrics.cpus.set(self.ncpu);
+    }
+}
+
+/// await container listing or inspect with a deadline, so a hung daemon fails
+/// the poll instead of stalling it, zero `timeout` waits forever
+async fn list_with_timeout<T, F>(
+    list: F,
+    timeout: Duration,
+    map_err: fn(bollard::errors::Error) -> AppError,
+) -> Result<T, AppError>
+where
+    F: Future<Output = Result<T, bollard::errors::Error>>,
+{
+    if timeout.is_zero() {
+        return list.await.map_err(map_err);
+    }
+    match tokio::time::timeout(timeout, list).await {
+        Ok(result) => result.map_err(map_err),
+        Err(_) => Err(AppError::ListTimeout(timeout)),
+    }
+}
+
+/// indices of next `batch_size` containers starting at `cursor`, wrapping
+/// around, and the cursor of the batch after it
+fn next_batch(cursor: usize, len: usize, batch_size: usize) -> (HashSet<usize>, usize) {
+    if len == 0 || batch_size >= len {
+        return ((0..len).collect(), 0);
+    }
+    let cursor = cursor % len;
+    let batch = (0..batch_size).map(|i| (cursor + i) % len).collect();
+    (batch, (cursor + batch_size) % len)
+}
+
+/// long-lived stats stream of a container, its reader task keeps the latest
+/// frame until a poll takes it
+#[derive(Debug)]
+struct StatStream {
+    latest: Arc<Mutex<Option<(ContainerStatsResponse, SystemTime)>>>,
+    handle: JoinHandle<()>,
+}
+impl Drop for StatStream {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// open a streaming stats request of a container, docker sends a frame about
+/// every second until the container stops or the connection drops
+fn open_stat_stream(docker: &Docker, id: &str) -> StatStream {
+    let latest = Arc::new(Mutex::new(None));
+    let docker = docker.clone();
+    let id = id.to_owned();
+    let frame_slot = latest.clone();
+    let handle = tokio::spawn(async move {
+        let stats_option = Some(StatsOptionsBuilder::new().stream(true).build());
+        let mut stats_stream = docker.stats(&id, stats_option);
+        loop {
+            match stats_stream.try_next().await {
+                Ok(Some(frame)) => *frame_slot.lock().await = Some((frame, SystemTime::now())),
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("stats stream of {} closed, error: {}", id, e);
+                    break;
+                }
+            }
+        }
+    });
+    StatStream { latest, handle }
+}
+
+/// one container stats call counted in a gauge for as long as it is held,
+/// also when the call is cancelled
+struct InflightCollection<'a>(&'a Gauge);
+
+impl<'a> InflightCollection<'a> {
+    fn start(gauge: &'a Gauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for InflightCollection<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// stats streams held open across polls with `stream_mode`, by container id
+#[derive(Debug, Default)]
+struct StatStreams {
+    streams: HashMap<String, StatStream>,
+}
+
+impl StatStreams {
+    /// latest frame received since it was last taken, opening the stream with
+    /// `open` when it is missing or has ended, a peek without `take` leaves
+    /// the frame for the next poll
+    async fn latest(
+        &mut self,
+        id: &str,
+        take: bool,
+        open: impl FnOnce() -> StatStream,
+    ) -> Option<(ContainerStatsResponse, SystemTime)> {
+        if self.streams.get(id).is_none_or(|s| s.handle.is_finished()) {
+            self.streams.insert(id.to_owned(), open());
+        }
+        let mut latest = self.streams[id].latest.lock().await;
+        if take { latest.take() } else { latest.clone() }
+    }
+
+    /// close streams of containers no longer listed
+    fn retain(&mut self, ids: &HashSet<String>) {
+        self.streams.retain(|id, _| ids.contains(id));
+    }
+}
+
+/// stats of all listed containers, `batch_cursor` is `None` for histogram
+/// sub-samples in between polls, which sample every container and leave
+/// batches and stream frames to the next poll
+async fn docker_stat_oneshot(
+    host: &str,
+    options: &DockerStatPollingOptions,
+    exit_codes: &Mutex<HashMap<String, i64>>,
+    batch_cursor: Option<&Mutex<usize>>,
+    stat_streams: &Mutex<StatStreams>,
+    inspected: &Mutex<HashMap<String, InspectedContainer>>,
+    inflight: &Gauge,
+) -> Result<Vec<TimedContainerStatsResponse>, AppError> {
+    let docker = connect_docker(host, options)?;
+
+    let mut statuses = vec!["running".to_owned(), "paused".to_owned()];
+    if options.include_stopped {
+        statuses.push("exited".to_owned());
+    }
+    let filters = list_filters(&statuses, options.opt_in_label.as_deref());
+
+    let list_containers_options = Some(
+        ListContainersOptionsBuilder::new()
+            .all(true)
+            .filters(&filters)
+            .build(),
+    );
+
+    let start_at = SystemTime::now();
+    let containers = match &options.container {
+        Some(container) => {
+            inspect_single_container(
+                &docker,
+                container,
+                &statuses,
+                options.docker_retries,
+                options.list_timeout,
+            )
+            .await?
+        }
+        None => with_retries(options.docker_retries, "list containers", || {
+            list_with_timeout(
+                docker.list_containers(list_containers_options.clone()),
+                options.list_timeout,
+                AppError::ListContainers,
+            )
+        })
+        .await?
+        .into_iter()
+        .map(ListedContainer::from)
+        .collect(),
+    };
+    trace!(
+        "containers listed from api in {} μs",
+        SystemTime::now()
+            .duration_since(start_at)
+            .unwrap()
+            .as_micros()
+    );
+
+    // inspected `container` bypasses the label filter of listing
+    let mut containers = containers;
+    if let Some(key) = &options.opt_in_label {
+        containers.retain(|c| is_opted_in(c.summary.labels.as_ref(), key));
+    }
+
+    let is_exited = |c: &ContainerSummary| c.state == Some(ContainerSummaryStateEnum::EXITED);
+    exit_codes.lock().await.retain(|id, _| {
+        containers
+            .iter()
+            .any(|c| c.summary.id.as_ref() == Some(id) && is_exited(&c.summary))
+    });
+    inspected
+        .lock()
+        .await
+        .retain(|id, _| containers.iter().any(|c| c.summary.id.as_ref() == Some(id)));
+
+    // stable order of containers, so batches rotate through all of them,
+    // polls without `batch_cursor` sample all and leave the rotation as it is
+    containers.sort_by(|a, b| a.summary.id.cmp(&b.summary.id));
+    let sampled = match batch_cursor {
+        Some(batch_cursor) if options.batch_size > 0 => {
+            let mut cursor = batch_cursor.lock().await;
+            let (batch, next_cursor) = next_batch(*cursor, containers.len(), options.batch_size);
+            *cursor = next_cursor;
+            Some(batch)
+        }
+        _ => None,
+    };
+
+    let mut stats: Vec<TimedContainerStatsResponse> = Vec::new();
+    let mut streamed_ids = HashSet::new();
+
+    let start_at = SystemTime::now();
+    let now = SystemTime::now();
+    for (index, listed) in containers.iter().enumerate() {
+        let container = &listed.summary;
+        if !is_container_old_enough(container.created, now, options.min_age) {
+            continue;
+        }
+
+        let id = if let Some(s) = &container.id {
+            s
+        } else {
+            continue;
+        };
+        if options
+            .exclude_container
+            .as_deref()
+            .is_some_and(|self_id| id.starts_with(self_id))
+        {
+            continue;
+        }
+        let name = match container
+            .names
+            .as_deref()
+            .and_then(|names| options.name_strategy.pick(names))
+        {
+            Some(name) => name,
+            None => continue,
+        };
+        let mounts = if options.collect_mounts {
+            mounts_from_summary(container.mounts.as_deref())
+        } else {
+            Vec::new()
+        };
+        // record of this container without stat, completed by each branch below
+        let base_response = |time: SystemTime| TimedContainerStatsResponse {
+            id: id.clone(),
+            name: name.clone(),
+            state: container.state.map(|s| s.to_string()),
+            metric_prefix: metric_prefix_from_labels(id, container.labels.as_ref()),
+            compose_service: compose_service_from_labels(container.labels.as_ref(), options.swarm),
+            mounts: mounts.clone(),
+            command: container.command.clone(),
+            stat: None,
+            time,
+            collection_duration: Duration::ZERO,
+            exit_code: None,
+            limits: ContainerLimits::default(),
+            // only running containers inspected after their stats have a restart time
+            restarted_at: None,
+            health: listed.health.clone(),
+            sampled: true,
+        };
+
+        // exited containers have no stats to collect
+        if is_exited(container) {
+            let exit_code =
+                exited_container_exit_code(&docker, id, exit_codes, options.docker_retries).await;
+            stats.push(TimedContainerStatsResponse {
+                exit_code,
+                ..base_response(SystemTime::now())
+            });
+            continue;
+        }
+
+        if options.stream_mode {
+            streamed_ids.insert(id.clone());
+            let frame = stat_streams
+                .lock()
+                .await
+                .latest(id, batch_cursor.is_some(), || open_stat_stream(&docker, id))
+                .await;
+            // no new frame yet, the container keeps its last values like outside of a batch
+            let (stat, time) = match frame {
+                Some((stat, time)) => (Some(stat), time),
+                None => (None, SystemTime::now()),
+            };
+            let running =
+                inspect_running_container(&docker, id, inspected, options.docker_retries).await;
+            stats.push(TimedContainerStatsResponse {
+                sampled: stat.is_some(),
+                stat,
+                limits: running.limits,
+                restarted_at: running.restarted_at,
+                ..base_response(time)
+            });
+            continue;
+        }
+
+        if !sampled.as_ref().is_none_or(|s| s.contains(&index)) {
+            stats.push(TimedContainerStatsResponse {
+                sampled: false,
+                ..base_response(SystemTime::now())
+            });
+            continue;
+        }
+
+        let stats_option = Some(
+            StatsOptionsBuilder::new()
+                .stream(false)
+                .one_shot(true)
+                .build(),
+        );
+        let container_start_at = SystemTime::now();
+        let stats_result = {
+            let _inflight = InflightCollection::start(inflight);
+            with_retries(options.docker_retries, "container stats", || {
+                docker
+                    .stats(id, stats_option.clone())
+                    .try_collect::<Vec<_>>()
+            })
+            .await
+        };
+        match stats_result {
+            Ok(v) => {
+                let time = SystemTime::now();
+                let collection_duration =
+                    time.duration_since(container_start_at).unwrap_or_default();
+                let running =
+                    inspect_running_container(&docker, id, inspected, options.docker_retries).await;
+                stats.push(TimedContainerStatsResponse {
+                    stat: v.first().map(|e| e.clone()),
+                    collection_duration,
+                    limits: running.limits,
+                    restarted_at: running.restarted_at,
+                    ..base_response(time)
+                });
+            }
+            Err(e) => {
+                error!("container stats of {} unavailable, error: {}", id, e);
+                // left for `retain_last_good_stats` to fill with the last stat
+                if options.retain_last_stat {
+                    stats.push(base_response(SystemTime::now()));
+                }
+            }
+        };
+    }
+    if options.stream_mode {
+        stat_streams.lock().await.retain(&streamed_ids);
+    }
+    trace!(
+        "stats of all containers from api in {} μs",
+        SystemTime::now()
+            .duration_since(start_at)
+            .unwrap()
+            .as_micros()
+    );
+    if let Some(slowest) = stats.iter().max_by_key(|s| s.collection_duration) {
+        trace!(
+            "slowest container stats {} in {} μs",
+            slowest.name,
+            slowest.collection_duration.as_micros()
+        );
+    }
+
+    Ok(stats)
+}
+
+/// mark running containers without a stat in this poll as left out of it, so
+/// they keep their last non-null stat like containers outside of a batch,
+/// unless no stat of them was collected yet
+fn retain_last_good_stats(
+    stats: &mut [TimedContainerStatsResponse],
+    last_good: &HashMap<String, TimedContainerStatsResponse>,
+) {
+    for stat in stats.iter_mut() {
+        let is_exited = stat.state.as_deref() == Some("exited");
+        if !stat.sampled || stat.stat.is_some() || is_exited {
+            continue;
+        }
+        if last_good.get(&stat.id).is_some_and(|s| s.stat.is_some()) {
+            debug!("no stats of {} in this poll, keeping last ones", stat.name);
+            stat.sampled = false;
+        }
+    }
+}
+
+/// last stats of containers seen in the `previous` poll but missing from the
+/// `current` one, marked vanished, until `grace` after they went missing
+fn track_vanished(
+    vanished: &mut HashMap<String, (DockerContainerStat, SystemTime)>,
+    previous: &[DockerContainerStat],
+    current: &[DockerContainerStat],
+    now: SystemTime,
+    grace: Duration,
+) -> Vec<DockerContainerStat> {
+    vanished.retain(|id, _| !current.iter().any(|s| s.id == *id));
+    for stat in previous {
+        if current.iter().any(|s| s.id == stat.id) || vanished.contains_key(&stat.id) {
+            continue;
+        }
+        let stat = DockerContainerStat {
+            vanished: true,
+            ..stat.clone()
+        };
+        vanished.insert(stat.id.clone(), (stat, now));
+    }
+    vanished.retain(|_, (_, since)| now.duration_since(*since).unwrap_or_default() < grace);
+    vanished.values().map(|(stat, _)| stat.clone()).collect()
+}
+
+fn set_container_metrics(
+    metrics: &DockerStatContainerMetrics,
+    labels: &ContainerLabels,
+    stat: &DockerContainerStat,
+    units: &MetricUnits,
+) {
+    metrics
+        .cpu_usage
+        .get_or_create(labels)
+        .set(units.cpu_usage(stat.cpu_usage));
+    metrics.mem_usage.get_or_create(labels).set(stat.mem_usage);
+    metrics
+        .mem_usage_ratio
+        .get_or_create(labels)
+        .set(stat.mem_usage_ratio);
+    metrics
+        .mem_kernel
+        .get_or_create(labels)
+        .set(stat.mem_kernel);
+    metrics.mem_dirty.get_or_create(labels).set(stat.mem_dirty);
+    metrics
+        .mem_writeback
+        .get_or_create(labels)
+        .set(stat.mem_writeback);
+    metrics
+        .net_in
+        .get_or_create(labels)
+        .set(units.network.from_bytes(stat.net_in));
+    metrics
+        .net_out
+        .get_or_create(labels)
+        .set(units.network.from_bytes(stat.net_out));
+    metrics
+        .net_in_bps
+        .get_or_create(labels)
+        .set(units.throughput.from_bits_per_second(stat.net_in_bps));
+    metrics
+        .net_out_bps
+        .get_or_create(labels)
+        .set(units.throughput.from_bits_per_second(stat.net_out_bps));
+    metrics.blk_in.get_or_create(labels).set(stat.blk_in);
+    metrics.blk_out.get_or_create(labels).set(stat.blk_out);
+    metrics
+        .blk_in_byteps
+        .get_or_create(labels)
+        .set(stat.blk_in_byteps);
+    metrics
+        .blk_out_byteps
+        .get_or_create(labels)
+        .set(stat.blk_out_byteps);
+    // not summed up for `group_by_service`
+    if !stat.is_service_group() {
+        metrics.mem_limit.get_or_create(labels).set(stat.mem_limit);
+        set_counter(&metrics.net_in_errors, labels, stat.net_in_errors);
+        set_counter(&metrics.net_out_errors, labels, stat.net_out_errors);
+        set_counter(&metrics.net_in_dropped, labels, stat.net_in_dropped);
+        set_counter(&metrics.net_out_dropped, labels, stat.net_out_dropped);
+        set_counter(&metrics.net_in_packets, labels, stat.net_in_packets);
+        set_counter(&metrics.net_out_packets, labels, stat.net_out_packets);
+        set_counter(
+            &metrics.cpu_usage_seconds,
+            labels,
+            stat.cpu_total_usage_ns as f64 / 1_000_000_000.,
+        );
+        metrics
+            .collection_duration
+            .get_or_create(labels)
+            .set(stat.collection_duration_seconds);
+    }
+    metrics
+        .blk_service_time
+        .get_or_create(labels)
+        .set(stat.blk_service_time_seconds);
+    // stats of paused containers do not advance, so their rates read 0 unless
+    // held with `hold_rates_on_pause`
+    let paused = stat.state.as_deref() == Some("paused");
+    metrics.paused.get_or_create(labels).set(paused as i64);
+    if let Some(code) = stat.exit_code {
+        metrics.exit_code.get_or_create(labels).set(code);
+    } else {
+        metrics.exit_code.remove(labels);
+    }
+    if let Some(limit) = stat.cpu_limit {
+        metrics.cpu_limit.get_or_create(labels).set(limit);
+    } else {
+        metrics.cpu_limit.remove(labels);
+    }
+    metrics
+        .precpu_available
+        .get_or_create(labels)
+        .set(stat.precpu_available as i64);
+    metrics.pids.get_or_create(labels).set(stat.pids);
+    if let Some(limit) = stat.pids_limit {
+        metrics.pids_limit.get_or_create(labels).set(limit);
+    } else {
+        metrics.pids_limit.remove(labels);
+    }
+    if let Some(shares) = stat.cpu_shares {
+        metrics.cpu_shares.get_or_create(labels).set(shares);
+    } else {
+        metrics.cpu_shares.remove(labels);
+    }
+    if let Some(seconds) = stat.unhealthy_seconds {
+        metrics.unhealthy.get_or_create(labels).set(seconds);
+    } else {
+        metrics.unhealthy.remove(labels);
+    }
+    if let Some(seconds) = stat.time_since_restart_seconds {
+        metrics
+            .time_since_restart
+            .get_or_create(labels)
+            .set(seconds);
+    } else {
+        metrics.time_since_restart.remove(labels);
+    }
+    metrics
+        .info
+        .get_or_create(&info_labels(labels, stat.command.as_deref()))
+        .set(1);
+    for mount in stat.mounts.iter() {
+        metrics
+            .mount_info
+            .get_or_create(&mount.labels(labels))
+            .set(1);
+    }
+    for device in stat.blkio_devices.iter() {
+        let labels = device.labels(labels);
+        metrics
+            .blk_device_in
+            .get_or_create(&labels)
+            .set(device.read_bytes);
+        metrics
+            .blk_device_out
+            .get_or_create(&labels)
+            .set(device.write_bytes);
+    }
+    for net in stat.network_interfaces.iter() {
+        let labels = interface_labels(labels, &net.interface);
+        metrics
+            .net_interface_in
+            .get_or_create(&labels)
+            .set(units.network.from_bytes(net.rx_bytes));
+        metrics
+            .net_interface_out
+            .get_or_create(&labels)
+            .set(units.network.from_bytes(net.tx_bytes));
+    }
+}
+
+/// random extra delay up to `max`, so exporters started together on many hosts
+/// do not keep polling their daemons in lockstep
+fn poll_jitter(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(fastrand::u64(0..=max.as_millis() as u64))
+}
+
+/// a single transient failure should not flip `up` and page people,
+/// only `threshold` consecutive failures do
+fn is_up(consecutive_failures: u32, threshold: u32) -> bool {
+    consecutive_failures < threshold.max(1)
+}
+
+/// select containers by name and/or id, empty filter matches every container
+#[derive(Debug, Clone, Default)]
+pub struct ContainerFilter {
+    /// container name without initial slash
+    pub name: Option<String>,
+    /// full container id or its prefix
+    pub id: Option<String>,
+}
+
+impl ContainerFilter {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.id.is_none()
+    }
+
+    pub fn matches(&self, stat: &DockerContainerStat) -> bool {
+        let name_matches = match &self.name {
+            Some(name) => stat.name.trim_start_matches('/') == name,
+            None => true,
+        };
+        let id_matches = match &self.id {
+            Some(id) => !id.is_empty() && stat.id.starts_with(id.as_str()),
+            None => true,
+        };
+        name_matches && id_matches
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LastDockerAPIContainersStats {
+    pub timestamp: SystemTime,
+    pub stats: HashMap<String, TimedContainerStatsResponse>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DockerVersionInfo {
+    /// version of this exporter
+    pub exporter_version: String,
+    /// docker API version negotiated with the daemon
+    pub api_version: String,
+    pub daemon_version: Option<String>,
+    pub daemon_api_version: Option<String>,
+}
+
+/// published stats kept for subscribers of `/docker/stats/sse` that fall behind
+const STATS_EVENTS_CAPACITY: usize = 4;
+
+/// container stat as written to JSON output, with memory also in MiB and GiB
+/// computed from the bytes
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "DockerContainerStat")]
+struct DockerContainerStatJson<'a> {
+    #[serde(flatten)]
+    stat: &'a DockerContainerStat,
+    /// `mem_usage` in MiB, for readers of JSON output
+    mem_usage_mib: f64,
+    /// `mem_limit` in GiB, for readers of JSON output
+    mem_limit_gib: f64,
+}
+
+impl<'a> From<&'a DockerContainerStat> for DockerContainerStatJson<'a> {
+    fn from(stat: &'a DockerContainerStat) -> Self {
+        Self {
+            stat,
+            mem_usage_mib: bytes_to_mib(stat.mem_usage),
+            mem_limit_gib: bytes_to_gib(stat.mem_limit),
+        }
+    }
+}
+
+fn serialize_stats<S: serde::Serializer>(
+    stats: &[DockerContainerStat],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(stats.iter().map(DockerContainerStatJson::from))
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct LastDockerStats {
+    pub timestamp: SystemTime,
+    #[serde(serialize_with = "serialize_stats")]
+    #[schemars(with = "Vec<DockerContainerStatJson<'static>>")]
+    pub stats: Vec<DockerContainerStat>,
+    /// stats were cut to `--max-json-containers`
+    pub truncated: bool,
+}
+
+/// container of last stats without its stats, listed by `/docker/containers`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContainerEntry {
+    pub id: String,
+    pub name: String,
+    pub state: Option<String>,
+}
+
+/// escape commas, equal signs and spaces of an InfluxDB tag value
+fn escape_influx_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl LastDockerStats {
+    /// sort container stats by `key`, stable so ties keep their order
+    pub fn sort(&mut self, key: StatsSortKey, order: SortOrder) {
+        let compare = |a: &DockerContainerStat, b: &DockerContainerStat| {
+            let by_value = |key: ContainerSortKey| key.value(a).total_cmp(&key.value(b));
+            match key {
+                StatsSortKey::Cpu => by_value(ContainerSortKey::Cpu),
+                StatsSortKey::Mem => by_value(ContainerSortKey::Memory),
+                StatsSortKey::Net => by_value(ContainerSortKey::Network),
+                StatsSortKey::Name => a.name.cmp(&b.name),
+            }
+        };
+        self.stats.sort_by(|a, b| match order {
+            SortOrder::Asc => compare(a, b),
+            SortOrder::Desc => compare(b, a),
+        });
+    }
+
+    /// keep the first `max` container stats, and mark the record truncated
+    /// when any was dropped
+    pub fn truncate(&mut self, max: usize) {
+        if self.stats.len() > max {
+            self.stats.truncate(max);
+            self.truncated = true;
+        }
+    }
+
+    /// stats in InfluxDB line protocol, one `docker_stats` line per container,
+    /// timestamped with the probe time in nanoseconds
+    pub fn to_influx_lines(&self) -> String {
+        let timestamp = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut lines = String::new();
+        for stat in self.stats.iter() {
+            lines.push_str(&format!(
+                "docker_stats,id={},name={} cpu_usage={},mem_usage={}i,mem_limit={}i,\
+                 net_in={}i,net_out={}i,net_in_bps={},net_out_bps={},\
+                 blk_in={}i,blk_out={}i,blk_in_byteps={},blk_out_byteps={} {}\n",
+                escape_influx_tag(&stat.id),
+                escape_influx_tag(stat.name.trim_start_matches('/')),
+                stat.cpu_usage,
+                stat.mem_usage,
+                stat.mem_limit,
+                stat.net_in,
+                stat.net_out,
+                stat.net_in_bps,
+                stat.net_out_bps,
+                stat.blk_in,
+                stat.blk_out,
+                stat.blk_in_byteps,
+                stat.blk_out_byteps,
+                timestamp
+            ));
+        }
+        lines
+    }
+}
+
+/// behavior options of the polling worker
+#[derive(Debug, Clone, Default)]
+pub struct DockerStatPollingOptions {
+    /// labels attached to every container metric
+    pub label_options: ContainerLabelOptions,
+
+    /// record sub-samples of CPU and memory usage into histograms
+    pub histograms: bool,
+
+    /// number of samples taken within each polling interval in histogram mode
+    pub histogram_samples: u32,
+
+    /// exclude containers created less than this duration ago
+    pub min_age: Duration,
+
+    /// consecutive failed polls before `up` metric turns to 0
+    pub failure_threshold: u32,
+
+    /// value of `node` label attached to every metric
+    pub node_label: Option<String>,
+
+    /// read memory limit from cgroup files instead of docker API
+    pub cgroup_direct: bool,
+
+    /// upper bound of random delay added to every polling interval
+    pub poll_jitter: Duration,
+
+    /// directory of docker client TLS `key.pem`, `cert.pem` and `ca.pem`,
+    /// enables TLS for `tcp://` docker hosts
+    pub docker_tls_cert_dir: Option<PathBuf>,
+
+    /// file keeping counter totals across exporter restarts, counters are
+    /// made monotonic over container restarts when set
+    pub state_file: Option<PathBuf>,
+
+    /// cap of containers exported to metrics
+    pub max_containers: Option<usize>,
+
+    /// ranking of containers kept under `max_containers`
+    pub max_containers_sort: ContainerSortKey,
+
+    /// also list exited containers, exporting their exit code
+    pub include_stopped: bool,
+
+    /// print stats to stdout after every poll
+    pub print_format: Option<PrintFormat>,
+
+    /// deadline of listing containers, zero waits forever
+    pub list_timeout: Duration,
+
+    /// containers sampled per poll in rotation, others keep last values, zero samples all
+    pub batch_size: usize,
+
+    /// how long encoded `/metrics` output is reused, zero encodes on every scrape
+    pub metrics_cache_ttl: Duration,
+
+    /// also collect traffic per network interface
+    pub network_interface_label: bool,
+
+    /// also collect blkio per block device
+    pub blkio_per_device: bool,
+
+    /// read stats from streams held open across polls instead of one-shot
+    /// requests, `batch_size` has no effect then
+    pub stream_mode: bool,
+
+    /// units of exported network metrics
+    pub units: MetricUnits,
+
+    /// sum stats of compose service replicas into one series per service
+    pub group_by_service: bool,
+
+    /// retries of each failed docker api call within a poll
+    pub docker_retries: u32,
+
+    /// polling intervals throughputs are averaged over, consecutive polls when
+    /// 0 or 1
+    pub rate_window: u32,
+
+    /// export mounts of containers as info metrics
+    pub collect_mounts: bool,
+
+    /// group by swarm stack and service instead of compose project and
+    /// service, takes effect with `group_by_service`
+    pub swarm: bool,
+
+    /// id or short id of a container left out of polling, the exporter's own
+    pub exclude_container: Option<String>,
+
+    /// keep last non-null stat of running containers whose stats request
+    /// failed or returned nothing, instead of zeroed values
+    pub retain_last_stat: bool,
+
+    /// name or id of the only container monitored, inspected instead of
+    /// listing all containers
+    pub container: Option<String>,
+
+    /// how long containers missing from the latest poll keep being exported
+    /// with their last values and a `stale="true"` label, zero drops them at once
+    pub explicit_stale: Duration,
+
+    /// characters of container commands kept in the `command` label of
+    /// `container_info`, zero keeps them whole
+    pub command_label_max_len: usize,
+
+    /// which name becomes the `name` label of containers with several names
+    pub name_strategy: NameStrategy,
+
+    /// report the last non-zero rates of paused containers instead of 0
+    pub hold_rates_on_pause: bool,
+
+    /// wait before the first poll, eg. for a docker daemon starting alongside
+    pub initial_delay: Duration,
+
+    /// rules of `--relabel-config` applied to the labels of every container
+    /// series, in order
+    pub relabel_rules: Vec<RelabelRule>,
+
+    /// monitor only containers with this label set to `true`
+    pub opt_in_label: Option<String>,
+}
+
+/// series of a container exported by last poll, compared with the next poll
+/// to drop series of containers that are gone or relabeled
+#[derive(Debug, Clone, PartialEq)]
+struct ExportedContainer {
+    prefix: String,
+    labels: ContainerLabels,
+    interfaces: Vec<String>,
+    mounts: Vec<MountInfo>,
+    /// label set of the `info` series
+    info: ContainerLabels,
+    /// label sets of block device series
+    devices: Vec<ContainerLabels>,
+}
+
+/// labels of a sample line without `le`, so lines of one series (histogram
+/// buckets, `_sum` and `_count`) share a key
+fn series_key(line: &str) -> String {
+    let labels = match line.find('{') {
+        Some(start) => &line[start + 1..],
+        None => return String::new(),
+    };
+    // label pairs split at commas outside of quoted values, `le` is left out
+    // wherever it is, const labels like `node` are encoded before it
+    let mut pairs = Vec::new();
+    let mut in_value = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in labels.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_value => escaped = true,
+            '"' => in_value = !in_value,
+            ',' | '}' if !in_value => {
+                pairs.push(&labels[start..i]);
+                start = i + 1;
+                if c == '}' {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    pairs
+        .into_iter()
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("le=\""))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// metrics text with the series of each metric family sorted by labels, led
+/// by `id`, as families keep series in hash maps whose order varies
+fn sort_series(text: &str) -> String {
+    let mut sorted = String::with_capacity(text.len());
+    let mut samples: Vec<&str> = Vec::new();
+    let flush = |samples: &mut Vec<&str>, sorted: &mut String| {
+        // stable, so lines of a series keep their order
+        samples.sort_by_cached_key(|line| series_key(line));
+        for line in samples.drain(..) {
+            sorted.push_str(line);
+            sorted.push('\n');
+        }
+    };
+    for line in text.lines() {
+        if line.starts_with('#') {
+            flush(&mut samples, &mut sorted);
+            sorted.push_str(line);
+            sorted.push('\n');
+        } else {
+            samples.push(line);
+        }
+    }
+    flush(&mut samples, &mut sorted);
+    sorted
+}
+
+/// OpenMetrics text of a registry with series in a stable order
+pub fn encode_sorted(registry: &Registry) -> Result<String, AppError> {
+    let mut body = String::new();
+    text::encode(&mut body, registry)?;
+    Ok(sort_series(&body))
+}
+
+/// encoded metrics text, reused within ttl until new stats are polled
+#[derive(Debug, Clone)]
+struct CachedMetrics {
+    body: String,
+    encoded_at: Instant,
+    stats_timestamp: SystemTime,
+}
+
+/// output format of `print_stat`
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PrintFormat {
+    /// aligned columns with a header row
+    Table,
+    /// indented JSON of last stats, like `/docker/stats?pretty=true`
+    Json,
+    /// tab separated values with a header row
+    Tsv,
+}
+
+const PRINT_HEADER: [&str; 8] = [
+    "ID", "NAME", "CPU", "MEM", "NET_IN", "NET_OUT", "BLK_IN", "BLK_OUT",
+];
+
+fn format_stats(stats: &LastDockerStats, format: PrintFormat) -> String {
+    if format == PrintFormat::Json {
+        return serde_json::to_string_pretty(stats).unwrap_or_else(|e| {
+            error!("serialize stats failed, error: {}", e);
+            String::new()
+        });
+    }
+
+    let mut rows = vec![PRINT_HEADER.map(|h| h.to_owned())];
+    for stat in stats.stats.iter() {
+        rows.push([
+            stat.id[..stat.id.len().min(12)].to_owned(),
+            stat.name.trim_start_matches('/').to_owned(),
+            format!("{:.4}", stat.cpu_usage),
+            stat.mem_usage.to_string(),
+            stat.net_in.to_string(),
+            stat.net_out.to_string(),
+            stat.blk_in.to_string(),
+            stat.blk_out.to_string(),
+        ]);
+    }
+
+    if format == PrintFormat::Tsv {
+        return rows
+            .iter()
+            .map(|row| row.join("\t"))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    let mut widths = [0; PRINT_HEADER.len()];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut lines = vec![format!("Last probe at {:?}", stats.timestamp)];
+    for row in rows.iter() {
+        let cells = row
+            .iter()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(i, (cell, width))| {
+                // id and name are left aligned, numbers right aligned
+                if i < 2 {
+                    format!("{:<width$}", cell)
+                } else {
+                    format!("{:>width$}", cell)
+                }
+            })
+            .collect::<Vec<_>>();
+        lines.push(cells.join("  ").trim_end().to_owned());
+    }
+    lines.join("\n")
+}
+
+/// which of several names of a container becomes its name, eg. with legacy links
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum NameStrategy {
+    /// first name as listed by docker
+    #[default]
+    First,
+    /// longest name, the first of equally long ones
+    Longest,
+    /// all names joined by commas, eg. `/web,db/alias`
+    Join,
+}
+
+impl NameStrategy {
+    /// name picked from docker `Names`, `None` without names
+    fn pick(&self, names: &[String]) -> Option<String> {
+        match self {
+            NameStrategy::First => names.first().cloned(),
+            NameStrategy::Longest => names.iter().rev().max_by_key(|name| name.len()).cloned(),
+            NameStrategy::Join if names.is_empty() => None,
+            NameStrategy::Join => Some(format!(
+                "/{}",
+                names
+                    .iter()
+                    .map(|name| name.trim_start_matches('/'))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            )),
+        }
+    }
+}
+
+/// key to rank containers by when `max_containers` is exceeded
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum ContainerSortKey {
+    #[default]
+    Cpu,
+    Memory,
+    Network,
+    Blkio,
+}
+
+impl ContainerSortKey {
+    fn value(&self, stat: &DockerContainerStat) -> f64 {
+        match self {
+            ContainerSortKey::Cpu => stat.cpu_usage,
+            ContainerSortKey::Memory => stat.mem_usage as f64,
+            ContainerSortKey::Network => stat.net_in_bps + stat.net_out_bps,
+            ContainerSortKey::Blkio => stat.blk_in_byteps + stat.blk_out_byteps,
+        }
+    }
+}
+
+/// key to sort `/docker/stats` responses by
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsSortKey {
+    Cpu,
+    Mem,
+    Name,
+    Net,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// at most `max` containers ranked highest by `key`
+fn top_containers(
+    stats: &[DockerContainerStat],
+    max: usize,
+    key: ContainerSortKey,
+) -> Vec<DockerContainerStat> {
+    let mut sorted = stats.to_vec();
+    sorted.sort_by(|a, b| key.value(b).total_cmp(&key.value(a)));
+    sorted.truncate(max);
+    sorted
+}
+
+/// cumulative byte counters of a container at a poll
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RateSample {
+    time: SystemTime,
+    net_in: u64,
+    net_out: u64,
+    blk_in: u64,
+    blk_out: u64,
+}
+
+impl RateSample {
+    fn new(stat: &DockerContainerStat, time: SystemTime) -> Self {
+        Self {
+            time,
+            net_in: stat.net_in,
+            net_out: stat.net_out,
+            blk_in: stat.blk_in,
+            blk_out: stat.blk_out,
+        }
+    }
+
+    /// any counter went backwards, the container restarted
+    fn is_reset_by(&self, next: &RateSample) -> bool {
+        next.net_in < self.net_in
+            || next.net_out < self.net_out
+            || next.blk_in < self.blk_in
+            || next.blk_out < self.blk_out
+    }
+}
+
+/// samples of a container over the last `rate_window` polling intervals,
+/// oldest first, to smooth throughputs of bursty containers
+#[derive(Debug, Default)]
+struct RateHistory {
+    samples: VecDeque<RateSample>,
+}
+
+impl RateHistory {
+    /// append a sample, keeping `window` intervals, a restart drops older samples
+    fn push(&mut self, sample: RateSample, window: usize) {
+        if self
+            .samples
+            .back()
+            .is_some_and(|last| last.is_reset_by(&sample))
+        {
+            self.samples.clear();
+        }
+        self.samples.push_back(sample);
+        while self.samples.len() > window + 1 {
+            self.samples.pop_front();
+        }
+    }
+
+    /// set throughputs of `stat` from the oldest to the newest sample, left
+    /// as they are until two samples are kept
+    fn apply_rates(&self, stat: &mut DockerContainerStat) {
+        let (oldest, newest) = match (self.samples.front(), self.samples.back()) {
+            (Some(oldest), Some(newest)) if self.samples.len() > 1 => (oldest, newest),
+            _ => return,
+        };
+        let seconds = match newest.time.duration_since(oldest.time) {
+            Ok(elapsed) if !elapsed.is_zero() => elapsed.as_secs_f64(),
+            _ => return,
+        };
+        let rate = |first: u64, last: u64| (last - first) as f64 / seconds;
+        stat.net_in_bps = rate(oldest.net_in, newest.net_in) * 8.;
+        stat.net_out_bps = rate(oldest.net_out, newest.net_out) * 8.;
+        stat.blk_in_byteps = rate(oldest.blk_in, newest.blk_in);
+        stat.blk_out_byteps = rate(oldest.blk_out, newest.blk_out);
+    }
+}
+
+/// containers added and removed between successive polls, counted from the
+/// second poll on
+#[derive(Debug, Default)]
+struct ContainerChurn {
+    added: u64,
+    removed: u64,
+    /// container ids of last poll, `None` before the first one
+    known: Option<HashSet<String>>,
+}
+
+impl ContainerChurn {
+    fn update(&mut self, current: HashSet<String>) {
+        if let Some(known) = &self.known {
+            self.added += current.difference(known).count() as u64;
+            self.removed += known.difference(&current).count() as u64;
+        }
+        self.known = Some(current);
+    }
+}
+
+/// last raw cumulative value from docker and monotonic total of a counter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct CounterSnapshot {
+    raw: u64,
+    total: u64,
+}
+
+impl CounterSnapshot {
+    /// a raw value lower than last one means the container restarted and its
+    /// docker counters start over from 0
+    fn advance(&mut self, raw: u64) -> u64 {
+        if raw >= self.raw {
+            self.total += raw - self.raw;
+        } else {
+            self.total += raw;
+        }
+        self.raw = raw;
+        self.total
+    }
+}
+
+/// polls a container can be missing from before its counter snapshots are
+/// dropped, so a container left out of a single poll keeps its totals
+const COUNTER_STATE_RETAINED_POLLS: u32 = 5;
+
+/// counter snapshots of a container by counter name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+struct ContainerCounters {
+    snapshots: HashMap<String, CounterSnapshot>,
+    /// consecutive polls the container has been missing from
+    #[serde(skip)]
+    missed_polls: u32,
+}
+
+/// counter snapshots by container id
+type CounterState = HashMap<String, ContainerCounters>;
+
+/// replace cumulative counter values of stats by their monotonic totals,
+/// snapshots of containers neither in stats nor in `kept_ids` are dropped
+/// after `COUNTER_STATE_RETAINED_POLLS` polls
+fn accumulate_counters(
+    state: &mut CounterState,
+    stats: &mut [DockerContainerStat],
+    kept_ids: &[String],
+) {
+    let mut current = CounterState::new();
+    for id in kept_ids {
+        if let Some(counters) = state.remove(id) {
+            current.insert(id.clone(), counters);
+        }
+    }
+    for stat in stats.iter_mut() {
+        let mut counters = state.remove(&stat.id).unwrap_or_default();
+        counters.missed_polls = 0;
+        for (name, value) in [
+            ("net_in_errors", &mut stat.net_in_errors),
+            ("net_out_errors", &mut stat.net_out_errors),
+            ("net_in_dropped", &mut stat.net_in_dropped),
+            ("net_out_dropped", &mut stat.net_out_dropped),
+            ("net_in_packets", &mut stat.net_in_packets),
+            ("net_out_packets", &mut stat.net_out_packets),
+            ("cpu_total_usage_ns", &mut stat.cpu_total_usage_ns),
+        ] {
+            *value = counters
+                .snapshots
+                .entry(name.to_owned())
+                .or_default()
+                .advance(*value);
+        }
+        current.insert(stat.id.clone(), counters);
+    }
+    for (id, mut counters) in state.drain() {
+        counters.missed_polls += 1;
+        if counters.missed_polls <= COUNTER_STATE_RETAINED_POLLS {
+            current.insert(id, counters);
+        }
+    }
+    *state = current;
+}
+
+#[derive(Debug)]
+pub struct DockerStatPollingWorker {
+    docker_host: String,
+    options: DockerStatPollingOptions,
+    prom_registry_prefix: Arc<Mutex<String>>,
+    delay_ms: Arc<Mutex<u64>>,
+
+    /// last collected docker stats record
+    last_stats: Arc<Mutex<LastDockerStats>>,
+
+    /// last records of `GET /container/{id}/stats` api
+    last_docker_stats: Arc<Mutex<LastDockerAPIContainersStats>>,
+
+    /// container metrics by metric prefix, updated in place after every poll
+    container_metrics: Arc<Mutex<HashMap<String, DockerStatContainerMetrics>>>,
+
+    /// metric prefix and label set of currently exported containers by container id
+    container_labels: Arc<Mutex<HashMap<String, ExportedContainer>>>,
+
+    /// docker daemon version, fetched once on first request
+    docker_version: Arc<Mutex<Option<DockerVersionInfo>>>,
+
+    /// failed polls since last successful one
+    consecutive_failures: Arc<Mutex<u32>>,
+
+    /// counter totals kept across restarts with `state_file`
+    counter_state: Arc<Mutex<CounterState>>,
+
+    /// exit codes of exited containers by container id, from inspect api
+    exit_codes: Arc<Mutex<HashMap<String, i64>>>,
+
+    /// index of first container of next batch with `batch_size`
+    batch_cursor: Arc<Mutex<usize>>,
+
+    /// last encoded metrics with `metrics_cache_ttl`
+    metrics_cache: Arc<Mutex<Option<CachedMetrics>>>,
+
+    /// host cgroup version detected from stats responses
+    cgroup_version: Arc<Mutex<Option<CgroupVersion>>>,
+
+    /// stats streams of containers with `stream_mode`
+    stat_streams: Arc<Mutex<StatStreams>>,
+
+    /// inspect details of running containers by container id
+    inspected: Arc<Mutex<HashMap<String, InspectedContainer>>>,
+
+    /// time each unhealthy container first reported unhealthy, by container id
+    unhealthy_since: Arc<Mutex<HashMap<String, SystemTime>>>,
+
+    /// last non-zero rates of running containers by container id, with
+    /// `hold_rates_on_pause`
+    active_rates: Arc<Mutex<HashMap<String, ActiveRates>>>,
+
+    /// last docker info, fetched once per poll, `None` until a call succeeds
+    daemon_info: Arc<Mutex<Option<DaemonInfo>>>,
+
+    /// recent samples of containers by container id with `rate_window`
+    rate_history: Arc<Mutex<HashMap<String, RateHistory>>>,
+
+    /// last stats of containers missing from recent polls and when they went
+    /// missing, by container id with `explicit_stale`
+    vanished: Arc<Mutex<HashMap<String, (DockerContainerStat, SystemTime)>>>,
+
+    /// containers added and removed across polls
+    churn: Arc<Mutex<ContainerChurn>>,
+
+    /// polls whose collection and parsing took longer than the polling interval
+    slow_polls: Arc<Mutex<u64>>,
+
+    /// last stats published after every poll, for `/docker/stats/sse`
+    stats_events: broadcast::Sender<Arc<LastDockerStats>>,
+
+    /// result of last ping and last docker error, for `/debug/connection`
+    connection: Arc<Mutex<DockerConnectionState>>,
+
+    /// container stats calls currently awaiting the docker api
+    inflight_collections: Gauge,
+
+    /// resource usage of this process, read once per poll, `None` outside of Linux
+    process_stats: Arc<Mutex<Option<ProcessStats>>>,
+}
+
+impl DockerStatPollingWorker {
+    async fn task_handler(&self) {
+        if !self.options.initial_delay.is_zero() {
+            info!("first poll in {:?}", self.options.initial_delay);
+            tokio::time::sleep(self.options.initial_delay).await;
+        }
+        loop {
+            let cycle_start_at = Instant::now();
+            self.refresh_process_stats().await;
+            // get last docker stats from api
+            let mut last_api_stats = match docker_stat_oneshot(
+                &self.docker_host,
+                &self.options,
+                &self.exit_codes,
+                Some(&self.batch_cursor),
+                &self.stat_streams,
+                &self.inspected,
+                &self.inflight_collections,
+            )
+            .await
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("docker_stat_oneshot failed, error: {}", e);
+                    self.connection.lock().await.last_error = Some(e.to_string());
+                    *self.consecutive_failures.lock().await += 1;
+                    tokio::time::sleep(self.get_delay().await).await;
+                    continue;
+                }
+            };
+            *self.consecutive_failures.lock().await = 0;
+            self.refresh_daemon_info().await;
+            self.churn
+                .lock()
+                .await
+                .update(last_api_stats.iter().map(|s| s.id.clone()).collect());
+            if self.options.retain_last_stat {
+                retain_last_good_stats(
+                    &mut last_api_stats,
+                    &self.last_docker_stats.lock().await.stats,
+                );
+            }
+            let whole_start_at = SystemTime::now();
+
+            let mut parsed_stat = Vec::new();
+            let mut stale_stat = Vec::new();
+
+            let start_at = SystemTime::now();
+            for container_api_stat in last_api_stats.iter() {
+                if !container_api_stat.sampled {
+                    let last_stat = {
+                        let last_stat_guard = self.last_stats.lock().await;
+                        last_stat_guard
+                            .stats
+                            .iter()
+                            .find(|s| s.id == container_api_stat.id)
+                            .cloned()
+                    };
+                    // listing is fresh even when stats are not
+                    stale_stat.extend(last_stat.map(|mut s| {
+                        s.health = container_api_stat.health.clone();
+                        s
+                    }));
+                    continue;
+                }
+
+                // fields of the container itself, kept with or without stat
+                let base = DockerContainerStat {
+                    id: container_api_stat.id.clone(),
+                    name: container_api_stat.name.clone(),
+                    state: container_api_stat.state.clone(),
+                    metric_prefix: container_api_stat.metric_prefix.clone(),
+                    compose_service: container_api_stat.compose_service.clone(),
+                    mounts: container_api_stat.mounts.clone(),
+                    command: container_api_stat
+                        .command
+                        .as_deref()
+                        .map(|c| truncate_command(c, self.options.command_label_max_len)),
+                    health: container_api_stat.health.clone(),
+                    ..Default::default()
+                };
+                let mut stat = if let Some(ref s) = container_api_stat.stat {
+                    let cpu_usage = if let Some(cpu_stats) = &s.cpu_stats {
+                        let system_cpu_usage = cpu_stats.system_cpu_usage.unwrap_or(0) as f64;
+                        let total_usage = if let Some(u) = &cpu_stats.cpu_usage {
+                            u.total_usage.unwrap_or(0) as f64
+                        } else {
+                            0.
+                        };
+                        total_usage / system_cpu_usage
+                    } else {
+                        0.
+                    };
+
+                    let cpu_total_usage_ns = s
+                        .cpu_stats
+                        .as_ref()
+                        .and_then(|c| c.cpu_usage.as_ref())
+                        .and_then(|u| u.total_usage)
+                        .unwrap_or(0);
+
+                    let (mem_usage, mem_limit) = if let Some(mem_stats) = &s.memory_stats {
+                        let limit = mem_stats.limit.unwrap_or(0);
+                        let usage = match get_mem(&mem_stats) {
+                            Ok(u) => u,
+                            Err(e) => {
+                                warn!("get_mem failed, error: {}", e);
+                                0
+                            }
+                        };
+                        (usage, limit)
+                    } else {
+                        (0, 0)
+                    };
+                    let mem_kernel = s.memory_stats.as_ref().map_or(0, get_mem_kernel);
+                    let (mem_dirty, mem_writeback) =
+                        s.memory_stats.as_ref().map_or((0, 0), get_mem_dirty);
+                    // unlimited or unreadable cgroup keeps API value, which is host memory when unlimited
+                    let mem_limit = if self.options.cgroup_direct {
+                        read_cgroup_memory_max(
+                            &container_api_stat.id,
+                            self.options.label_options.cgroup_driver,
+                        )
+                        .await
+                        .unwrap_or(mem_limit)
+                    } else {
+                        mem_limit
+                    };
+
+                    // net io
+                    let (net_in, net_out) = if let Some(networks) = &s.networks {
+                        get_net_io(networks)
+                    } else {
+                        (0, 0)
+                    };
+                    let (net_in_packets, net_out_packets) = if let Some(networks) = &s.networks {
+                        get_net_packets(networks)
+                    } else {
+                        (0, 0)
+                    };
+                    let net_errors = if let Some(networks) = &s.networks {
+                        get_net_errors(networks)
+                    } else {
+                        NetworkErrorStats::default()
+                    };
+                    let network_interfaces = match &s.networks {
+                        Some(networks) if self.options.network_interface_label => {
+                            get_net_interfaces(networks)
+                        }
+                        _ => Vec::new(),
+                    };
+
+                    // blk io
+                    let blkio_devices = match &s.blkio_stats {
+                        Some(blkio) if self.options.blkio_per_device => {
+                            get_blk_devices(blkio, block_device_name)
+                        }
+                        _ => Vec::new(),
+                    };
+                    let (blk_in, blk_out) = if let Some(blkio) = &s.blkio_stats {
+                        get_blk_io(blkio)
+                    } else {
+                        (0, 0)
+                    };
+                    let blk_service_time = s.blkio_stats.as_ref().map_or(0, get_blk_service_time);
+
+                    DockerContainerStat {
+                        cpu_usage,
+                        cpu_total_usage_ns,
+                        precpu_available: has_precpu_stats(s),
+                        mem_usage,
+                        mem_limit,
+                        mem_usage_ratio: mem_usage_ratio(mem_usage, mem_limit),
+                        mem_kernel,
+                        mem_dirty,
+                        mem_writeback,
+                        net_in,
+                        net_out,
                         blk_in,
                         blk_out,
+                        blk_service_time_seconds: blk_service_time as f64 / 1_000_000_000.,
+                        net_in_errors: net_errors.rx_errors,
+                        net_out_errors: net_errors.tx_errors,
+                        net_in_dropped: net_errors.rx_dropped,
+                        net_out_dropped: net_errors.tx_dropped,
+                        net_in_packets,
+                        net_out_packets,
+                        network_interfaces,
+                        blkio_devices,
+                        cpu_limit: container_api_stat.limits.cpu,
+                        pids: s.pids_stats.as_ref().and_then(|p| p.current).unwrap_or(0),
+                        pids_limit: container_api_stat.limits.pids,
+                        cpu_shares: container_api_stat.limits.shares,
+                        time_since_restart_seconds: container_api_stat
+                            .restarted_at
+                            .and_then(|t| container_api_stat.time.duration_since(t).ok())
+                            .map(|d| d.as_secs_f64()),
+                        collection_duration_seconds: container_api_stat
+                            .collection_duration
+                            .as_secs_f64(),
+                        ..base
+                    }
+                } else {
+                    DockerContainerStat {
+                        exit_code: container_api_stat.exit_code,
+                        ..base
+                    }
+                };
+
+                // previous docker stat from api
+                let pre_api_stat = {
+                    let stat_guard = self.last_docker_stats.lock().await;
+                    stat_guard
+                        .stats
+                        .get(&container_api_stat.id)
+                        .map(|s| s.clone())
+                };
+
+                if let Some(pre_api_stat) = pre_api_stat {
+                    if let (Some(pre_container_stat), Some(container_stat)) =
+                        (pre_api_stat.stat, &container_api_stat.stat)
+                    {
+                        // inspect again next poll for the new run
+                        if cpu_counter_restarted(&pre_container_stat, container_stat) {
+                            self.inspected.lock().await.remove(&container_api_stat.id);
+                        }
+                        let duration = container_api_stat
+                            .time
+                            .duration_since(pre_api_stat.time)
+                            .unwrap();
+                        let time_delta = 1_000_000_000. / duration.as_nanos() as f64;
+
+                        // get cpu use between the stats
+                        let cpu_usage = if let (Some(first_cpustat), Some(second_cpu_stat)) =
+                            (&pre_container_stat.cpu_stats, &container_stat.cpu_stats)
+                        {
+                            get_cpu_usage(first_cpustat, second_cpu_stat, time_delta)
+                        } else {
+                            0.0
+                        };
+                        stat.cpu_usage = cpu_usage;
+
+                        // get netio bps between the stats
+                        let (first_net_in, first_net_out) =
+                            if let Some(networks) = &pre_container_stat.networks {
+                                get_net_io(networks)
+                            } else {
+                                (0, 0)
+                            };
+                        let (net_in_bps, net_out_bps) = (
+                            (stat.net_in - first_net_in) as f64 * time_delta,
+                            (stat.net_out - first_net_out) as f64 * time_delta,
+                        );
+                        stat.net_in_bps = net_in_bps * 8.;
+                        stat.net_out_bps = net_out_bps * 8.;
+
+                        // get blkio bps between the stats
+                        let (first_blk_in, first_blk_out) =
+                            if let Some(blkio) = &pre_container_stat.blkio_stats {
+                                get_blk_io(blkio)
+                            } else {
+                                (0, 0)
+                            };
+                        let (blk_in_byteps, blk_out_byteps) = (
+                            (stat.blk_in - first_blk_in) as f64 * time_delta,
+                            (stat.blk_out - first_blk_out) as f64 * time_delta,
+                        );
+                        stat.blk_in_byteps = blk_in_byteps;
+                        stat.blk_out_byteps = blk_out_byteps;
+                    }
+                }
+
+                // smooth throughputs over the last polls, replacing consecutive poll rates
+                if self.options.rate_window > 1 && container_api_stat.stat.is_some() {
+                    let mut history_guard = self.rate_history.lock().await;
+                    let history = history_guard
+                        .entry(container_api_stat.id.clone())
+                        .or_default();
+                    history.push(
+                        RateSample::new(&stat, container_api_stat.time),
+                        self.options.rate_window as usize,
+                    );
+                    history.apply_rates(&mut stat);
+                }
+
+                parsed_stat.push(stat);
+            }
+            self.rate_history
+                .lock()
+                .await
+                .retain(|id, _| last_api_stats.iter().any(|s| &s.id == id));
+            trace!(
+                "parsed all containers stats in {} μs",
+                SystemTime::now()
+                    .duration_since(start_at)
+                    .unwrap()
+                    .as_micros() as u64
+            );
+
+            // stale stats already hold totals, so only fresh ones are accumulated
+            if self.options.state_file.is_some() {
+                let stale_ids = stale_stat.iter().map(|s| s.id.clone()).collect::<Vec<_>>();
+                accumulate_counters(
+                    &mut *self.counter_state.lock().await,
+                    &mut parsed_stat,
+                    &stale_ids,
+                );
+                // written every poll, so a crash loses at most one poll of totals
+                if let Err(e) = self.save_state().await {
+                    error!("save state file failed, error: {}", e);
+                }
+            }
+            parsed_stat.append(&mut stale_stat);
+            track_unhealthy(
+                &mut *self.unhealthy_since.lock().await,
+                &mut parsed_stat,
+                SystemTime::now(),
+            );
+            if self.options.hold_rates_on_pause {
+                hold_paused_rates(&mut *self.active_rates.lock().await, &mut parsed_stat);
+            }
+            let fresh_count = parsed_stat.len();
+            if !self.options.explicit_stale.is_zero() {
+                let previous = self.last_stats.lock().await;
+                parsed_stat.extend(track_vanished(
+                    &mut *self.vanished.lock().await,
+                    &previous.stats,
+                    &parsed_stat,
+                    SystemTime::now(),
+                    self.options.explicit_stale,
+                ));
+            }
+            self.update_container_metrics(&parsed_stat).await;
+            // vanished containers are only exported as metrics
+            parsed_stat.truncate(fresh_count);
+
+            // update last status for next probe
+            let _ = {
+                let mut last_stat_guard = self.last_stats.lock().await;
+                last_stat_guard.timestamp = whole_start_at;
+                last_stat_guard.stats.clear();
+                last_stat_guard.stats.append(&mut parsed_stat);
+            };
+            self.publish_stats().await;
+
+            let _ = {
+                let mut last_api_stat_guard = self.last_docker_stats.lock().await;
+                last_api_stat_guard.timestamp = whole_start_at;
+                let mut previous = std::mem::take(&mut last_api_stat_guard.stats);
+                for api_stat in last_api_stats {
+                    // containers outside of this batch keep their last sampled record
+                    let api_stat = if api_stat.sampled {
+                        api_stat
+                    } else if let Some(pre_api_stat) = previous.remove(&api_stat.id) {
+                        pre_api_stat
+                    } else {
+                        continue;
+                    };
+                    last_api_stat_guard
+                        .stats
+                        .insert(api_stat.id.clone(), api_stat);
+                }
+
+                // keep last detected version while no container reports stats
+                let detected = last_api_stat_guard
+                    .stats
+                    .values()
+                    .find_map(|s| s.stat.as_ref().and_then(detect_cgroup_version));
+                if detected.is_some() {
+                    *self.cgroup_version.lock().await = detected;
+                }
+            };
+            self.check_poll_duration(cycle_start_at.elapsed()).await;
+
+            let delay = self.get_delay().await + poll_jitter(self.options.poll_jitter);
+            if self.options.histograms && self.options.histogram_samples > 1 {
+                self.sleep_with_sub_samples(delay).await;
+            } else {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(format) = self.options.print_format {
+                self.print_stat(format).await;
+            }
+        }
+    }
+
+    async fn get_delay(&self) -> Duration {
+        let delay_guard = self.delay_ms.lock().await;
+        Duration::from_millis(*delay_guard)
+    }
+
+    /// count a poll that took longer than the polling interval, polls drift
+    /// apart from the interval then
+    async fn check_poll_duration(&self, elapsed: Duration) {
+        let delay = self.get_delay().await;
+        if elapsed <= delay {
+            return;
+        }
+        warn!(
+            "poll took {:?}, longer than polling interval {:?}, consider raising --polling_interval",
+            elapsed, delay
+        );
+        *self.slow_polls.lock().await += 1;
+    }
+
+    /// set container metrics from parsed stats, and drop series of containers
+    /// that are gone or relabeled since last poll
+    async fn update_container_metrics(&self, stats: &[DockerContainerStat]) {
+        let registry_prefix = self.prom_registry_prefix.lock().await.clone();
+        let mut labels_guard = self.container_labels.lock().await;
+        let mut metrics_guard = self.container_metrics.lock().await;
+        let mut current_labels = HashMap::new();
+
+        for stat in stats {
+            let prefix = stat
+                .metric_prefix
+                .clone()
+                .unwrap_or_else(|| registry_prefix.clone());
+            let mut labels = container_labels(
+                &stat.id,
+                stat.name.trim_start_matches('/'),
+                &self.options.label_options,
+            );
+            if stat.vanished {
+                labels.push(("stale".to_owned(), "true".to_owned()));
+            }
+            let labels = match relabel(&self.options.relabel_rules, labels) {
+                Some(labels) => labels,
+                None => continue,
+            };
+            let metrics = metrics_guard.entry(prefix.clone()).or_default();
+            set_container_metrics(metrics, &labels, stat, &self.options.units);
+            if self.options.histograms {
+                metrics
+                    .cpu_usage_histogram
+                    .get_or_create(&labels)
+                    .observe(stat.cpu_usage);
+                metrics
+                    .mem_usage_histogram
+                    .get_or_create(&labels)
+                    .observe(stat.mem_usage as f64);
+            }
+            let interfaces = stat
+                .network_interfaces
+                .iter()
+                .map(|net| net.interface.clone())
+                .collect();
+            let devices = stat
+                .blkio_devices
+                .iter()
+                .map(|device| device.labels(&labels))
+                .collect();
+            current_labels.insert(
+                stat.id.clone(),
+                ExportedContainer {
+                    prefix,
+                    info: info_labels(&labels, stat.command.as_deref()),
+                    labels,
+                    interfaces,
+                    mounts: stat.mounts.clone(),
+                    devices,
+                },
+            );
+        }
+
+        for (id, exported) in labels_guard.iter() {
+            let metrics = match metrics_guard.get(&exported.prefix) {
+                Some(m) => m,
+                None => continue,
+            };
+            let current = current_labels
+                .get(id)
+                .filter(|c| c.prefix == exported.prefix && c.labels == exported.labels);
+            if current.is_none() {
+                metrics.remove(&exported.labels);
+            }
+            // interfaces come and go with `docker network connect/disconnect`
+            for interface in exported.interfaces.iter() {
+                if !current.is_some_and(|c| c.interfaces.contains(interface)) {
+                    metrics.remove_interface(&exported.labels, interface);
+                }
+            }
+            if current.is_none_or(|c| c.info != exported.info) {
+                metrics.info.remove(&exported.info);
+            }
+            for mount in exported.mounts.iter() {
+                if !current.is_some_and(|c| c.mounts.contains(mount)) {
+                    metrics.mount_info.remove(&mount.labels(&exported.labels));
+                }
+            }
+            for device in exported.devices.iter() {
+                if !current.is_some_and(|c| c.devices.contains(device)) {
+                    metrics.remove_device(device);
+                }
+            }
+        }
+        // overridden prefixes without containers would still expose empty metric families
+        metrics_guard.retain(|prefix, _| {
+            *prefix == registry_prefix || current_labels.values().any(|c| c.prefix == *prefix)
+        });
+        *labels_guard = current_labels;
+    }
+
+    /// split the polling interval into slices and take a sub-sample of CPU and
+    /// memory usage after each slice, so histograms see bursts between polls
+    async fn sleep_with_sub_samples(&self, delay: Duration) {
+        let samples = self.options.histogram_samples;
+        let slice = delay / samples;
+        let mut previous = self.last_docker_stats.lock().await.stats.clone();
+
+        for _ in 1..samples {
+            tokio::time::sleep(slice).await;
+
+            let api_stats = match docker_stat_oneshot(
+                &self.docker_host,
+                &self.options,
+                &self.exit_codes,
+                // sub-samples cover all containers without moving the batch rotation
+                None,
+                &self.stat_streams,
+                &self.inspected,
+                &self.inflight_collections,
+            )
+            .await
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("histogram sub-sample failed, error: {}", e);
+                    continue;
+                }
+            };
+
+            let labels_guard = self.container_labels.lock().await;
+            let metrics_guard = self.container_metrics.lock().await;
+            for api_stat in api_stats {
+                let exported = labels_guard.get(&api_stat.id);
+                let metrics = exported.and_then(|e| metrics_guard.get(&e.prefix));
+                if let (Some(ExportedContainer { labels, .. }), Some(metrics), Some(stat)) =
+                    (exported, metrics, &api_stat.stat)
+                {
+                    let pre_api_stat = previous.get(&api_stat.id);
+                    // a peeked stream frame seen before has nothing new
+                    if pre_api_stat.is_some_and(|pre| pre.time == api_stat.time) {
+                        continue;
+                    }
+                    if let Some(pre_api_stat) = pre_api_stat {
+                        let pre_cpu_stats = pre_api_stat
+                            .stat
+                            .as_ref()
+                            .and_then(|s| s.cpu_stats.as_ref());
+                        let duration = api_stat.time.duration_since(pre_api_stat.time);
+                        if let (Some(pre_cpu_stats), Some(cpu_stats), Ok(duration)) =
+                            (pre_cpu_stats, &stat.cpu_stats, duration)
+                        {
+                            let time_delta = 1_000_000_000. / duration.as_nanos() as f64;
+                            metrics
+                                .cpu_usage_histogram
+                                .get_or_create(labels)
+                                .observe(get_cpu_usage(pre_cpu_stats, cpu_stats, time_delta));
+                        }
+                    }
+
+                    if let Some(Ok(mem_usage)) = stat.memory_stats.as_ref().map(get_mem) {
+                        metrics
+                            .mem_usage_histogram
+                            .get_or_create(labels)
+                            .observe(mem_usage as f64);
+                    }
+                }
+                previous.insert(api_stat.id.clone(), api_stat);
+            }
+        }
+
+        tokio::time::sleep(slice).await;
+    }
+
+    pub fn new(host: &str, polling_millis: u64, options: DockerStatPollingOptions) -> Self {
+        let registry_prefix = "container".to_owned();
+        Self {
+            docker_host: host.to_owned(),
+            options,
+            prom_registry_prefix: Arc::new(Mutex::new(registry_prefix.clone())),
+            delay_ms: Arc::new(Mutex::new(polling_millis)),
+            last_stats: Arc::new(Mutex::new(LastDockerStats {
+                truncated: false,
+                timestamp: SystemTime::now(),
+                stats: Vec::new(),
+            })),
+            last_docker_stats: Arc::new(Mutex::new(LastDockerAPIContainersStats {
+                timestamp: SystemTime::now(),
+                stats: HashMap::new(),
+            })),
+            container_metrics: Arc::new(Mutex::new(HashMap::from([(
+                registry_prefix,
+                DockerStatContainerMetrics::default(),
+            )]))),
+            container_labels: Arc::new(Mutex::new(HashMap::new())),
+            docker_version: Arc::new(Mutex::new(None)),
+            consecutive_failures: Arc::new(Mutex::new(0)),
+            counter_state: Arc::new(Mutex::new(HashMap::new())),
+            exit_codes: Arc::new(Mutex::new(HashMap::new())),
+            batch_cursor: Arc::new(Mutex::new(0)),
+            metrics_cache: Arc::new(Mutex::new(None)),
+            cgroup_version: Arc::new(Mutex::new(None)),
+            stat_streams: Arc::new(Mutex::new(StatStreams::default())),
+            inspected: Arc::new(Mutex::new(HashMap::new())),
+            daemon_info: Arc::new(Mutex::new(None)),
+            rate_history: Arc::new(Mutex::new(HashMap::new())),
+            vanished: Arc::new(Mutex::new(HashMap::new())),
+            churn: Arc::new(Mutex::new(ContainerChurn::default())),
+            slow_polls: Arc::new(Mutex::new(0)),
+            stats_events: broadcast::channel(STATS_EVENTS_CAPACITY).0,
+            connection: Arc::new(Mutex::new(DockerConnectionState::default())),
+            inflight_collections: Gauge::default(),
+            process_stats: Arc::new(Mutex::new(None)),
+            unhealthy_since: Arc::new(Mutex::new(HashMap::new())),
+            active_rates: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// restore counter totals from `state_file`, a missing file is not an error
+    pub async fn load_state(&self) -> Result<(), AppError> {
+        let path = match &self.options.state_file {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let content = match tokio::fs::read(path).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(AppError::StateFile(e)),
+        };
+        let state: CounterState = serde_json::from_slice(&content).map_err(AppError::Serialize)?;
+        *self.counter_state.lock().await = state;
+        Ok(())
+    }
+
+    /// write counter totals to `state_file`, through a temporary file renamed
+    /// over it so an interrupted write leaves the previous state
+    pub async fn save_state(&self) -> Result<(), AppError> {
+        let path = match &self.options.state_file {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let content =
+            serde_json::to_vec(&*self.counter_state.lock().await).map_err(AppError::Serialize)?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, content)
+            .await
+            .map_err(AppError::StateFile)?;
+        tokio::fs::rename(&tmp_path, path)
+            .await
+            .map_err(AppError::StateFile)?;
+        Ok(())
+    }
+
+    /// write last collected stats as JSON
+    pub async fn dump_last_stats(&self, path: &Path) -> Result<(), AppError> {
+        let content = serde_json::to_vec(&self.get_last_container_stats().await)
+            .map_err(AppError::Serialize)?;
+        tokio::fs::write(path, content)
+            .await
+            .map_err(AppError::DumpFile)?;
+        Ok(())
+    }
+
+    pub fn spawn_polling_stat_task(&self, myself: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move { myself.task_handler().await })
+    }
+
+    pub async fn get_cgroup2_data(
+        &self,
+        id: &str,
+    ) -> Result<TimedContainerStatsResponse, AppError> {
+        let stats = {
+            let stats_guard = self.last_docker_stats.lock().await;
+            let container_stat = stats_guard.stats.get(id);
+            container_stat.map(|s| s.clone())
+        };
+
+        match stats {
+            Some(s) => Ok(s),
+            None => Err(AppError::NotFound(format!("container {}", id))),
+        }
+    }
+
+    /// check docker daemon is reachable, and return its version
+    pub async fn ping(&self) -> Result<DockerVersionInfo, AppError> {
+        let result = self.try_ping().await;
+        let mut connection = self.connection.lock().await;
+        connection.last_ping_ok = Some(result.is_ok());
+        if let Err(e) = &result {
+            connection.last_error = Some(e.to_string());
+        }
+        result
+    }
+
+    async fn try_ping(&self) -> Result<DockerVersionInfo, AppError> {
+        let docker = connect_docker(&self.docker_host, &self.options)?;
+        docker.ping().await.map_err(AppError::Connect)?;
+        self.get_version().await
+    }
+
+    /// docker connectivity details, pinging the daemon first
+    pub async fn connection_state(&self) -> DockerConnectionState {
+        let daemon_version = self.ping().await.ok().and_then(|v| v.daemon_version);
+        let (scheme, fallback) = connection_scheme(
+            &self.docker_host,
+            self.options.docker_tls_cert_dir.is_some(),
+        );
+        DockerConnectionState {
+            host: self.docker_host.clone(),
+            scheme: scheme.to_owned(),
+            fallback: fallback.map(str::to_owned),
+            daemon_version,
+            ..self.connection.lock().await.clone()
+        }
+    }
+
+    pub async fn get_version(&self) -> Result<DockerVersionInfo, AppError> {
+        let mut version_guard = self.docker_version.lock().await;
+        if let Some(version) = version_guard.as_ref() {
+            return Ok(version.clone());
+        }
+
+        let docker = connect_docker(&self.docker_host, &self.options)?
+            .negotiate_version()
+            .await
+            .map_err(AppError::Version)?;
+        let daemon_version = docker.version().await.map_err(AppError::Version)?;
+
+        let version = DockerVersionInfo {
+            exporter_version: env!("CARGO_PKG_VERSION").to_owned(),
+            api_version: docker.client_version().to_string(),
+            daemon_version: daemon_version.version,
+            daemon_api_version: daemon_version.api_version,
+        };
+        *version_guard = Some(version.clone());
+        Ok(version)
+    }
+
+    /// fetch docker info for daemon metrics, a failed call keeps the last info
+    async fn refresh_daemon_info(&self) {
+        let docker = match connect_docker(&self.docker_host, &self.options) {
+            Ok(docker) => docker,
+            Err(e) => {
+                warn!("docker info failed, error: {}", e);
+                return;
+            }
+        };
+        let info = with_retries(self.options.docker_retries, "docker info", || docker.info());
+        match info.await {
+            Ok(info) => *self.daemon_info.lock().await = Some(DaemonInfo::from(&info)),
+            Err(e) => warn!("docker info failed, error: {}", e),
+        }
+    }
+
+    async fn refresh_process_stats(&self) {
+        *self.process_stats.lock().await = ProcessStats::read().await;
+    }
+
+    /// metric name prefix of containers without `prometheus.prefix` label
+    pub async fn metric_prefix(&self) -> String {
+        self.prom_registry_prefix.lock().await.clone()
+    }
+
+    /// last stats with CPU usage in the unit of metrics, every JSON or printed
+    /// output of stats goes through here
+    pub async fn get_last_container_stats(&self) -> LastDockerStats {
+        let mut stats = self.last_stats.lock().await.clone();
+        for stat in stats.stats.iter_mut() {
+            stat.cpu_usage = self.options.units.cpu_usage(stat.cpu_usage);
+        }
+        stats
+    }
+
+    /// replace last stats as if collected by a poll, for handler tests
+    #[cfg(test)]
+    pub async fn set_last_stats(&self, stats: Vec<DockerContainerStat>) {
+        self.update_container_metrics(&stats).await;
+        self.last_stats.lock().await.stats = stats;
+    }
+
+    /// ids, names and states of containers of last stats
+    pub async fn get_last_containers(&self) -> Vec<ContainerEntry> {
+        self.last_stats
+            .lock()
+            .await
+            .stats
+            .iter()
+            .map(|stat| ContainerEntry {
+                id: stat.id.clone(),
+                name: stat.name.clone(),
+                state: stat.state.clone(),
+            })
+            .collect()
+    }
+
+    /// receiver of last stats published after every poll, a slow receiver
+    /// misses the oldest ones
+    pub fn subscribe_stats(&self) -> broadcast::Receiver<Arc<LastDockerStats>> {
+        self.stats_events.subscribe()
+    }
+
+    /// send last stats to subscribers, if any
+    pub async fn publish_stats(&self) {
+        // no copy of last stats without subscribers
+        if self.stats_events.receiver_count() == 0 {
+            return;
+        }
+        let stats = Arc::new(self.get_last_container_stats().await);
+        let _ = self.stats_events.send(stats);
+    }
+
+    /// last records of vanished containers still exported with `explicit_stale`,
+    /// they are not part of last stats
+    async fn vanished_stats(&self) -> Vec<DockerContainerStat> {
+        let mut stats = self
+            .vanished
+            .lock()
+            .await
+            .values()
+            .map(|(stat, _)| stat.clone())
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| a.id.cmp(&b.id));
+        stats
+    }
+
+    pub async fn get_last_container_stats_registry(&self) -> Registry {
+        let stats = self.last_stats.lock().await.stats.clone();
+        let vanished = self.vanished_stats().await;
+        if self.options.group_by_service {
+            // totals still sum all containers, the cap applies to services
+            let mut grouped = group_by_service(&stats);
+            grouped.extend(group_vanished_by_service(&grouped, &vanished));
+            let truncated = match self.options.max_containers {
+                Some(max) if grouped.len() > max => {
+                    let truncated = grouped.len() - max;
+                    grouped = top_containers(&grouped, max, self.options.max_containers_sort);
+                    truncated
+                }
+                _ => 0,
+            };
+            let container_metrics = self.stats_container_metrics(&grouped).await;
+            return self
+                .build_registry(&container_metrics, &stats, false, truncated)
+                .await;
+        }
+
+        // vanished containers are ranked with their last values under the cap
+        let exported = [stats.as_slice(), vanished.as_slice()].concat();
+        let exceeded_max = self
+            .options
+            .max_containers
+            .filter(|max| exported.len() > *max);
+        if let Some(max) = exceeded_max {
+            let top = top_containers(&exported, max, self.options.max_containers_sort);
+            let container_metrics = self.stats_container_metrics(&top).await;
+            let mut registry = self
+                .build_registry(&container_metrics, &stats, false, exported.len() - max)
+                .await;
+            // histograms are kept in persistent metrics only, so they are taken from there
+            if self.options.histograms {
+                self.register_histograms_of(&mut registry, &top).await;
+            }
+            return registry;
+        }
+
+        let container_metrics = self.container_metrics.lock().await.clone();
+        self.build_registry(&container_metrics, &stats, self.options.histograms, 0)
+            .await
+    }
+
+    /// metrics of all containers in OpenMetrics text, cached for
+    /// `metrics_cache_ttl` unless new stats are polled meanwhile
+    pub async fn get_encoded_metrics(&self) -> Result<String, AppError> {
+        let ttl = self.options.metrics_cache_ttl;
+        if ttl.is_zero() {
+            return encode_sorted(&self.get_last_container_stats_registry().await);
+        }
+
+        let stats_timestamp = self.last_stats.lock().await.timestamp;
+        let mut cache_guard = self.metrics_cache.lock().await;
+        let fresh_cache = cache_guard
+            .as_ref()
+            .filter(|c| c.stats_timestamp == stats_timestamp && c.encoded_at.elapsed() < ttl);
+        if let Some(cache) = fresh_cache {
+            return Ok(cache.body.clone());
+        }
+
+        let body = encode_sorted(&self.get_last_container_stats_registry().await)?;
+        *cache_guard = Some(CachedMetrics {
+            body: body.clone(),
+            encoded_at: Instant::now(),
+            stats_timestamp,
+        });
+        Ok(body)
+    }
+
+    /// registry of matching containers only, built from last stats on every call
+    /// (histograms are not included)
+    pub async fn get_filtered_container_stats_registry(
+        &self,
+        filter: &ContainerFilter,
+    ) -> Registry {
+        let stats = {
+            let stat_guard = self.last_stats.lock().await;
+            stat_guard
+                .stats
+                .iter()
+                .filter(|s| filter.matches(s))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        let vanished = self
+            .vanished_stats()
+            .await
+            .into_iter()
+            .filter(|s| filter.matches(s));
+        let exported = stats.iter().cloned().chain(vanished).collect::<Vec<_>>();
+        let container_metrics = self.stats_container_metrics(&exported).await;
+        self.build_registry(&container_metrics, &stats, false, 0)
+            .await
+    }
+
+    /// register persistent histograms of given containers only, under the
+    /// metric prefix of each
+    async fn register_histograms_of(&self, registry: &mut Registry, stats: &[DockerContainerStat]) {
+        let exported = self.container_labels.lock().await;
+        let metrics_guard = self.container_metrics.lock().await;
+        for (prefix, metrics) in metrics_guard.iter() {
+            let labels = stats
+                .iter()
+                .filter_map(|stat| exported.get(&stat.id))
+                .filter(|container| &container.prefix == prefix)
+                .map(|container| container.labels.clone())
+                .collect::<Vec<_>>();
+            metrics.register_histograms_of(registry.sub_registry_with_prefix(prefix), &labels);
+        }
+    }
+
+    /// fresh container metrics of given stats by metric prefix, records of
+    /// `group_by_service` are labeled by their compose service and vanished
+    /// records get a `stale` label
+    async fn stats_container_metrics(
+        &self,
+        stats: &[DockerContainerStat],
+    ) -> HashMap<String, DockerStatContainerMetrics> {
+        let registry_prefix = self.prom_registry_prefix.lock().await.clone();
+        let mut container_metrics = HashMap::from([(
+            registry_prefix.clone(),
+            DockerStatContainerMetrics::default(),
+        )]);
+        for stat in stats.iter() {
+            let prefix = stat
+                .metric_prefix
+                .clone()
+                .unwrap_or(registry_prefix.clone());
+            let mut labels = match &stat.compose_service {
+                Some(s) if stat.is_service_group() => service_labels(&s.project, &s.service),
+                _ => container_labels(
+                    &stat.id,
+                    stat.name.trim_start_matches('/'),
+                    &self.options.label_options,
+                ),
+            };
+            if stat.vanished {
+                labels.push(("stale".to_owned(), "true".to_owned()));
+            }
+            let labels = match relabel(&self.options.relabel_rules, labels) {
+                Some(labels) => labels,
+                None => continue,
+            };
+            set_container_metrics(
+                container_metrics.entry(prefix).or_default(),
+                &labels,
+                stat,
+                &self.options.units,
+            );
+        }
+        container_metrics
+    }
+
+    /// `container_metrics` are registered under their metric prefix each,
+    /// `truncated` is the count of containers left out by the container cap
+    async fn build_registry(
+        &self,
+        container_metrics: &HashMap<String, DockerStatContainerMetrics>,
+        stats: &[DockerContainerStat],
+        histograms: bool,
+        truncated: usize,
+    ) -> Registry {
+        let mut registry = match &self.options.node_label {
+            Some(node) => {
+                Registry::with_labels([(Cow::from("node"), Cow::from(node.clone()))].into_iter())
+            }
+            None => Registry::default(),
+        };
+
+        for (prefix, metrics) in container_metrics {
+            metrics.register(
+                registry.sub_registry_with_prefix(prefix),
+                histograms,
+                &self.options.units,
+            );
+        }
+
+        let totals = sum_container_stats(stats);
+        let total_metrics = DockerStatTotalMetrics::default();
+        total_metrics
+            .cpu_usage
+            .set(self.options.units.cpu_usage(totals.cpu_usage));
+        total_metrics.mem_usage.set(totals.mem_usage);
+        let throughput = self.options.units.throughput;
+        total_metrics
+            .net_in_bps
+            .set(throughput.from_bits_per_second(totals.net_in_bps));
+        total_metrics
+            .net_out_bps
+            .set(throughput.from_bits_per_second(totals.net_out_bps));
+
+        let self_metrics = DockerStatSelfMetrics::default();
+        let consecutive_failures = *self.consecutive_failures.lock().await;
+        self_metrics
+            .up
+            .set(is_up(consecutive_failures, self.options.failure_threshold) as i64);
+        let timestamp = self.last_stats.lock().await.timestamp;
+        let data_age = SystemTime::now()
+            .duration_since(timestamp)
+            .unwrap_or_default();
+        self_metrics.data_age.set(data_age.as_secs_f64());
+        self_metrics.truncated.set(truncated as i64);
+        let cgroup_version = match *self.cgroup_version.lock().await {
+            Some(CgroupVersion::V1) => 1,
+            Some(CgroupVersion::V2) => 2,
+            None => 0,
+        };
+        self_metrics.cgroup_version.set(cgroup_version);
+        self_metrics
+            .polling_interval
+            .set(self.get_delay().await.as_secs_f64());
+        let (added, removed) = {
+            let churn = self.churn.lock().await;
+            (churn.added, churn.removed)
+        };
+        self_metrics.containers_added.inc_by(added);
+        self_metrics.containers_removed.inc_by(removed);
+        self_metrics
+            .slow_polls
+            .inc_by(*self.slow_polls.lock().await);
+        self_metrics
+            .inflight_collections
+            .set(self.inflight_collections.get());
+
+        let self_registry = registry.sub_registry_with_prefix("docker_stats");
+        total_metrics.register(self_registry, &self.options.units);
+        self_metrics.register(self_registry);
+        if let Some(process) = *self.process_stats.lock().await {
+            let process_metrics = DockerStatProcessMetrics::default();
+            process.set_metrics(&process_metrics);
+            process_metrics.register(self_registry);
+        }
+
+        if let Some(info) = self.daemon_info.lock().await.as_ref() {
+            let daemon_metrics = DockerDaemonMetrics::default();
+            info.set_metrics(&daemon_metrics);
+            daemon_metrics.register(registry.sub_registry_with_prefix("docker_daemon"));
+        }
+
+        registry
+    }
+
+    pub fn set_delay(&self, duration: Duration) {
+        let mut delay = self.delay_ms.blocking_lock();
+        *delay = duration.as_millis() as u64;
+    }
+
+    pub async fn print_stat(&self, format: PrintFormat) {
+        let last_stats = self.get_last_container_stats().await;
+        println!("{}", format_stats(&last_stats, format));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::secret::{
+        ContainerBlkioStatEntry, ContainerConfig, ContainerState, ContainerStateStatusEnum, Health,
+        MountPointTypeEnum,
+    };
+
+    use crate::docker_stat_metrics::DataUnit;
+
+    fn memory_stats(usage: u64, stats: &[(&str, u64)]) -> ContainerMemoryStats {
+        ContainerMemoryStats {
+            usage: Some(usage),
+            stats: Some(
+                stats
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), *v))
+                    .collect::<HashMap<_, _>>(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_get_mem_cgroup_v1() {
+        let mem = memory_stats(
+            100_000,
+            &[
+                ("cache", 30_000),
+                ("total_cache", 30_000),
+                ("total_inactive_file", 20_000),
+                ("total_rss", 60_000),
+            ],
+        );
+        assert_eq!(
+            detect_memory_cgroup_version(mem.stats.as_ref().unwrap()),
+            CgroupVersion::V1
+        );
+        assert_eq!(get_mem(&mem).unwrap(), 80_000);
+
+        let mem = memory_stats(100_000, &[("cache", 30_000), ("rss", 60_000)]);
+        assert_eq!(get_mem(&mem).unwrap(), 70_000);
+    }
+
+    #[test]
+    fn test_get_mem_cgroup_v2() {
+        let mem = memory_stats(
+            100_000,
+            &[
+                ("anon", 50_000),
+                ("file", 40_000),
+                ("inactive_file", 25_000),
+                ("active_file", 15_000),
+            ],
+        );
+        assert_eq!(
+            detect_memory_cgroup_version(mem.stats.as_ref().unwrap()),
+            CgroupVersion::V2
+        );
+        assert_eq!(get_mem(&mem).unwrap(), 75_000);
+
+        let mem = memory_stats(100_000, &[("anon", 50_000), ("file", 40_000)]);
+        assert_eq!(get_mem(&mem).unwrap(), 60_000);
+    }
+
+    #[test]
+    fn test_detect_cgroup_version() {
+        let v1 = ContainerStatsResponse {
+            memory_stats: Some(memory_stats(
+                100_000,
+                &[("cache", 30_000), ("total_inactive_file", 20_000)],
+            )),
+            ..Default::default()
+        };
+        assert_eq!(detect_cgroup_version(&v1), Some(CgroupVersion::V1));
+
+        let v2 = ContainerStatsResponse {
+            memory_stats: Some(memory_stats(
+                100_000,
+                &[("anon", 50_000), ("inactive_file", 25_000)],
+            )),
+            ..Default::default()
+        };
+        assert_eq!(detect_cgroup_version(&v2), Some(CgroupVersion::V2));
+
+        // without memory stats keys, only cgroup v1 reports serviced io
+        let v1_blkio = ContainerStatsResponse {
+            blkio_stats: Some(ContainerBlkioStats {
+                io_service_bytes_recursive: Some(Vec::new()),
+                io_serviced_recursive: Some(Vec::new()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(detect_cgroup_version(&v1_blkio), Some(CgroupVersion::V1));
+        let v2_blkio = ContainerStatsResponse {
+            blkio_stats: Some(ContainerBlkioStats {
+                io_service_bytes_recursive: Some(Vec::new()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(detect_cgroup_version(&v2_blkio), Some(CgroupVersion::V2));
+
+        assert_eq!(
+            detect_cgroup_version(&ContainerStatsResponse::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_is_container_old_enough() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let min_age = Duration::from_secs(60);
+
+        assert!(is_container_old_enough(Some(1_699_000_000), now, min_age));
+        assert!(is_container_old_enough(Some(1_699_999_940), now, min_age));
+        assert!(!is_container_old_enough(Some(1_699_999_941), now, min_age));
+        assert!(!is_container_old_enough(Some(1_700_000_010), now, min_age));
+        assert!(is_container_old_enough(None, now, min_age));
+
+        // no filter by default
+        assert!(is_container_old_enough(
+            Some(1_700_000_000),
+            now,
+            Duration::ZERO
+        ));
+    }
+
+    #[test]
+    fn test_sum_container_stats() {
+        let stats = vec![
+            DockerContainerStat {
+                cpu_usage: 0.25,
+                mem_usage: 1_000,
+                net_in_bps: 800.,
+                net_out_bps: 80.,
+                ..Default::default()
+            },
+            DockerContainerStat {
+                cpu_usage: 1.5,
+                mem_usage: 2_500,
+                net_in_bps: 200.,
+                net_out_bps: 20.,
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            sum_container_stats(&stats),
+            DockerContainerStatTotals {
+                cpu_usage: 1.75,
+                mem_usage: 3_500,
+                net_in_bps: 1000.,
+                net_out_bps: 100.,
+            }
+        );
+        assert_eq!(
+            sum_container_stats(&[]),
+            DockerContainerStatTotals::default()
+        );
+    }
+
+    fn cpu_stats(
+        total_usage: u64,
+        system_cpu_usage: u64,
+        online_cpus: Option<u32>,
+        percpu_usage: Option<Vec<u64>>,
+    ) -> ContainerCpuStats {
+        ContainerCpuStats {
+            cpu_usage: Some(bollard::secret::ContainerCpuUsage {
+                total_usage: Some(total_usage),
+                percpu_usage,
+                ..Default::default()
+            }),
+            system_cpu_usage: Some(system_cpu_usage),
+            online_cpus,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_has_precpu_stats() {
+        let stat = |precpu_stats| ContainerStatsResponse {
+            cpu_stats: Some(cpu_stats(200, 2000, Some(2), None)),
+            precpu_stats,
+            ..Default::default()
+        };
+        assert!(has_precpu_stats(&stat(Some(cpu_stats(
+            100,
+            1000,
+            Some(2),
+            None
+        )))));
+        // empty in one-shot responses and first stream frames
+        assert!(!has_precpu_stats(&stat(Some(ContainerCpuStats::default()))));
+        assert!(!has_precpu_stats(&stat(None)));
+    }
+
+    #[test]
+    fn test_get_cpu_usage() {
+        let first = cpu_stats(1_000, 100_000, Some(4), None);
+        let second = cpu_stats(6_000, 200_000, Some(4), None);
+        assert_eq!(get_cpu_usage(&first, &second, 1.), 0.2);
+    }
+
+    #[test]
+    fn test_get_cpu_usage_without_online_cpus() {
+        let first = cpu_stats(1_000, 100_000, None, Some(vec![500, 500, 0, 0]));
+        let second = cpu_stats(6_000, 200_000, None, Some(vec![3_000, 3_000, 0, 0]));
+        assert_eq!(get_cpu_usage(&first, &second, 1.), 0.2);
+
+        let first = cpu_stats(1_000, 100_000, None, None);
+        let second = cpu_stats(6_000, 200_000, None, None);
+        assert_eq!(get_cpu_usage(&first, &second, 1.), 0.);
+    }
+
+    #[test]
+    fn test_summary_from_inspect() {
+        let inspect = ContainerInspectResponse {
+            id: Some("abc".to_owned()),
+            name: Some("/web".to_owned()),
+            created: Some("2024-01-02T03:04:05.123456789Z".to_owned()),
+            state: Some(ContainerState {
+                status: Some(ContainerStateStatusEnum::RUNNING),
+                health: Some(Health {
+                    status: Some(HealthStatusEnum::UNHEALTHY),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            config: Some(ContainerConfig {
+                labels: Some(HashMap::from([(
+                    "prometheus.prefix".to_owned(),
+                    "web".to_owned(),
+                )])),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let listed = summary_from_inspect(inspect);
+        assert_eq!(listed.health.as_deref(), Some("unhealthy"));
+        let summary = listed.summary;
+        assert_eq!(summary.id.as_deref(), Some("abc"));
+        assert_eq!(summary.names, Some(vec!["/web".to_owned()]));
+        assert_eq!(summary.created, Some(1704164645));
+        assert_eq!(summary.state, Some(ContainerSummaryStateEnum::RUNNING));
+        assert_eq!(summary.status, None);
+        assert_eq!(
+            metric_prefix_from_labels("abc", summary.labels.as_ref()).as_deref(),
+            Some("web")
+        );
+
+        // no health check, exited
+        let listed = summary_from_inspect(ContainerInspectResponse {
+            state: Some(ContainerState {
+                status: Some(ContainerStateStatusEnum::EXITED),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        assert_eq!(
+            listed.summary.state,
+            Some(ContainerSummaryStateEnum::EXITED)
+        );
+        assert_eq!(listed.health, None);
+    }
+
+    #[test]
+    fn test_connection_scheme() {
+        assert_eq!(
+            connection_scheme("unix:///var/run/docker.sock", false),
+            ("unix", None)
+        );
+        assert_eq!(connection_scheme("/run/docker.sock", false), ("unix", None));
+        assert_eq!(
+            connection_scheme("http://localhost:2375", false),
+            ("http", None)
+        );
+        assert_eq!(
+            connection_scheme("tcp://docker:2376", true),
+            ("tcp+tls", None)
+        );
+        assert_eq!(connection_scheme("tcp://docker", false), ("tcp", None));
+        assert_eq!(
+            connection_scheme("ssh://docker", false),
+            ("default", Some(FALLBACK_UNSUPPORTED_SCHEME))
+        );
+        assert_eq!(
+            connection_scheme("not a uri", false),
+            ("default", Some(FALLBACK_INVALID_URI))
+        );
+    }
+
+    #[test]
+    fn test_unix_socket_path() {
+        assert_eq!(
+            unix_socket_path("unix:///run/user/1000/docker.sock"),
+            Some("/run/user/1000/docker.sock")
+        );
+        assert_eq!(
+            unix_socket_path("unix:///var/run/docker.sock"),
+            Some("/var/run/docker.sock")
+        );
+        assert_eq!(
+            unix_socket_path("unix:///custom.sock"),
+            Some("/custom.sock")
+        );
+        assert_eq!(
+            unix_socket_path("/var/run/docker.sock"),
+            Some("/var/run/docker.sock")
+        );
+        assert_eq!(
+            unix_socket_path("unix:/run/docker.sock"),
+            Some("/run/docker.sock")
+        );
+        assert_eq!(
+            unix_socket_path("unix:///run/docker.sock?timeout=10"),
+            Some("/run/docker.sock")
+        );
+        assert_eq!(unix_socket_path("unix://"), None);
+        assert_eq!(unix_socket_path("unix://?timeout=10"), None);
+        assert_eq!(unix_socket_path("http://localhost:2375"), None);
+        assert_eq!(unix_socket_path("tcp://docker.local"), None);
+    }
+
+    #[test]
+    fn test_is_up() {
+        assert!(is_up(0, 3));
+        assert!(is_up(2, 3));
+        assert!(!is_up(3, 3));
+        assert!(!is_up(4, 3));
+
+        // threshold 0 behaves as 1
+        assert!(is_up(0, 0));
+        assert!(!is_up(1, 0));
+    }
+
+    fn container_stat(id: &str, name: &str) -> DockerContainerStat {
+        DockerContainerStat {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    async fn encode_registry(worker: &DockerStatPollingWorker) -> String {
+        let registry = worker.get_last_container_stats_registry().await;
+        let mut body = String::new();
+        prometheus_client::encoding::text::encode(&mut body, &registry).unwrap();
+        body
+    }
+
+    #[tokio::test]
+    async fn test_sorted_metrics() {
+        assert_eq!(
+            series_key("a_bucket{le=\"0.5\",id=\"x\",name=\"a}b\"} 3"),
+            "id=\"x\",name=\"a}b\""
+        );
+        assert_eq!(
+            series_key("a_sum{id=\"x\",name=\"a}b\"} 3"),
+            "id=\"x\",name=\"a}b\""
+        );
+        assert_eq!(series_key("a_bucket{le=\"+Inf\"} 3"), "");
+        // const labels of the registry come before `le`
+        assert_eq!(
+            series_key("a_bucket{node=\"h\",le=\"0.5\",id=\"x\"} 3"),
+            "node=\"h\",id=\"x\""
+        );
+        assert_eq!(series_key("up 1"), "");
+
+        for node_label in [None, Some("host-a".to_owned())] {
+            assert_sorted_metrics(node_label).await;
+        }
+    }
+
+    async fn assert_sorted_metrics(node_label: Option<String>) {
+        let ids = [
+            "cccccccccccc",
+            "aaaaaaaaaaaa",
+            "dddddddddddd",
+            "bbbbbbbbbbbb",
+        ];
+        let options = DockerStatPollingOptions {
+            histograms: true,
+            node_label,
+            ..Default::default()
+        };
+        let mut bodies = Vec::new();
+        for order in [ids, [ids[3], ids[2], ids[1], ids[0]]] {
+            let worker =
+                DockerStatPollingWorker::new("unix:///var/run/docker.sock", 2000, options.clone());
+            let stats = order
+                .iter()
+                .map(|id| container_stat(id, &format!("/{}", &id[..3])))
+                .collect::<Vec<_>>();
+            worker.update_container_metrics(&stats).await;
+            worker.last_stats.lock().await.stats = stats;
+            let body = worker.get_encoded_metrics().await.unwrap();
+            // the only value depending on time
+            let body = body
+                .lines()
+                .filter(|l| !l.starts_with("docker_stats_data_age_seconds"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            bodies.push(body);
+        }
+        assert_eq!(bodies[0], bodies[1]);
+
+        let cpu_names = bodies[0]
+            .lines()
+            .filter(|l| l.starts_with("container_cpu_usage_ratios{"))
+            .map(|l| l.split("name=\"").nth(1).unwrap()[..3].to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(cpu_names, ["aaa", "bbb", "ccc", "ddd"]);
+        // histogram lines of a series stay together, buckets in order
+        let histogram = bodies[0]
+            .lines()
+            .filter(|l| l.starts_with("container_cpu_usage_ratio_histogram"))
+            .collect::<Vec<_>>();
+        let aaa = histogram
+            .iter()
+            .position(|l| l.contains("name=\"aaa\""))
+            .unwrap();
+        let per_series = histogram.len() / ids.len();
+        assert!(
+            histogram[aaa..aaa + per_series]
+                .iter()
+                .all(|l| l.contains("name=\"aaa\""))
+        );
+        let buckets = histogram[aaa..aaa + per_series]
+            .iter()
+            .filter(|l| l.contains("_bucket{"))
+            .collect::<Vec<_>>();
+        assert!(buckets.last().unwrap().contains("le=\"+Inf\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_container_metrics_removes_stale() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+
+        worker
+            .update_container_metrics(&[
+                container_stat("aaaaaaaaaaaa", "/web"),
+                container_stat("bbbbbbbbbbbb", "/db"),
+            ])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"web\""));
+        assert!(body.contains("name=\"db\""));
+
+        // db is gone, web is renamed
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/frontend")])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"frontend\""));
+        assert!(!body.contains("name=\"web\""));
+        assert!(!body.contains("name=\"db\""));
+        assert_eq!(worker.container_labels.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_container_churn() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        {
+            let mut churn = worker.churn.lock().await;
+            // containers running at startup are not added
+            churn.update(ids(&["a", "b"]));
+            assert_eq!((churn.added, churn.removed), (0, 0));
+            churn.update(ids(&["b", "c", "d"]));
+            assert_eq!((churn.added, churn.removed), (2, 1));
+            churn.update(ids(&["b", "c", "d"]));
+            assert_eq!((churn.added, churn.removed), (2, 1));
+            churn.update(ids(&[]));
+            assert_eq!((churn.added, churn.removed), (2, 4));
+        }
+
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("docker_stats_containers_added_total 2\n"));
+        assert!(body.contains("docker_stats_containers_removed_total 4\n"));
+    }
+
+    #[tokio::test]
+    async fn test_explicit_stale() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let web = container_stat("aaaaaaaaaaaa", "/web");
+        let db = DockerContainerStat {
+            mem_usage: 4096,
+            ..container_stat("bbbbbbbbbbbb", "/db")
+        };
+        let mut vanished = HashMap::new();
+        let grace = Duration::from_secs(30);
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+        let first = [web.clone(), db.clone()];
+        assert!(track_vanished(&mut vanished, &[], &first, t0, grace).is_empty());
+        worker.update_container_metrics(&first).await;
+
+        // db vanishes between polls and is exported with its last values
+        let second = [web.clone()];
+        let stale = track_vanished(&mut vanished, &first, &second, t0 + grace / 2, grace);
+        assert_eq!(stale.len(), 1);
+        assert!(stale[0].vanished);
+        worker
+            .update_container_metrics(&[second.to_vec(), stale].concat())
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"db\",stale=\"true\"} 4096"));
+        assert!(!body.contains("name=\"db\"}"));
+        assert!(body.contains("name=\"web\"}"));
+
+        // still missing after the grace window
+        let later = t0 + grace / 2 + grace;
+        let stale = track_vanished(&mut vanished, &second, &second, later, grace);
+        assert!(stale.is_empty());
+        worker.update_container_metrics(&second).await;
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("name=\"db\""));
+
+        // back again before the window ends
+        track_vanished(&mut vanished, &first, &second, t0, grace);
+        assert!(track_vanished(&mut vanished, &second, &first, t0, grace).is_empty());
+        assert!(vanished.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_explicit_stale_rebuilt_registry() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                max_containers: Some(1),
+                max_containers_sort: ContainerSortKey::Memory,
+                ..Default::default()
+            },
+        );
+        let stat = |id: &str, name: &str, mem_usage: u64| DockerContainerStat {
+            mem_usage,
+            ..container_stat(id, name)
+        };
+        let first = [
+            stat("aaaaaaaaaaaa", "/web", 1024),
+            stat("bbbbbbbbbbbb", "/db", 4096),
+            stat("cccccccccccc", "/cache", 512),
+        ];
+        let second = vec![first[0].clone(), first[2].clone()];
+        track_vanished(
+            &mut *worker.vanished.lock().await,
+            &first,
+            &second,
+            SystemTime::now(),
+            Duration::from_secs(30),
+        );
+        worker.set_last_stats(second).await;
+
+        // db vanishes while the cap is exceeded, and still ranks first
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"db\",stale=\"true\"} 4096"));
+        assert!(!body.contains("name=\"web\""));
+        assert!(body.contains("docker_stats_truncated 2\n"));
+        // totals only cover containers of the latest poll
+        assert!(body.contains("docker_stats_total_memory_bytes 1536\n"));
+
+        let filter = ContainerFilter {
+            name: Some("db".to_owned()),
+            id: None,
+        };
+        let registry = worker.get_filtered_container_stats_registry(&filter).await;
+        let mut body = String::new();
+        prometheus_client::encoding::text::encode(&mut body, &registry).unwrap();
+        assert!(body.contains("name=\"db\",stale=\"true\"} 4096"));
+        assert!(!body.contains("name=\"web\""));
+    }
+
+    #[tokio::test]
+    async fn test_update_container_metrics_removes_stale_interface() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let interface = |name: &str, rx_bytes: u64| NetworkInterfaceStat {
+            interface: name.to_owned(),
+            rx_bytes,
+            tx_bytes: 0,
+        };
+
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.network_interfaces = vec![interface("eth0", 100), interface("eth1", 200)];
+        worker.update_container_metrics(&[stat.clone()]).await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("interface=\"eth0\"} 100"));
+        assert!(body.contains("interface=\"eth1\"} 200"));
+
+        // eth1 is disconnected
+        stat.network_interfaces = vec![interface("eth0", 150)];
+        worker.update_container_metrics(&[stat]).await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("interface=\"eth0\"} 150"));
+        assert!(!body.contains("interface=\"eth1\""));
+    }
+
+    #[tokio::test]
+    async fn test_cpu_usage_seconds() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.cpu_total_usage_ns = 1_500_000_000;
+        worker.update_container_metrics(&[stat.clone()]).await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains(
+            "container_cpu_usage_seconds_total{id=\"/system.slice/docker-aaaaaaaaaaaa.scope\",name=\"web\"} 1.5\n"
+        ));
+
+        stat.cpu_total_usage_ns = 4_250_000_000;
+        worker.update_container_metrics(&[stat]).await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"web\"} 4.25\n"));
+    }
+
+    #[tokio::test]
+    async fn test_cpu_as_percent() {
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.cpu_usage = 0.375;
+
+        let mut values = Vec::new();
+        for cpu_percent in [false, true] {
+            let worker = DockerStatPollingWorker::new(
+                "unix:///var/run/docker.sock",
+                2000,
+                DockerStatPollingOptions {
+                    units: MetricUnits {
+                        cpu_percent,
                         ..Default::default()
-                    }
+                    },
+                    ..Default::default()
+                },
+            );
+            worker.last_stats.lock().await.stats.push(stat.clone());
+            worker.update_container_metrics(&[stat.clone()]).await;
+            let body = encode_registry(&worker).await;
+            let name = if cpu_percent {
+                "container_cpu_usage_percent{"
+            } else {
+                "container_cpu_usage_ratios{"
+            };
+            let line = body.lines().find(|l| l.starts_with(name)).unwrap();
+            let metric = line.rsplit_once(' ').unwrap().1.parse::<f64>().unwrap();
+            let json = worker.get_last_container_stats().await.stats[0].cpu_usage;
+            assert_eq!(metric, json);
+
+            // dumped stats carry the same value
+            let path = std::env::temp_dir()
+                .join(format!("docker-stat-prom-{}.json", uuid::Uuid::new_v4()));
+            worker.dump_last_stats(&path).await.unwrap();
+            let dumped: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+            assert_eq!(dumped["stats"][0]["cpu_usage"], metric);
+            std::fs::remove_file(path).unwrap();
+            values.push(metric);
+        }
+        assert_eq!(values[1], values[0] * 100.);
+        assert_eq!(values[1], 37.5);
+    }
+
+    #[tokio::test]
+    async fn test_network_units() {
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.net_in = 100;
+        stat.net_in_bps = 800.;
+
+        for (units, expected) in [
+            (
+                MetricUnits::default(),
+                [
+                    "container_network_receive_bytes{",
+                    "} 100",
+                    "container_network_receive_bps{",
+                    "} 800.0",
+                    "docker_stats_total_network_receive_bps 800.0",
+                ],
+            ),
+            (
+                MetricUnits {
+                    network: DataUnit::Bits,
+                    throughput: DataUnit::Bytes,
+                    cpu_percent: false,
+                },
+                [
+                    "container_network_receive_bits{",
+                    "} 800",
+                    "container_network_receive_byteps{",
+                    "} 100.0",
+                    "docker_stats_total_network_receive_byteps 100.0",
+                ],
+            ),
+        ] {
+            let worker = DockerStatPollingWorker::new(
+                "unix:///var/run/docker.sock",
+                2000,
+                DockerStatPollingOptions {
+                    units,
+                    ..Default::default()
+                },
+            );
+            worker.last_stats.lock().await.stats.push(stat.clone());
+            worker.update_container_metrics(&[stat.clone()]).await;
+            let body = encode_registry(&worker).await;
+            let line = |prefix: &str| {
+                body.lines()
+                    .find(|l| l.starts_with(prefix))
+                    .unwrap_or_else(|| panic!("{} not in {}", prefix, body))
+                    .to_owned()
+            };
+            assert!(line(expected[0]).ends_with(expected[1]), "{:?}", units);
+            assert!(line(expected[2]).ends_with(expected[3]), "{:?}", units);
+            assert!(body.contains(expected[4]), "{:?}", units);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_group_by_service() {
+        let replica = |id: &str, name: &str, service: Option<&str>, mem_usage: u64| {
+            let mut stat = container_stat(id, name);
+            stat.mem_usage = mem_usage;
+            stat.mem_limit = 1000;
+            stat.mem_usage_ratio = mem_usage_ratio(mem_usage, 1000);
+            stat.net_in_packets = 10;
+            stat.cpu_usage = 0.25;
+            stat.compose_service = service.map(|s| ComposeService {
+                project: "shop".to_owned(),
+                service: s.to_owned(),
+            });
+            stat
+        };
+        let stats = vec![
+            replica("aaaaaaaaaaaa", "/shop-web-1", Some("web"), 100),
+            replica("bbbbbbbbbbbb", "/shop-db-1", Some("db"), 1000),
+            replica("cccccccccccc", "/shop-web-2", Some("web"), 200),
+            replica("dddddddddddd", "/shop-web-3", Some("web"), 300),
+            replica("eeeeeeeeeeee", "/standalone", None, 50),
+        ];
+
+        let grouped = group_by_service(&stats);
+        assert_eq!(grouped.len(), 3);
+        assert_eq!(grouped[0].name, "web");
+        assert_eq!(grouped[0].mem_usage, 600);
+        assert_eq!(grouped[0].cpu_usage, 0.75);
+        assert_eq!(grouped[0].mem_usage_ratio, 0.3);
+        assert_eq!(grouped[0].net_in_packets, 0);
+        assert_eq!(grouped[1].name, "db");
+        assert_eq!(grouped[1].mem_usage, 1000);
+        assert_eq!(grouped[2].id, "eeeeeeeeeeee");
+
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                group_by_service: true,
+                ..Default::default()
+            },
+        );
+        worker.last_stats.lock().await.stats = stats.clone();
+        let body = encode_registry(&worker).await;
+        assert!(
+            body.contains("container_memory_usage_bytes{project=\"shop\",service=\"web\"} 600")
+        );
+        assert!(
+            body.contains("container_memory_usage_bytes{project=\"shop\",service=\"db\"} 1000")
+        );
+        assert!(body.contains("name=\"standalone\""));
+        assert!(body.contains("container_network_receive_packets_total{id="));
+        // counters and limits are not summed up across replicas
+        assert!(!body.contains("packets_total{project="));
+        assert!(!body.contains("limit_bytes{project="));
+        assert!(!body.contains("shop-web-1"));
+        assert!(body.contains("docker_stats_total_memory_bytes 1650"));
+
+        // a service is stale once all of its replicas vanished
+        let current = vec![stats[0].clone(), stats[2].clone(), stats[4].clone()];
+        track_vanished(
+            &mut *worker.vanished.lock().await,
+            &stats,
+            &current,
+            SystemTime::now(),
+            Duration::from_secs(30),
+        );
+        worker.last_stats.lock().await.stats = current;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains(
+            "container_memory_usage_bytes{project=\"shop\",service=\"db\",stale=\"true\"} 1000"
+        ));
+        assert!(
+            body.contains("container_memory_usage_bytes{project=\"shop\",service=\"web\"} 300")
+        );
+        assert!(!body.contains("service=\"web\",stale"));
+    }
+
+    #[tokio::test]
+    async fn test_polling_interval_metric() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2500,
+            DockerStatPollingOptions::default(),
+        );
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("docker_stats_polling_interval_seconds 2.5"));
+    }
+
+    #[tokio::test]
+    async fn test_mount_info() {
+        let mounts = mounts_from_summary(Some(&[
+            MountPoint {
+                typ: Some(MountPointTypeEnum::VOLUME),
+                name: Some("pgdata".to_owned()),
+                source: Some("/var/lib/docker/volumes/pgdata/_data".to_owned()),
+                destination: Some("/var/lib/postgresql/data".to_owned()),
+                ..Default::default()
+            },
+            MountPoint {
+                typ: Some(MountPointTypeEnum::BIND),
+                source: Some("/etc/app/config.yml".to_owned()),
+                destination: Some("/app/config.yml".to_owned()),
+                ..Default::default()
+            },
+        ]));
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].destination, "/app/config.yml");
+        assert_eq!(mounts[0].mount_type, "bind");
+        assert_eq!(mounts[1].mount_type, "volume");
+        assert!(mounts_from_summary(None).is_empty());
+
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let mut stat = container_stat("aaaaaaaaaaaa", "/db");
+        stat.mounts = mounts;
+        worker
+            .update_container_metrics(&[stat, container_stat("bbbbbbbbbbbb", "/web")])
+            .await;
+        let body = encode_registry(&worker).await;
+        let mount_lines = body
+            .lines()
+            .filter(|l| l.starts_with("container_mount_info{"))
+            .collect::<Vec<_>>();
+        assert_eq!(mount_lines.len(), 2);
+        assert!(mount_lines.iter().all(|l| l.contains("name=\"db\"")));
+        assert!(mount_lines.iter().any(|l| l.contains(
+            "source=\"/etc/app/config.yml\",destination=\"/app/config.yml\",type=\"bind\"} 1"
+        )));
+
+        // series go away with the container
+        worker
+            .update_container_metrics(&[container_stat("bbbbbbbbbbbb", "/web")])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("container_mount_info{"));
+    }
+
+    #[test]
+    fn test_name_strategy() {
+        let names = [
+            "/web".to_owned(),
+            "/proxy/web-alias".to_owned(),
+            "/db/w".to_owned(),
+        ];
+        assert_eq!(NameStrategy::First.pick(&names).unwrap(), "/web");
+        assert_eq!(
+            NameStrategy::Longest.pick(&names).unwrap(),
+            "/proxy/web-alias"
+        );
+        assert_eq!(
+            NameStrategy::Join.pick(&names).unwrap(),
+            "/web,proxy/web-alias,db/w"
+        );
+        // ties go to the first name
+        let tied = ["/web".to_owned(), "/api".to_owned()];
+        assert_eq!(NameStrategy::Longest.pick(&tied).unwrap(), "/web");
+        for strategy in [
+            NameStrategy::First,
+            NameStrategy::Longest,
+            NameStrategy::Join,
+        ] {
+            assert_eq!(strategy.pick(&["/web".to_owned()]).unwrap(), "/web");
+            assert!(strategy.pick(&[]).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_last_containers() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.state = Some("running".to_owned());
+        stat.cpu_usage = 0.5;
+        worker.last_stats.lock().await.stats.push(stat);
+        let containers = worker.get_last_containers().await;
+        assert_eq!(
+            serde_json::to_value(&containers).unwrap(),
+            serde_json::json!([{"id": "aaaaaaaaaaaa", "name": "/web", "state": "running"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relabel_rules() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                relabel_rules: serde_json::from_str(
+                    r#"[
+                        {"source_labels": ["name"], "regex": "test-.*", "action": "drop"},
+                        {"source_labels": ["name"], "target_label": "container"},
+                        {"regex": "name", "action": "labeldrop"}
+                    ]"#,
+                )
+                .unwrap(),
+                ..Default::default()
+            },
+        );
+        let stats = [
+            container_stat("aaaaaaaaaaaa", "/web"),
+            container_stat("bbbbbbbbbbbb", "/test-web"),
+        ];
+        worker.update_container_metrics(&stats).await;
+        let body = encode_registry(&worker).await;
+        assert!(body.contains(
+            "container_cpu_usage_ratios{id=\"/system.slice/docker-aaaaaaaaaaaa.scope\",container=\"web\"}"
+        ));
+        assert!(!body.contains("name=\""));
+        assert!(!body.contains("bbbbbbbbbbbb"));
+    }
+
+    #[tokio::test]
+    async fn test_container_info_command() {
+        let long_command = format!("python -m app {}", "--flag ".repeat(40));
+        let command = truncate_command(&long_command, 128);
+        assert_eq!(command.chars().count(), 128);
+        assert!(long_command.starts_with(&command));
+        assert_eq!(truncate_command(&long_command, 0), long_command);
+        assert_eq!(
+            truncate_command("nginx -g daemon off;", 128),
+            "nginx -g daemon off;"
+        );
+        // cut on a character boundary
+        assert_eq!(truncate_command("échö", 2), "éc");
+
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let mut stat = container_stat("aaaaaaaaaaaa", "/app");
+        stat.command = Some(command.clone());
+        worker
+            .update_container_metrics(&[stat, container_stat("bbbbbbbbbbbb", "/web")])
+            .await;
+        let body = encode_registry(&worker).await;
+        let info_lines = body
+            .lines()
+            .filter(|l| l.starts_with("container_info{"))
+            .collect::<Vec<_>>();
+        assert_eq!(info_lines.len(), 2);
+        assert!(
+            info_lines.iter().any(|l| l.contains("name=\"app\"")
+                && l.ends_with(&format!("command=\"{}\"}} 1", command)))
+        );
+        // omitted without a command
+        assert!(
+            info_lines
+                .iter()
+                .any(|l| l.contains("name=\"web\"") && !l.contains("command="))
+        );
+
+        worker
+            .update_container_metrics(&[container_stat("bbbbbbbbbbbb", "/web")])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("command="));
+    }
+
+    #[test]
+    fn test_swarm_service() {
+        let task_labels = |task: &str| {
+            HashMap::from([
+                (SWARM_STACK_LABEL.to_owned(), "shop".to_owned()),
+                (SWARM_SERVICE_LABEL.to_owned(), "shop_web".to_owned()),
+                ("com.docker.swarm.task.name".to_owned(), task.to_owned()),
+            ])
+        };
+        let web = ComposeService {
+            project: "shop".to_owned(),
+            service: "web".to_owned(),
+        };
+        assert_eq!(
+            compose_service_from_labels(Some(&task_labels("shop_web.1.x1")), true),
+            Some(web.clone())
+        );
+        assert_eq!(
+            compose_service_from_labels(Some(&task_labels("shop_web.1.x1")), false),
+            None
+        );
+        // `docker service create` without stack
+        let labels = HashMap::from([(SWARM_SERVICE_LABEL.to_owned(), "cache".to_owned())]);
+        assert_eq!(
+            compose_service_from_labels(Some(&labels), true),
+            Some(ComposeService {
+                project: String::new(),
+                service: "cache".to_owned(),
+            })
+        );
+
+        let task = |id: &str, labels: &HashMap<String, String>| {
+            let mut stat = container_stat(id, "/task");
+            stat.mem_usage = 100;
+            stat.compose_service = compose_service_from_labels(Some(labels), true);
+            stat
+        };
+        let grouped = group_by_service(&[
+            task("aaaaaaaaaaaa", &task_labels("shop_web.1.x1")),
+            task("bbbbbbbbbbbb", &task_labels("shop_web.2.y2")),
+            task("cccccccccccc", &labels),
+        ]);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].compose_service, Some(web));
+        assert_eq!(grouped[0].mem_usage, 200);
+        assert_eq!(grouped[1].name, "cache");
+    }
+
+    #[tokio::test]
+    async fn test_filtered_container_stats_registry() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        worker.last_stats.lock().await.stats.extend([
+            container_stat("aaaaaaaaaaaa", "/web"),
+            container_stat("bbbbbbbbbbbb", "/db"),
+        ]);
+
+        let encode = |registry: Registry| {
+            let mut body = String::new();
+            prometheus_client::encoding::text::encode(&mut body, &registry).unwrap();
+            body
+        };
+
+        let body = encode(
+            worker
+                .get_filtered_container_stats_registry(&ContainerFilter {
+                    name: Some("web".to_owned()),
+                    id: None,
+                })
+                .await,
+        );
+        assert!(body.contains("name=\"web\""));
+        assert!(!body.contains("name=\"db\""));
+
+        let body = encode(
+            worker
+                .get_filtered_container_stats_registry(&ContainerFilter {
+                    name: None,
+                    id: Some("bbbb".to_owned()),
+                })
+                .await,
+        );
+        assert!(!body.contains("name=\"web\""));
+        assert!(body.contains("name=\"db\""));
+
+        let body = encode(
+            worker
+                .get_filtered_container_stats_registry(&ContainerFilter {
+                    name: Some("web".to_owned()),
+                    id: Some("bbbb".to_owned()),
+                })
+                .await,
+        );
+        assert!(!body.contains("container_cpu_usage_ratios{"));
+        assert!(body.contains("docker_stats_up"));
+    }
+
+    #[tokio::test]
+    async fn test_paused_metric() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        worker
+            .update_container_metrics(&[
+                DockerContainerStat {
+                    state: Some("running".to_owned()),
+                    ..container_stat("aaaaaaaaaaaa", "/web")
+                },
+                DockerContainerStat {
+                    state: Some("paused".to_owned()),
+                    ..container_stat("bbbbbbbbbbbb", "/db")
+                },
+            ])
+            .await;
+
+        let body = encode_registry(&worker).await;
+        let paused = body
+            .lines()
+            .filter(|l| l.starts_with("container_paused{"))
+            .collect::<Vec<_>>();
+        assert_eq!(paused.len(), 2);
+        assert!(
+            paused
+                .iter()
+                .any(|l| l.contains("name=\"web\"") && l.ends_with(" 0"))
+        );
+        assert!(
+            paused
+                .iter()
+                .any(|l| l.contains("name=\"db\"") && l.ends_with(" 1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_node_label() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                node_label: Some("node-1".to_owned()),
+                ..Default::default()
+            },
+        );
+        worker
+            .update_container_metrics(&[container_stat("3f4e8a2b9c1d", "/web")])
+            .await;
+
+        let body = encode_registry(&worker).await;
+
+        let series = body.lines().filter(|l| !l.starts_with('#'));
+        for line in series {
+            assert!(line.contains("node=\"node-1\""), "{}", line);
+        }
+        assert!(body.contains("container_cpu_usage_ratios{"));
+        assert!(body.contains("docker_stats_up{"));
+    }
+
+    #[tokio::test]
+    async fn test_metric_prefix_override() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let db = DockerContainerStat {
+            metric_prefix: Some("db_container".to_owned()),
+            ..container_stat("bbbbbbbbbbbb", "/db")
+        };
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/web"), db])
+            .await;
+
+        let body = encode_registry(&worker).await;
+        assert!(body.contains(
+            "\ncontainer_cpu_usage_ratios{id=\"/system.slice/docker-aaaaaaaaaaaa.scope\""
+        ));
+        assert!(body.contains(
+            "\ndb_container_cpu_usage_ratios{id=\"/system.slice/docker-bbbbbbbbbbbb.scope\""
+        ));
+        assert!(!body.contains(
+            "\ncontainer_cpu_usage_ratios{id=\"/system.slice/docker-bbbbbbbbbbbb.scope\""
+        ));
+
+        // prefix is dropped along with its last container
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/web")])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("db_container_"));
+        assert!(body.contains(
+            "\ncontainer_cpu_usage_ratios{id=\"/system.slice/docker-aaaaaaaaaaaa.scope\""
+        ));
+    }
+
+    #[test]
+    fn test_metric_prefix_from_labels() {
+        let labels =
+            |prefix: &str| HashMap::from([(METRIC_PREFIX_LABEL.to_owned(), prefix.to_owned())]);
+
+        let prefix = |labels: Option<&HashMap<String, String>>| {
+            metric_prefix_from_labels("bbbbbbbbbbbb", labels)
+        };
+        assert_eq!(
+            prefix(Some(&labels("db_container"))),
+            Some("db_container".to_owned())
+        );
+        assert_eq!(prefix(Some(&labels("1db"))), None);
+        assert_eq!(prefix(Some(&labels("db-container"))), None);
+        assert_eq!(prefix(Some(&labels(""))), None);
+        assert_eq!(prefix(Some(&HashMap::new())), None);
+        assert_eq!(prefix(None), None);
+        // warned once for the container, not on every poll
+        assert!(
+            WARNED_INVALID_PREFIXES
+                .lock()
+                .unwrap()
+                .contains("bbbbbbbbbbbb")
+        );
+    }
+
+    #[test]
+    fn test_sort_last_stats() {
+        let stat = |id: &str, name: &str, cpu_usage: f64, mem_usage: u64, net_in_bps: f64| {
+            DockerContainerStat {
+                cpu_usage,
+                mem_usage,
+                net_in_bps,
+                ..container_stat(id, name)
+            }
+        };
+        let mut stats = LastDockerStats {
+            timestamp: SystemTime::UNIX_EPOCH,
+            truncated: false,
+            stats: vec![
+                stat("aaaaaaaaaaaa", "/web", 0.5, 100, 10.),
+                stat("bbbbbbbbbbbb", "/db", 0.1, 300, 30.),
+                stat("cccccccccccc", "/cache", 0.9, 200, 20.),
+            ],
+        };
+        let mut sorted = |key, order| {
+            stats.sort(key, order);
+            stats
+                .stats
+                .iter()
+                .map(|s| s.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sorted(StatsSortKey::Cpu, SortOrder::Asc),
+            ["/db", "/web", "/cache"]
+        );
+        assert_eq!(
+            sorted(StatsSortKey::Cpu, SortOrder::Desc),
+            ["/cache", "/web", "/db"]
+        );
+        assert_eq!(
+            sorted(StatsSortKey::Mem, SortOrder::Desc),
+            ["/db", "/cache", "/web"]
+        );
+        assert_eq!(
+            sorted(StatsSortKey::Net, SortOrder::Asc),
+            ["/web", "/cache", "/db"]
+        );
+        assert_eq!(
+            sorted(StatsSortKey::Name, SortOrder::Asc),
+            ["/cache", "/db", "/web"]
+        );
+    }
+
+    #[test]
+    fn test_truncate_last_stats() {
+        let mut stats = LastDockerStats {
+            timestamp: SystemTime::UNIX_EPOCH,
+            truncated: false,
+            stats: ["aaaaaaaaaaaa", "bbbbbbbbbbbb", "cccccccccccc"]
+                .iter()
+                .map(|id| container_stat(id, "/web"))
+                .collect(),
+        };
+
+        stats.truncate(3);
+        assert_eq!(stats.stats.len(), 3);
+        assert!(!stats.truncated);
+
+        stats.truncate(2);
+        assert_eq!(
+            stats
+                .stats
+                .iter()
+                .map(|s| s.id.as_str())
+                .collect::<Vec<_>>(),
+            ["aaaaaaaaaaaa", "bbbbbbbbbbbb"]
+        );
+        assert!(stats.truncated);
+        let json: serde_json::Value = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["truncated"], true);
+    }
+
+    #[test]
+    fn test_to_influx_lines() {
+        let stats = LastDockerStats {
+            truncated: false,
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            stats: vec![DockerContainerStat {
+                cpu_usage: 0.25,
+                mem_usage: 1024,
+                mem_limit: 4096,
+                net_in_bps: 800.,
+                blk_out: 512,
+                ..container_stat("3f4e8a2b9c1d", "/my web")
+            }],
+        };
+
+        assert_eq!(
+            stats.to_influx_lines(),
+            "docker_stats,id=3f4e8a2b9c1d,name=my\\ web cpu_usage=0.25,mem_usage=1024i,\
+             mem_limit=4096i,net_in=0i,net_out=0i,net_in_bps=800,net_out_bps=0,\
+             blk_in=0i,blk_out=512i,blk_in_byteps=0,blk_out_byteps=0 1700000000000000000\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_age() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        worker.last_stats.lock().await.timestamp = SystemTime::now() - Duration::from_secs(90);
+
+        let body = encode_registry(&worker).await;
+        let age = body
+            .lines()
+            .find_map(|l| l.strip_prefix("docker_stats_data_age_seconds "))
+            .unwrap()
+            .parse::<f64>()
+            .unwrap();
+        assert!((90.0..91.0).contains(&age), "{}", age);
+    }
+
+    #[tokio::test]
+    async fn test_daemon_info_metrics() {
+        let info = SystemInfo {
+            containers_running: Some(3),
+            containers_paused: Some(1),
+            containers_stopped: None,
+            images: Some(12),
+            mem_total: Some(8_589_934_592),
+            ncpu: Some(4),
+            ..Default::default()
+        };
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+
+        // no metrics until docker info succeeds
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("docker_daemon_"));
+
+        *worker.daemon_info.lock().await = Some(DaemonInfo::from(&info));
+        let body = encode_registry(&worker).await;
+        for line in [
+            "docker_daemon_containers_total{state=\"running\"} 3",
+            "docker_daemon_containers_total{state=\"paused\"} 1",
+            "docker_daemon_containers_total{state=\"stopped\"} 0",
+            "docker_daemon_images_total 12",
+            "docker_daemon_memory_bytes 8589934592",
+            "docker_daemon_cpus 4",
+        ] {
+            assert!(body.contains(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_rate_window() {
+        // bursty container, 1000 bytes every other second
+        let samples = [(0, 0), (1, 1000), (2, 1000), (3, 2000), (4, 2000)];
+        let rates = |window: usize| {
+            let mut history = RateHistory::default();
+            samples
+                .iter()
+                .map(|(seconds, net_in)| {
+                    let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+                    stat.net_in = *net_in;
+                    stat.blk_in = net_in / 2;
+                    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(*seconds);
+                    history.push(RateSample::new(&stat, time), window);
+                    history.apply_rates(&mut stat);
+                    stat
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // consecutive polls follow every burst
+        let single = rates(1);
+        assert_eq!(
+            single.iter().map(|s| s.net_in_bps).collect::<Vec<_>>(),
+            [0., 8000., 0., 8000., 0.]
+        );
+        // two intervals average them out once filled
+        let windowed = rates(2);
+        assert_eq!(
+            windowed.iter().map(|s| s.net_in_bps).collect::<Vec<_>>(),
+            [0., 8000., 4000., 4000., 4000.]
+        );
+        assert_eq!(windowed[4].blk_in_byteps, 250.);
+
+        // a restart drops samples before it
+        let mut history = RateHistory::default();
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        for (seconds, net_in) in [(0, 5000), (1, 6000), (2, 100)] {
+            stat.net_in = net_in;
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(seconds);
+            history.push(RateSample::new(&stat, time), 3);
+        }
+        assert_eq!(history.samples.len(), 1);
+    }
+
+    #[test]
+    fn test_accumulate_counters() {
+        let mut state = CounterState::new();
+        let stat = |errors: u64| DockerContainerStat {
+            net_in_errors: errors,
+            ..container_stat("aaaaaaaaaaaa", "/web")
+        };
+
+        let mut stats = [stat(5)];
+        accumulate_counters(&mut state, &mut stats, &[]);
+        assert_eq!(stats[0].net_in_errors, 5);
+
+        let mut stats = [stat(8)];
+        accumulate_counters(&mut state, &mut stats, &[]);
+        assert_eq!(stats[0].net_in_errors, 8);
+
+        // container restarted, docker counter starts over
+        let mut stats = [stat(2)];
+        accumulate_counters(&mut state, &mut stats, &[]);
+        assert_eq!(stats[0].net_in_errors, 10);
+
+        // stale containers keep their snapshots
+        accumulate_counters(&mut state, &mut [], &["aaaaaaaaaaaa".to_owned()]);
+        assert_eq!(state.len(), 1);
+
+        // containers missing from a few polls keep their snapshots
+        for _ in 0..COUNTER_STATE_RETAINED_POLLS {
+            accumulate_counters(&mut state, &mut [], &[]);
+        }
+        let mut stats = [stat(3)];
+        accumulate_counters(&mut state, &mut stats, &[]);
+        assert_eq!(stats[0].net_in_errors, 11);
+
+        // gone containers are dropped
+        for _ in 0..=COUNTER_STATE_RETAINED_POLLS {
+            accumulate_counters(&mut state, &mut [], &[]);
+        }
+        assert!(state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load_state() {
+        let path =
+            std::env::temp_dir().join(format!("docker-stat-prom-{}.json", uuid::Uuid::new_v4()));
+        let options = DockerStatPollingOptions {
+            state_file: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let worker =
+            DockerStatPollingWorker::new("unix:///var/run/docker.sock", 2000, options.clone());
+        let mut stats = [DockerContainerStat {
+            net_in_errors: 7,
+            ..container_stat("aaaaaaaaaaaa", "/web")
+        }];
+        accumulate_counters(&mut *worker.counter_state.lock().await, &mut stats, &[]);
+        worker.save_state().await.unwrap();
+
+        // exporter restarted while container restarted
+        let restarted = DockerStatPollingWorker::new("unix:///var/run/docker.sock", 2000, options);
+        restarted.load_state().await.unwrap();
+        let mut stats = [DockerContainerStat {
+            net_in_errors: 1,
+            ..container_stat("aaaaaaaaaaaa", "/web")
+        }];
+        accumulate_counters(&mut *restarted.counter_state.lock().await, &mut stats, &[]);
+        assert_eq!(stats[0].net_in_errors, 8);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_containers() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                max_containers: Some(2),
+                max_containers_sort: ContainerSortKey::Memory,
+                histograms: true,
+                ..Default::default()
+            },
+        );
+        let stat = |id: &str, name: &str, mem_usage: u64| DockerContainerStat {
+            mem_usage,
+            ..container_stat(id, name)
+        };
+        let stats = vec![
+            stat("aaaaaaaaaaaa", "/web", 300),
+            stat("bbbbbbbbbbbb", "/db", 500),
+            stat("cccccccccccc", "/cache", 100),
+            stat("dddddddddddd", "/queue", 400),
+        ];
+        worker.update_container_metrics(&stats).await;
+        worker.last_stats.lock().await.stats = stats;
+
+        let body = encode_registry(&worker).await;
+        assert!(body.contains("name=\"db\""));
+        assert!(body.contains("name=\"queue\""));
+        assert!(!body.contains("name=\"web\""));
+        assert!(!body.contains("name=\"cache\""));
+        assert!(body.contains("docker_stats_truncated 2\n"));
+        // totals still cover every container
+        assert!(body.contains("docker_stats_total_memory_bytes 1300\n"));
+        // histograms of top containers stay exported
+        let histogram_count = |name: &str| {
+            body.lines().any(|line| {
+                line.starts_with("container_memory_usage_bytes_histogram_count{")
+                    && line.contains(&format!("name=\"{}\"", name))
+            })
+        };
+        assert!(histogram_count("db"));
+        assert!(histogram_count("queue"));
+        assert!(!histogram_count("web"));
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_metric() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let exited = DockerContainerStat {
+            state: Some("exited".to_owned()),
+            exit_code: Some(137),
+            ..container_stat("bbbbbbbbbbbb", "/job")
+        };
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/web"), exited])
+            .await;
+
+        let body = encode_registry(&worker).await;
+        let exit_codes = body
+            .lines()
+            .filter(|l| l.starts_with("container_exit_code{"))
+            .collect::<Vec<_>>();
+        assert_eq!(exit_codes.len(), 1);
+        assert!(exit_codes[0].contains("name=\"job\""));
+        assert!(exit_codes[0].ends_with(" 137"));
+
+        // restarted container drops its exit code
+        worker
+            .update_container_metrics(&[container_stat("bbbbbbbbbbbb", "/job")])
+            .await;
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("container_exit_code{"));
+    }
+
+    fn print_stats() -> LastDockerStats {
+        LastDockerStats {
+            truncated: false,
+            timestamp: SystemTime::UNIX_EPOCH,
+            stats: vec![
+                DockerContainerStat {
+                    cpu_usage: 0.5,
+                    mem_usage: 1048576,
+                    net_in: 42,
+                    ..container_stat("3f4e8a2b9c1d7e6f", "/web")
+                },
+                DockerContainerStat {
+                    cpu_usage: 0.0125,
+                    mem_usage: 512,
+                    blk_out: 1024,
+                    ..container_stat("aaaaaaaaaaaaaaaa", "/database")
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_format_stats_table() {
+        assert_eq!(
+            format_stats(&print_stats(), PrintFormat::Table),
+            "Last probe at SystemTime { tv_sec: 0, tv_nsec: 0 }\n\
+             ID            NAME         CPU      MEM  NET_IN  NET_OUT  BLK_IN  BLK_OUT\n\
+             3f4e8a2b9c1d  web       0.5000  1048576      42        0       0        0\n\
+             aaaaaaaaaaaa  database  0.0125      512       0        0       0     1024"
+        );
+    }
+
+    #[test]
+    fn test_format_stats_tsv() {
+        assert_eq!(
+            format_stats(&print_stats(), PrintFormat::Tsv),
+            "ID\tNAME\tCPU\tMEM\tNET_IN\tNET_OUT\tBLK_IN\tBLK_OUT\n\
+             3f4e8a2b9c1d\tweb\t0.5000\t1048576\t42\t0\t0\t0\n\
+             aaaaaaaaaaaa\tdatabase\t0.0125\t512\t0\t0\t0\t1024"
+        );
+    }
+
+    #[test]
+    fn test_stats_json_memory_units() {
+        let json = serde_json::to_value(print_stats()).unwrap();
+        let web = &json["stats"][0];
+        assert_eq!(web["mem_usage"], 1048576);
+        assert_eq!(web["mem_usage_mib"], 1.);
+        assert_eq!(web["mem_limit_gib"], 0.);
+        assert!(web.get("vanished").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_with_timeout() {
+        let slow_list = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(Vec::new())
+        };
+        let result = list_with_timeout::<Vec<ContainerSummary>, _>(
+            slow_list,
+            Duration::from_millis(10),
+            AppError::ListContainers,
+        )
+        .await;
+        assert!(matches!(result, Err(AppError::ListTimeout(_))));
+
+        let list = async { Ok(vec![ContainerSummary::default()]) };
+        let result =
+            list_with_timeout(list, Duration::from_millis(10), AppError::ListContainers).await;
+        assert_eq!(result.unwrap().len(), 1);
+
+        let failed_list = async { Err(bollard::errors::Error::RequestTimeoutError) };
+        let result =
+            list_with_timeout::<(), _>(failed_list, Duration::ZERO, AppError::ListContainers).await;
+        assert!(matches!(result, Err(AppError::ListContainers(_))));
+
+        let failed_inspect = async { Err(bollard::errors::Error::RequestTimeoutError) };
+        let result =
+            list_with_timeout::<(), _>(failed_inspect, Duration::ZERO, AppError::Inspect).await;
+        assert!(matches!(result, Err(AppError::Inspect(_))));
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let calls = std::cell::Cell::new(0);
+        let flaky = || {
+            calls.set(calls.get() + 1);
+            let attempt = calls.get();
+            async move {
+                if attempt == 1 {
+                    Err(bollard::errors::Error::RequestTimeoutError)
                 } else {
-                    DockerContainerStat {
-                        id: container_api_stat.id.clone(),
-                        name: container_api_stat.name.clone(),
-                        ..Default::default()
-                    }
-                };
+                    Ok(attempt)
+                }
+            }
+        };
+
+        let result = with_retries(1, "flaky call", flaky).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(calls.get(), 2);
+
+        calls.set(0);
+        let result = with_retries(0, "flaky call", flaky).await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_next_batch() {
+        let sorted = |batch: HashSet<usize>| {
+            let mut batch = batch.into_iter().collect::<Vec<_>>();
+            batch.sort();
+            batch
+        };
+
+        let (batch, cursor) = next_batch(0, 5, 2);
+        assert_eq!((sorted(batch), cursor), (vec![0, 1], 2));
+        let (batch, cursor) = next_batch(cursor, 5, 2);
+        assert_eq!((sorted(batch), cursor), (vec![2, 3], 4));
+        // wraps around
+        let (batch, cursor) = next_batch(cursor, 5, 2);
+        assert_eq!((sorted(batch), cursor), (vec![0, 4], 1));
+
+        // containers removed since last batch
+        let (batch, cursor) = next_batch(4, 3, 2);
+        assert_eq!((sorted(batch), cursor), (vec![1, 2], 0));
+
+        // batch covers all
+        let (batch, cursor) = next_batch(3, 4, 10);
+        assert_eq!((sorted(batch), cursor), (vec![0, 1, 2, 3], 0));
+        assert!(next_batch(0, 0, 2).0.is_empty());
+    }
+
+    #[test]
+    fn test_health_from_status() {
+        let health = |status| health_from_status(Some(status));
+        assert_eq!(health("Up 3 minutes (healthy)").as_deref(), Some("healthy"));
+        assert_eq!(
+            health("Up 10 seconds (unhealthy)").as_deref(),
+            Some("unhealthy")
+        );
+        assert_eq!(
+            health("Up 2 seconds (health: starting)").as_deref(),
+            Some("starting")
+        );
+        assert_eq!(health("Up 3 minutes"), None);
+        assert_eq!(health("Up 3 minutes (Paused)"), None);
+        assert_eq!(health_from_status(None), None);
+    }
+
+    #[test]
+    fn test_track_unhealthy() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let poll = |health: Option<&str>| {
+            let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+            stat.health = health.map(|h| h.to_owned());
+            stat
+        };
+        let mut unhealthy_since = HashMap::new();
+
+        let mut stats = [poll(Some("healthy"))];
+        track_unhealthy(&mut unhealthy_since, &mut stats, start);
+        assert_eq!(stats[0].unhealthy_seconds, Some(0.));
+
+        // turns unhealthy, time counts from the first unhealthy poll
+        let mut stats = [poll(Some("unhealthy"))];
+        track_unhealthy(
+            &mut unhealthy_since,
+            &mut stats,
+            start + Duration::from_secs(2),
+        );
+        assert_eq!(stats[0].unhealthy_seconds, Some(0.));
+        let mut stats = [poll(Some("unhealthy"))];
+        track_unhealthy(
+            &mut unhealthy_since,
+            &mut stats,
+            start + Duration::from_secs(7),
+        );
+        assert_eq!(stats[0].unhealthy_seconds, Some(5.));
+
+        // recovery resets it
+        let mut stats = [poll(Some("healthy"))];
+        track_unhealthy(
+            &mut unhealthy_since,
+            &mut stats,
+            start + Duration::from_secs(9),
+        );
+        assert_eq!(stats[0].unhealthy_seconds, Some(0.));
+        assert!(unhealthy_since.is_empty());
+        let mut stats = [poll(Some("unhealthy"))];
+        track_unhealthy(
+            &mut unhealthy_since,
+            &mut stats,
+            start + Duration::from_secs(11),
+        );
+        assert_eq!(stats[0].unhealthy_seconds, Some(0.));
+
+        // without health check there is no value, and the container is forgotten
+        let mut stats = [poll(None)];
+        track_unhealthy(
+            &mut unhealthy_since,
+            &mut stats,
+            start + Duration::from_secs(13),
+        );
+        assert_eq!(stats[0].unhealthy_seconds, None);
+        assert!(unhealthy_since.is_empty());
+    }
+
+    /// docker daemon answering container list, stats and inspect requests of
+    /// `containers` listed as JSON, recording `inflight` while serving stats
+    async fn mock_docker(
+        containers: &'static str,
+        inflight: Gauge,
+        during_stats: Arc<Mutex<Vec<i64>>>,
+    ) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-                // previous docker stat from api
-                let pre_api_stat = {
-                    let stat_guard = self.last_docker_stats.lock().await;
-                    stat_guard
-                        .stats
-                        .get(&container_api_stat.id)
-                        .map(|s| s.clone())
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or_default();
+                let body = if path.contains("/containers/json") {
+                    containers
+                } else if path.contains("/stats") {
+                    during_stats.lock().await.push(inflight.get());
+                    r#"{"id":"aaaaaaaaaaaa","name":"/web"}"#
+                } else {
+                    "{}"
                 };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
 
-                if let Some(pre_api_stat) = pre_api_stat {
-                    if let (Some(pre_container_stat), Some(container_stat)) =
-                        (pre_api_stat.stat, &container_api_stat.stat)
-                    {
-                        let duration = container_api_stat
-                            .time
-                            .duration_since(pre_api_stat.time)
-                            .unwrap();
-                        let time_delta = 1_000_000_000. / duration.as_nanos() as f64;
+    #[tokio::test]
+    async fn test_inflight_collections() {
+        let inflight = Gauge::default();
+        let during_stats = Arc::new(Mutex::new(Vec::new()));
+        let host = mock_docker(
+            r#"[{"Id":"aaaaaaaaaaaa","Names":["/web"],"State":"running"}]"#,
+            inflight.clone(),
+            during_stats.clone(),
+        )
+        .await;
 
-                        // get cpu use between the stats
-                        let cpu_usage = if let (Some(first_cpustat), Some(second_cpu_stat)) =
-                            (&pre_container_stat.cpu_stats, &container_stat.cpu_stats)
-                        {
-                            get_cpu_usage(first_cpustat, second_cpu_stat, time_delta)
-                        } else {
-                            0.0
-                        };
-                        stat.cpu_usage = cpu_usage;
+        let stats = docker_stat_oneshot(
+            &host,
+            &DockerStatPollingOptions::default(),
+            &Mutex::new(HashMap::new()),
+            Some(&Mutex::new(0)),
+            &Mutex::new(StatStreams::default()),
+            &Mutex::new(HashMap::new()),
+            &inflight,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].stat.is_some());
+        // counted while the stats call was served, released after the poll
+        assert_eq!(*during_stats.lock().await, vec![1]);
+        assert_eq!(inflight.get(), 0);
+    }
 
-                        // get netio bps between the stats
-                        let (first_net_in, first_net_out) =
-                            if let Some(networks) = &pre_container_stat.networks {
-                                get_net_io(networks)
-                            } else {
-                                (0, 0)
-                            };
-                        let (net_in_bps, net_out_bps) = (
-                            (stat.net_in - first_net_in) as f64 * time_delta,
-                            (stat.net_out - first_net_out) as f64 * time_delta,
-                        );
-                        stat.net_in_bps = net_in_bps * 8.;
-                        stat.net_out_bps = net_out_bps * 8.;
+    #[tokio::test]
+    async fn test_batch_with_sub_samples() {
+        let host = mock_docker(
+            r#"[
+                {"Id":"aaaaaaaaaaaa","Names":["/a"],"State":"running"},
+                {"Id":"bbbbbbbbbbbb","Names":["/b"],"State":"running"},
+                {"Id":"cccccccccccc","Names":["/c"],"State":"running"}
+            ]"#,
+            Gauge::default(),
+            Arc::new(Mutex::new(Vec::new())),
+        )
+        .await;
+        let options = DockerStatPollingOptions {
+            batch_size: 1,
+            ..Default::default()
+        };
+        let cursor = Mutex::new(0);
+        let (exit_codes, stat_streams, inspected, inflight) = (
+            Mutex::new(HashMap::new()),
+            Mutex::new(StatStreams::default()),
+            Mutex::new(HashMap::new()),
+            Gauge::default(),
+        );
+        let poll = |batch_cursor| {
+            docker_stat_oneshot(
+                &host,
+                &options,
+                &exit_codes,
+                batch_cursor,
+                &stat_streams,
+                &inspected,
+                &inflight,
+            )
+        };
+        let sampled_ids = |stats: Vec<TimedContainerStatsResponse>| {
+            stats
+                .into_iter()
+                .filter(|s| s.sampled)
+                .map(|s| s.id)
+                .collect::<Vec<_>>()
+        };
 
-                        // get blkio bps between the stats
-                        let (first_blk_in, first_blk_out) =
-                            if let Some(blkio) = &pre_container_stat.blkio_stats {
-                                get_blk_io(blkio)
-                            } else {
-                                (0, 0)
-                            };
-                        let (blk_in_byteps, blk_out_byteps) = (
-                            (stat.blk_in - first_blk_in) as f64 * time_delta,
-                            (stat.blk_out - first_blk_out) as f64 * time_delta,
-                        );
-                        stat.blk_in_byteps = blk_in_byteps;
-                        stat.blk_out_byteps = blk_out_byteps;
-                    }
-                }
+        // every poll samples the next container, sub-samples in between read
+        // all of them and leave the rotation as it is
+        for expected in [
+            "aaaaaaaaaaaa",
+            "bbbbbbbbbbbb",
+            "cccccccccccc",
+            "aaaaaaaaaaaa",
+        ] {
+            assert_eq!(sampled_ids(poll(Some(&cursor)).await.unwrap()), [expected]);
+            for _ in 0..2 {
+                assert_eq!(sampled_ids(poll(None).await.unwrap()).len(), 3);
+            }
+        }
+    }
 
-                parsed_stat.push(stat);
+    #[tokio::test]
+    async fn test_slow_polls() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        worker
+            .check_poll_duration(Duration::from_millis(1500))
+            .await;
+        worker
+            .check_poll_duration(Duration::from_millis(2000))
+            .await;
+        assert!(
+            encode_registry(&worker)
+                .await
+                .contains("docker_stats_slow_polls_total 0\n")
+        );
+
+        // a poll stuck on a slow daemon for longer than the interval
+        worker
+            .check_poll_duration(Duration::from_millis(2500))
+            .await;
+        assert_eq!(*worker.slow_polls.lock().await, 1);
+        assert!(
+            encode_registry(&worker)
+                .await
+                .contains("docker_stats_slow_polls_total 1\n")
+        );
+    }
+
+    #[test]
+    fn test_opt_in_label_filter() {
+        let statuses = vec!["running".to_owned(), "paused".to_owned()];
+        assert_eq!(
+            list_filters(&statuses, None),
+            HashMap::from([("status".to_owned(), statuses.clone())])
+        );
+        assert_eq!(
+            list_filters(&statuses, Some("prometheus.scrape")),
+            HashMap::from([
+                ("status".to_owned(), statuses.clone()),
+                (
+                    "label".to_owned(),
+                    vec!["prometheus.scrape=true".to_owned()]
+                ),
+            ])
+        );
+
+        let labels =
+            |value: &str| HashMap::from([("prometheus.scrape".to_owned(), value.to_owned())]);
+        assert!(is_opted_in(Some(&labels("true")), "prometheus.scrape"));
+        assert!(!is_opted_in(Some(&labels("false")), "prometheus.scrape"));
+        assert!(!is_opted_in(Some(&labels("true")), "other"));
+        assert!(!is_opted_in(None, "prometheus.scrape"));
+    }
+
+    #[test]
+    fn test_parse_process_stats() {
+        let stat = "4242 (docker stat) S 1 4242 4242 0 -1 4194560 2403 0 0 0 250 130 0 0 20 0 9 0 1234 123456789 3000 18446744073709551615";
+        let status =
+            "Name:\tdocker-stat-pro\nVmPeak:\t  200000 kB\nVmRSS:\t   12000 kB\nThreads:\t9\n";
+        assert_eq!(
+            ProcessStats::parse(stat, status),
+            Some(ProcessStats {
+                resident_memory: 12000 * 1024,
+                cpu_seconds: 3.8,
+            })
+        );
+        assert_eq!(ProcessStats::parse(stat, "Name:\tdocker-stat-pro\n"), None);
+        assert_eq!(ProcessStats::parse("4242 (docker", status), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_process_stats() {
+        let process = ProcessStats::read().await.unwrap();
+        assert!(process.resident_memory > 0);
+        assert!(process.cpu_seconds >= 0.);
+
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let body = encode_registry(&worker).await;
+        assert!(!body.contains("docker_stats_process_"));
+        worker.refresh_process_stats().await;
+        let body = encode_registry(&worker).await;
+        let value = |name: &str| {
+            body.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(' '))
+                .and_then(|v| v.parse::<f64>().ok())
+        };
+        assert!(value("docker_stats_process_resident_memory_bytes").is_some_and(|v| v > 0.));
+        assert!(value("docker_stats_process_cpu_seconds_total").is_some_and(|v| v >= 0.));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_initial_delay() {
+        let worker = Arc::new(DockerStatPollingWorker::new(
+            "unix:///nonexistent/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                initial_delay: Duration::from_secs(10),
+                ..Default::default()
+            },
+        ));
+        let task = worker.spawn_polling_stat_task(worker.clone());
+
+        tokio::time::sleep(Duration::from_secs(9)).await;
+        assert_eq!(*worker.consecutive_failures.lock().await, 0);
+        assert!(worker.connection.lock().await.last_error.is_none());
+
+        // the first poll fails on the missing socket once the delay elapsed
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..1000 {
+            if *worker.consecutive_failures.lock().await > 0 {
+                break;
             }
-            debug!(
-                "parsed all containers stats in {} μs",
-                SystemTime::now()
-                    .duration_since(start_at)
-                    .unwrap()
-                    .as_micros() as u64
-            );
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(*worker.consecutive_failures.lock().await > 0);
+        task.abort();
+    }
 
-            // update last status for next probe
-            let _ = {
-                let mut last_stat_guard = self.last_stats.lock().await;
-                last_stat_guard.timestamp = whole_start_at;
-                last_stat_guard.stats.clear();
-                last_stat_guard.stats.append(&mut parsed_stat);
-            };
+    #[test]
+    fn test_hold_paused_rates() {
+        let poll = |state: &str, cpu_usage: f64, net_in_bps: f64| DockerContainerStat {
+            id: "aaaaaaaaaaaa".to_owned(),
+            state: Some(state.to_owned()),
+            cpu_usage,
+            net_in_bps,
+            ..Default::default()
+        };
+        let mut active = HashMap::new();
 
-            let _ = {
-                let mut last_api_stat_guard = self.last_docker_stats.lock().await;
-                last_api_stat_guard.timestamp = whole_start_at;
-                last_api_stat_guard.stats.clear();
-                for api_stat in last_api_stats {
-                    last_api_stat_guard
-                        .stats
-                        .insert(api_stat.id.clone(), api_stat);
-                }
-            };
+        let mut stats = [poll("running", 0.5, 800.)];
+        hold_paused_rates(&mut active, &mut stats);
+        assert_eq!((stats[0].cpu_usage, stats[0].net_in_bps), (0.5, 800.));
+        // an idle interval keeps the last non-zero rate of each kind
+        let mut stats = [poll("running", 0.25, 0.)];
+        hold_paused_rates(&mut active, &mut stats);
+        assert_eq!((stats[0].cpu_usage, stats[0].net_in_bps), (0.25, 0.));
+
+        // paused counters stand still, rates are held
+        for _ in 0..2 {
+            let mut stats = [poll("paused", 0., 0.)];
+            hold_paused_rates(&mut active, &mut stats);
+            assert_eq!((stats[0].cpu_usage, stats[0].net_in_bps), (0.25, 800.));
+        }
+
+        // unpaused, rates are live again
+        let mut stats = [poll("running", 0.1, 0.)];
+        hold_paused_rates(&mut active, &mut stats);
+        assert_eq!((stats[0].cpu_usage, stats[0].net_in_bps), (0.1, 0.));
+
+        // gone containers are forgotten, paused ones unseen running read 0
+        hold_paused_rates(&mut active, &mut []);
+        assert!(active.is_empty());
+        let mut stats = [poll("paused", 0., 0.)];
+        hold_paused_rates(&mut active, &mut stats);
+        assert_eq!((stats[0].cpu_usage, stats[0].net_in_bps), (0., 0.));
+    }
+
+    #[test]
+    fn test_cpu_limit_cores() {
+        let nano = HostConfig {
+            nano_cpus: Some(1_500_000_000),
+            cpu_quota: Some(50_000),
+            ..Default::default()
+        };
+        assert_eq!(cpu_limit_cores(&nano), Some(1.5));
+
+        let quota = HostConfig {
+            cpu_quota: Some(50_000),
+            cpu_period: Some(25_000),
+            ..Default::default()
+        };
+        assert_eq!(cpu_limit_cores(&quota), Some(2.));
+        let quota_default_period = HostConfig {
+            cpu_quota: Some(50_000),
+            cpu_period: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(cpu_limit_cores(&quota_default_period), Some(0.5));
+
+        // unlimited
+        let unlimited = HostConfig {
+            nano_cpus: Some(0),
+            cpu_quota: Some(-1),
+            ..Default::default()
+        };
+        assert_eq!(cpu_limit_cores(&unlimited), None);
+        assert_eq!(cpu_limit_cores(&HostConfig::default()), None);
+    }
+
+    #[test]
+    fn test_self_container_id() {
+        const ID: &str = "3f4e8a2b9c1d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f";
+
+        // cgroup v2 with systemd and cgroupfs drivers
+        let systemd = format!("0::/system.slice/docker-{}.scope\n", ID);
+        assert_eq!(self_container_id(&systemd, None).as_deref(), Some(ID));
+        let cgroupfs = format!("0::/docker/{}\n", ID);
+        assert_eq!(
+            self_container_id(&cgroupfs, Some("3f4e8a2b9c1d")).as_deref(),
+            Some(ID)
+        );
+        // cgroup v1 lists a line per controller
+        let v1 = format!("12:pids:/docker/{}\n11:memory:/docker/{}\n", ID, ID);
+        assert_eq!(self_container_id(&v1, None).as_deref(), Some(ID));
 
-            let delay = {
-                let delay_guard = self.delay_ms.lock().await;
-                Duration::from_millis(*delay_guard)
+        // private cgroup namespace hides the path, hostname is the short id
+        assert_eq!(
+            self_container_id("0::/\n", Some("3f4e8a2b9c1d")).as_deref(),
+            Some("3f4e8a2b9c1d")
+        );
+        // not in a container, or hostname overridden
+        assert_eq!(
+            self_container_id("0::/user.slice/user-1000.slice\n", Some("my-host")),
+            None
+        );
+        assert_eq!(self_container_id("", None), None);
+    }
+
+    #[test]
+    fn test_time_since_restart() {
+        let state = |started_at: &str, finished_at: &str| ContainerState {
+            started_at: Some(started_at.to_owned()),
+            finished_at: Some(finished_at.to_owned()),
+            ..Default::default()
+        };
+        // first run
+        assert_eq!(
+            restarted_at(&state("2024-05-01T10:00:00.5Z", "0001-01-01T00:00:00Z")),
+            None
+        );
+        // restarted after the previous run finished
+        let finished_at = humantime::parse_rfc3339("2024-05-01T11:59:58Z").unwrap();
+        assert_eq!(
+            restarted_at(&state("2024-05-01T12:00:00Z", "2024-05-01T11:59:58Z")),
+            Some(finished_at)
+        );
+        // stopped after its last run
+        assert_eq!(
+            restarted_at(&state("2024-05-01T12:00:00Z", "2024-05-01T12:30:00Z")),
+            None
+        );
+
+        // restart between polls starts the CPU counter over
+        let cpu_stat = |total_usage: u64| ContainerStatsResponse {
+            cpu_stats: Some(ContainerCpuStats {
+                cpu_usage: Some(bollard::secret::ContainerCpuUsage {
+                    total_usage: Some(total_usage),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(!cpu_counter_restarted(&cpu_stat(1_000), &cpu_stat(2_000)));
+        assert!(cpu_counter_restarted(&cpu_stat(2_000), &cpu_stat(50)));
+        assert!(!cpu_counter_restarted(
+            &cpu_stat(2_000),
+            &ContainerStatsResponse::default()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cpu_shares_from_inspect() {
+        let inspect: ContainerInspectResponse = serde_json::from_str(
+            r#"{
+                "Id": "aaaaaaaaaaaa",
+                "State": {"Status": "running", "StartedAt": "2024-05-01T12:00:00Z"},
+                "HostConfig": {"CpuShares": 512, "NanoCpus": 0, "PidsLimit": null}
+            }"#,
+        )
+        .unwrap();
+        let inspected = InspectedContainer::from_inspect(&inspect);
+        assert_eq!(inspected.limits.shares, Some(512));
+        assert_eq!(inspected.limits.cpu, None);
+        assert_eq!(inspected.restarted_at, None);
+
+        // unset shares are left out
+        let unset = HostConfig {
+            cpu_shares: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(ContainerLimits::from_host_config(&unset).shares, None);
+
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        let mut stat = container_stat("aaaaaaaaaaaa", "/web");
+        stat.cpu_shares = inspected.limits.shares;
+        worker
+            .update_container_metrics(&[stat, container_stat("bbbbbbbbbbbb", "/db")])
+            .await;
+        let body = encode_registry(&worker).await;
+        let shares = body
+            .lines()
+            .filter(|l| l.starts_with("container_cpu_shares{"))
+            .collect::<Vec<_>>();
+        assert_eq!(shares.len(), 1);
+        assert!(shares[0].contains("name=\"web\"") && shares[0].ends_with(" 512"));
+    }
+
+    #[test]
+    fn test_container_limits_from_host_config() {
+        let limited = HostConfig {
+            nano_cpus: Some(500_000_000),
+            pids_limit: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            ContainerLimits::from_host_config(&limited),
+            ContainerLimits {
+                cpu: Some(0.5),
+                pids: Some(100),
+                shares: None,
+            }
+        );
+
+        // 0 and -1 are unlimited
+        for pids_limit in [Some(0), Some(-1), None] {
+            let unlimited = HostConfig {
+                pids_limit,
+                ..Default::default()
             };
-            tokio::time::sleep(delay).await;
-            // self.print_stat().await;
+            assert_eq!(
+                ContainerLimits::from_host_config(&unlimited),
+                ContainerLimits::default()
+            );
         }
     }
 
-    pub fn new(host: &str, polling_millis: u64) -> Self {
-        Self {
-            docker_host: host.to_owned(),
-            prom_registry_prefix: Arc::new(Mutex::new("container".to_owned())),
-            delay_ms: Arc::new(Mutex::new(polling_millis)),
-            last_stats: Arc::new(Mutex::new(LastDockerStats {
-                timestamp: SystemTime::now(),
-                stats: Vec::new(),
-            })),
-            last_docker_stats: Arc::new(Mutex::new(LastDockerAPIContainersStats {
-                timestamp: SystemTime::now(),
-                stats: HashMap::new(),
-            })),
+    #[tokio::test]
+    async fn test_stat_streams_lifecycle() {
+        // a reader task publishing one frame, then waiting until it is aborted
+        let fake_stream = |closed: tokio::sync::oneshot::Sender<()>| {
+            let latest = Arc::new(Mutex::new(Some((
+                ContainerStatsResponse::default(),
+                SystemTime::now(),
+            ))));
+            let handle = tokio::spawn(async move {
+                let _closed = closed;
+                std::future::pending::<()>().await;
+            });
+            StatStream { latest, handle }
+        };
+        let mut streams = StatStreams::default();
+
+        let (tx, web_closed) = tokio::sync::oneshot::channel();
+        assert!(
+            streams
+                .latest("web", false, || fake_stream(tx))
+                .await
+                .is_some()
+        );
+        // a peek leaves the frame to be taken
+        assert!(
+            streams
+                .latest("web", true, || unreachable!("stream is still open"))
+                .await
+                .is_some()
+        );
+        // frame is taken once, an open stream is not opened again
+        assert!(
+            streams
+                .latest("web", true, || unreachable!("stream is still open"))
+                .await
+                .is_none()
+        );
+
+        // an ended stream is reopened
+        let (tx, _db_closed) = tokio::sync::oneshot::channel();
+        streams.streams.insert(
+            "db".to_owned(),
+            StatStream {
+                latest: Arc::new(Mutex::new(None)),
+                handle: tokio::spawn(async {}),
+            },
+        );
+        while !streams.streams["db"].handle.is_finished() {
+            tokio::task::yield_now().await;
         }
+        assert!(
+            streams
+                .latest("db", true, || fake_stream(tx))
+                .await
+                .is_some()
+        );
+
+        // streams of containers gone from the list are closed
+        streams.retain(&HashSet::from(["db".to_owned()]));
+        assert!(!streams.streams.contains_key("web"));
+        assert!(web_closed.await.is_err());
+        assert!(streams.streams.contains_key("db"));
     }
 
-    pub fn spawn_polling_stat_task(&self, myself: Arc<Self>) -> JoinHandle<()> {
-        tokio::spawn(async move { myself.task_handler().await })
+    #[test]
+    fn test_get_blk_service_time() {
+        let entry = |op: &str, value: u64| ContainerBlkioStatEntry {
+            major: Some(8),
+            minor: Some(0),
+            op: Some(op.to_owned()),
+            value: Some(value),
+        };
+        let blkio = ContainerBlkioStats {
+            io_service_time_recursive: Some(vec![
+                entry("read", 1_500_000_000),
+                entry("write", 500_000_000),
+                entry("sync", 2_000_000_000),
+                entry("total", 2_000_000_000),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(get_blk_service_time(&blkio), 2_000_000_000);
+
+        // cgroup v2
+        assert_eq!(get_blk_service_time(&ContainerBlkioStats::default()), 0);
     }
 
-    pub async fn get_cgroup2_data(
-        &self,
-        id: &str,
-    ) -> Result<TimedContainerStatsResponse, io::Error> {
-        let stats = {
-            let stats_guard = self.last_docker_stats.lock().await;
-            let container_stat = stats_guard.stats.get(id);
-            container_stat.map(|s| s.clone())
+    #[test]
+    fn test_get_blk_devices() {
+        let entry = |major: u64, minor: u64, op: &str, value: u64| ContainerBlkioStatEntry {
+            major: Some(major),
+            minor: Some(minor),
+            op: Some(op.to_owned()),
+            value: Some(value),
+        };
+        let blkio = ContainerBlkioStats {
+            io_service_bytes_recursive: Some(vec![
+                entry(259, 0, "read", 4096),
+                entry(259, 0, "write", 8192),
+                entry(8, 16, "read", 100),
+                entry(8, 0, "read", 1000),
+                entry(8, 0, "write", 2000),
+                entry(8, 0, "total", 3000),
+            ]),
+            ..Default::default()
         };
+        let names = |device: &str| match device {
+            "8:0" => Some("sda".to_owned()),
+            "259:0" => Some("nvme0n1".to_owned()),
+            _ => None,
+        };
+        let device =
+            |device: &str, device_name: &str, read_bytes: u64, write_bytes: u64| BlkioDeviceStat {
+                device: device.to_owned(),
+                device_name: device_name.to_owned(),
+                read_bytes,
+                write_bytes,
+            };
 
-        match stats {
-            Some(s) => Ok(s),
-            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "id not found")),
+        assert_eq!(
+            get_blk_devices(&blkio, names),
+            [
+                device("8:0", "sda", 1000, 2000),
+                device("8:16", "", 100, 0),
+                device("259:0", "nvme0n1", 4096, 8192),
+            ]
+        );
+        assert!(get_blk_devices(&ContainerBlkioStats::default(), names).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_cache() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions {
+                metrics_cache_ttl: Duration::from_secs(60),
+                ..Default::default()
+            },
+        );
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/web")])
+            .await;
+        let body = worker.get_encoded_metrics().await.unwrap();
+        assert!(body.contains("name=\"web\""));
+
+        // reused within ttl
+        worker
+            .update_container_metrics(&[container_stat("aaaaaaaaaaaa", "/frontend")])
+            .await;
+        assert_eq!(worker.get_encoded_metrics().await.unwrap(), body);
+
+        // new poll data invalidates the cache
+        worker.last_stats.lock().await.timestamp = SystemTime::now() + Duration::from_secs(1);
+        let body = worker.get_encoded_metrics().await.unwrap();
+        assert!(body.contains("name=\"frontend\""));
+    }
+
+    #[tokio::test]
+    async fn test_dump_last_stats() {
+        let worker = DockerStatPollingWorker::new(
+            "unix:///var/run/docker.sock",
+            2000,
+            DockerStatPollingOptions::default(),
+        );
+        worker
+            .last_stats
+            .lock()
+            .await
+            .stats
+            .push(container_stat("aaaaaaaaaaaa", "/web"));
+
+        let path =
+            std::env::temp_dir().join(format!("docker-stat-prom-{}.json", uuid::Uuid::new_v4()));
+        worker.dump_last_stats(&path).await.unwrap();
+
+        let dumped: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(dumped["stats"][0]["id"], "aaaaaaaaaaaa");
+        assert!(dumped["timestamp"].is_object());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_cgroup_memory_max() {
+        assert_eq!(parse_cgroup_memory_max("536870912\n"), Some(536_870_912));
+        assert_eq!(parse_cgroup_memory_max("max\n"), None);
+        assert_eq!(parse_cgroup_memory_max(""), None);
+    }
+
+    #[test]
+    fn test_poll_jitter() {
+        assert_eq!(poll_jitter(Duration::ZERO), Duration::ZERO);
+
+        let max = Duration::from_millis(50);
+        for _ in 0..100 {
+            assert!(poll_jitter(max) <= max);
         }
     }
 
-    pub async fn get_last_container_stats(&self) -> LastDockerStats {
-        self.last_stats.lock().await.clone()
+    #[test]
+    fn test_docker_tcp_address() {
+        assert_eq!(
+            docker_tcp_address("tcp://myhost", false).as_deref(),
+            Some("tcp://myhost:2375")
+        );
+        assert_eq!(
+            docker_tcp_address("tcp://myhost", true).as_deref(),
+            Some("tcp://myhost:2376")
+        );
+        assert_eq!(
+            docker_tcp_address("tcp://myhost:12375", true).as_deref(),
+            Some("tcp://myhost:12375")
+        );
+        assert_eq!(
+            docker_tcp_address("tcp://10.0.0.2/", false).as_deref(),
+            Some("tcp://10.0.0.2:2375")
+        );
+        assert_eq!(
+            docker_tcp_address("tcp://[::1]", false).as_deref(),
+            Some("tcp://[::1]:2375")
+        );
+        assert_eq!(docker_tcp_address("http://myhost:2375", false), None);
+        assert_eq!(
+            docker_tcp_address("unix:///var/run/docker.sock", false),
+            None
+        );
     }
 
-    pub async fn get_last_container_stats_registry(&self) -> Registry {
-        let registry_prefix = {
-            let prefix_guard = self.prom_registry_prefix.lock().await;
-            &prefix_guard.clone()
-        };
-        let mut registry = Registry::with_prefix(registry_prefix);
+    #[test]
+    fn test_get_net_interfaces() {
+        let networks = HashMap::from([
+            (
+                "eth1".to_owned(),
+                ContainerNetworkStats {
+                    rx_bytes: Some(2048),
+                    tx_bytes: Some(512),
+                    ..Default::default()
+                },
+            ),
+            (
+                "eth0".to_owned(),
+                ContainerNetworkStats {
+                    rx_bytes: Some(1024),
+                    tx_bytes: None,
+                    ..Default::default()
+                },
+            ),
+            (
+                "v6-only.100".to_owned(),
+                ContainerNetworkStats {
+                    rx_bytes: Some(64),
+                    tx_bytes: Some(32),
+                    ..Default::default()
+                },
+            ),
+        ]);
 
-        let _ = {
-            let stat_guard = self.last_stats.lock().await;
-            for stat in stat_guard.stats.iter() {
-                let metrics = DockerStatContainerMetrics::new(&stat.id);
-                metrics.cpu_usage.set(stat.cpu_usage);
-                metrics.mem_usage.set(stat.mem_usage);
-                metrics.mem_limit.set(stat.mem_limit);
-                metrics.net_in.set(stat.net_in);
-                metrics.net_out.set(stat.net_out);
-                metrics.net_in_bps.set(stat.net_in_bps);
-                metrics.net_out_bps.set(stat.net_out_bps);
-                metrics.blk_in.set(stat.blk_in);
-                metrics.blk_out.set(stat.blk_out);
-                metrics.blk_in_byteps.set(stat.blk_in_byteps);
-                metrics.blk_out_byteps.set(stat.blk_out_byteps);
-
-                metrics.register_as_sub_registry(&mut registry, &stat.name[1..]);
+        assert_eq!(
+            get_net_interfaces(&networks),
+            vec![
+                NetworkInterfaceStat {
+                    interface: "eth0".to_owned(),
+                    rx_bytes: 1024,
+                    tx_bytes: 0,
+                },
+                NetworkInterfaceStat {
+                    interface: "eth1".to_owned(),
+                    rx_bytes: 2048,
+                    tx_bytes: 512,
+                },
+                NetworkInterfaceStat {
+                    interface: "v6-only.100".to_owned(),
+                    rx_bytes: 64,
+                    tx_bytes: 32,
+                },
+            ]
+        );
+        assert!(get_net_interfaces(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_bytes_to_human_units() {
+        assert_eq!(bytes_to_mib(536_870_912), 512.);
+        assert_eq!(bytes_to_gib(536_870_912), 0.5);
+        assert_eq!(bytes_to_mib(1_572_864), 1.5);
+        assert_eq!(bytes_to_gib(0), 0.);
+    }
+
+    #[test]
+    fn test_get_net_packets() {
+        let networks = HashMap::from([
+            (
+                "eth0".to_owned(),
+                ContainerNetworkStats {
+                    rx_packets: Some(120),
+                    tx_packets: Some(80),
+                    ..Default::default()
+                },
+            ),
+            (
+                "eth1".to_owned(),
+                ContainerNetworkStats {
+                    rx_packets: Some(30),
+                    tx_packets: None,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        assert_eq!(get_net_packets(&networks), (150, 80));
+        assert_eq!(get_net_packets(&HashMap::new()), (0, 0));
+    }
+
+    #[test]
+    fn test_get_net_errors() {
+        let networks = HashMap::from([
+            (
+                "eth0".to_owned(),
+                ContainerNetworkStats {
+                    rx_errors: Some(3),
+                    tx_errors: Some(1),
+                    rx_dropped: Some(10),
+                    tx_dropped: None,
+                    ..Default::default()
+                },
+            ),
+            (
+                "eth1".to_owned(),
+                ContainerNetworkStats {
+                    rx_errors: Some(2),
+                    tx_errors: None,
+                    rx_dropped: Some(5),
+                    tx_dropped: Some(7),
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        assert_eq!(
+            get_net_errors(&networks),
+            NetworkErrorStats {
+                rx_errors: 5,
+                tx_errors: 1,
+                rx_dropped: 15,
+                tx_dropped: 7,
             }
-        };
-        registry
+        );
+        assert_eq!(
+            get_net_errors(&HashMap::new()),
+            NetworkErrorStats::default()
+        );
     }
 
-    pub fn set_delay(&self, duration: Duration) {
-        let mut delay = self.delay_ms.blocking_lock();
-        *delay = duration.as_millis() as u64;
+    #[test]
+    fn test_get_mem_kernel() {
+        let mem = memory_stats(1000, &[("cache", 100), ("kernel", 300), ("kernel_tcp", 20)]);
+        assert_eq!(get_mem_kernel(&mem), 320);
+
+        let mem = memory_stats(1000, &[("kernel", 300)]);
+        assert_eq!(get_mem_kernel(&mem), 300);
+
+        let mem = memory_stats(1000, &[("total_inactive_file", 100)]);
+        assert_eq!(get_mem_kernel(&mem), 0);
+        assert_eq!(get_mem_kernel(&ContainerMemoryStats::default()), 0);
     }
 
-    pub async fn print_stat(&self) {
-        let last_stats_guard = self.last_stats.lock().await;
-        println!("Last probe at {:?}", last_stats_guard.timestamp);
-        println!("stats:");
-        println!("");
-        for stat in last_stats_guard.stats.iter() {
-            let formatted_line = format!(
-                "{} {} {:.4} {} {} {} {} {}",
-                &stat.id[..7],
-                &stat.name[1..],
-                stat.cpu_usage,
-                stat.mem_usage,
-                stat.net_in,
-                stat.net_out,
-                stat.blk_in,
-                stat.blk_out
-            );
-            println!("{}", formatted_line);
+    #[test]
+    fn test_mem_usage_ratio() {
+        assert_eq!(mem_usage_ratio(256, 1024), 0.25);
+        assert_eq!(mem_usage_ratio(1024, 1024), 1.);
+        // usage reported above limit
+        assert_eq!(mem_usage_ratio(1100, 1024), 1.);
+        assert_eq!(mem_usage_ratio(1100, 0), 0.);
+    }
+
+    #[test]
+    fn test_get_mem_dirty() {
+        // cgroup v1
+        let mem = memory_stats(1000, &[("cache", 100), ("dirty", 30), ("writeback", 5)]);
+        assert_eq!(get_mem_dirty(&mem), (30, 5));
+
+        // cgroup v2
+        let mem = memory_stats(
+            1000,
+            &[("file", 100), ("file_dirty", 40), ("file_writeback", 8)],
+        );
+        assert_eq!(get_mem_dirty(&mem), (40, 8));
+
+        let mem = memory_stats(1000, &[("file_dirty", 40)]);
+        assert_eq!(get_mem_dirty(&mem), (40, 0));
+        let mem = memory_stats(1000, &[("total_inactive_file", 100)]);
+        assert_eq!(get_mem_dirty(&mem), (0, 0));
+        assert_eq!(get_mem_dirty(&ContainerMemoryStats::default()), (0, 0));
+    }
+
+    #[test]
+    fn test_get_mem_missing_keys() {
+        // each key alone is subtracted
+        for key in ["file", "inactive_file", "total_inactive_file", "cache"] {
+            let mem = memory_stats(100_000, &[("anon", 50_000), (key, 30_000)]);
+            assert_eq!(get_mem(&mem).unwrap(), 70_000, "{}", key);
+        }
+
+        // raw usage without page cache keys
+        let mem = memory_stats(100_000, &[("anon", 50_000)]);
+        assert_eq!(get_mem(&mem).unwrap(), 100_000);
+        let mem = ContainerMemoryStats {
+            usage: Some(100_000),
+            ..Default::default()
+        };
+        assert_eq!(get_mem(&mem).unwrap(), 100_000);
+
+        let mem = ContainerMemoryStats::default();
+        assert!(get_mem(&mem).is_err());
+    }
+
+    fn timed_stat(
+        id: &str,
+        state: &str,
+        stat: Option<ContainerStatsResponse>,
+    ) -> TimedContainerStatsResponse {
+        TimedContainerStatsResponse {
+            id: id.to_owned(),
+            name: format!("/{}", id),
+            state: Some(state.to_owned()),
+            metric_prefix: None,
+            compose_service: None,
+            mounts: Vec::new(),
+            command: None,
+            stat,
+            time: SystemTime::now(),
+            collection_duration: Duration::ZERO,
+            exit_code: None,
+            limits: ContainerLimits::default(),
+            restarted_at: None,
+            health: None,
+            sampled: true,
         }
     }
+
+    #[test]
+    fn test_retain_last_good_stats() {
+        let good = Some(ContainerStatsResponse {
+            name: Some("/a".to_owned()),
+            ..Default::default()
+        });
+        let mut last_good = HashMap::new();
+        last_good.insert("a".to_owned(), timed_stat("a", "running", good.clone()));
+        last_good.insert("b".to_owned(), timed_stat("b", "running", None));
+        last_good.insert("d".to_owned(), timed_stat("d", "exited", good.clone()));
+
+        // stats of a failed intermittently, b never had any, c is new
+        let mut stats = vec![
+            timed_stat("a", "running", None),
+            timed_stat("b", "running", None),
+            timed_stat("c", "running", None),
+            timed_stat("d", "exited", None),
+            timed_stat("e", "running", good),
+        ];
+        retain_last_good_stats(&mut stats, &last_good);
+        let sampled = stats.iter().map(|s| s.sampled).collect::<Vec<_>>();
+        assert_eq!(sampled, [false, true, true, true, true]);
+    }
 }